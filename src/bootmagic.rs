@@ -0,0 +1,50 @@
+use defmt::Format;
+
+use crate::matrix::Result;
+
+// Scans a key has to read the same in before bootmagic acts, spread over longer than a bounce
+// lasts, as there is no debouncing yet at that point.
+pub const SCAN_COUNT: usize = 5;
+pub const SCAN_INTERVAL_MICROS: u64 = 5_000;
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum Action {
+    BootloaderJump,
+    ClearSettings,
+}
+
+// Keys held while plugging in, as (row, col) positions on the local key matrix. On split
+// keyboards, each half checks its own matrix.
+pub struct Bootmagic {
+    bootloader_key: Option<(usize, usize)>,
+    clear_settings_key: Option<(usize, usize)>,
+}
+
+impl Bootmagic {
+    pub fn new(
+        bootloader_key: Option<(usize, usize)>,
+        clear_settings_key: Option<(usize, usize)>,
+    ) -> Self {
+        Bootmagic {
+            bootloader_key,
+            clear_settings_key,
+        }
+    }
+
+    pub fn check<const ROW_COUNT: usize, const COL_COUNT: usize>(
+        &self,
+        result: &Result<ROW_COUNT, COL_COUNT>,
+    ) -> Option<Action> {
+        let is_pressed = |key: Option<(usize, usize)>| {
+            key.and_then(|(i, j)| result.matrix.get(i)?.get(j))
+                .is_some_and(|bit| bit.pressed)
+        };
+        if is_pressed(self.bootloader_key) {
+            Some(Action::BootloaderJump)
+        } else if is_pressed(self.clear_settings_key) {
+            Some(Action::ClearSettings)
+        } else {
+            None
+        }
+    }
+}
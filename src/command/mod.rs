@@ -0,0 +1,214 @@
+pub mod raw_hid;
+pub mod via;
+
+use defmt::Format;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    debug,
+    key::{Action, HostMode, LayerIndex},
+    physical::PhysicalKey,
+    processor::{
+        events::rgb::RGBSettings,
+        input::chatter::SwitchHealth,
+        pipeline::{Stage, StageErrors},
+    },
+    rotary::Direction,
+};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const REPORT_SIZE_BYTES: usize = 64;
+
+pub type Report = [u8; REPORT_SIZE_BYTES];
+
+#[derive(Clone, Copy, Debug, Format)]
+pub enum Frame {
+    Native(Report),
+    Via(via::Report),
+}
+
+/*
+    Report byte layout
+       +-----------------------------+
+    0  |          version            |
+       +-----------------------------+
+    1  |                             |
+    .  |    payload (postcard)       |
+    .  |                             |
+   63  |                             |
+       +-----------------------------+
+*/
+
+#[derive(Clone, Copy, Debug, Deserialize, Format)]
+pub enum Request<L: LayerIndex> {
+    GetFirmwareInfo,
+    GetKeymapEntry {
+        layer: u8,
+        row: u8,
+        col: u8,
+    },
+    SetKeymapEntry {
+        layer: u8,
+        row: u8,
+        col: u8,
+        action: Action<L>,
+    },
+    GetEncoderEntry {
+        layer: u8,
+        direction: Direction,
+    },
+    SetEncoderEntry {
+        layer: u8,
+        direction: Direction,
+        action: Action<L>,
+    },
+    GetRGBSettings,
+    SetRGBSettings(RGBSettings),
+    BootloaderJump,
+    GetDebugCounters,
+    ResetKeymap,
+    GetProfile,
+    SetProfile(u8),
+    // Index into the physical layout, in keymap order.
+    GetPhysicalKey(u8),
+    GetSwitchHealth {
+        row: u8,
+        col: u8,
+    },
+    ResetSwitchHealth,
+    // Index into the processor pipeline, input stages first.
+    GetProcessor(u8),
+    SetProcessorEnabled {
+        index: u8,
+        is_enabled: bool,
+    },
+    ResetProcessorErrors,
+    GetHostMode,
+    SetHostMode(HostMode),
+}
+
+#[derive(Clone, Copy, Debug, Format, Serialize)]
+pub enum Response<L: LayerIndex> {
+    Ack,
+    FirmwareInfo(FirmwareInfo),
+    KeymapEntry {
+        layer: u8,
+        row: u8,
+        col: u8,
+        action: Action<L>,
+    },
+    EncoderEntry {
+        layer: u8,
+        direction: Direction,
+        action: Action<L>,
+    },
+    RGBSettings(RGBSettings),
+    DebugCounters([u32; debug::COUNTER_COUNT]),
+    Error(Error),
+    Profile {
+        active: u8,
+        count: u8,
+    },
+    PhysicalKey(PhysicalKey),
+    SwitchHealth {
+        row: u8,
+        col: u8,
+        health: SwitchHealth,
+    },
+    Processor {
+        index: u8,
+        count: u8,
+        stage: Stage,
+        is_enabled: bool,
+        errors: StageErrors,
+    },
+    HostMode(HostMode),
+}
+
+#[derive(Clone, Copy, Debug, Format, Serialize)]
+pub struct FirmwareInfo {
+    pub protocol_version: u8,
+    pub name: &'static str,
+    pub version: &'static str,
+    pub layer_count: u8,
+    pub key_matrix_row_count: u8,
+    pub key_matrix_col_count: u8,
+    pub key_count: u8,
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq, Serialize)]
+pub enum Error {
+    UnsupportedVersion,
+    MalformedRequest,
+    Unhandled,
+    OutOfBounds,
+    ResponseSerializationFailed,
+}
+
+pub trait Handler<L: LayerIndex> {
+    fn handle(&mut self, request: &Request<L>) -> Option<Response<L>>;
+}
+
+pub struct Dispatcher {
+    firmware_info: FirmwareInfo,
+    physical_layout: &'static [PhysicalKey],
+}
+
+impl Dispatcher {
+    pub fn new(firmware_info: FirmwareInfo, physical_layout: &'static [PhysicalKey]) -> Self {
+        Dispatcher {
+            firmware_info,
+            physical_layout,
+        }
+    }
+
+    pub fn dispatch<L: LayerIndex>(
+        &mut self,
+        report: &Report,
+        handlers: &mut [&mut dyn Handler<L>],
+    ) -> Report {
+        debug::increment_counter(debug::CounterTag::CommandRequest);
+        let response = match Self::decode::<L>(report) {
+            Ok(Request::GetFirmwareInfo) => Response::FirmwareInfo(self.firmware_info),
+            Ok(Request::GetDebugCounters) => Response::DebugCounters(debug::get_counters()),
+            Ok(Request::GetPhysicalKey(index)) => match self.physical_layout.get(index as usize) {
+                Some(&key) => Response::PhysicalKey(key),
+                None => Response::Error(Error::OutOfBounds),
+            },
+            Ok(request) => handle(handlers, &request),
+            Err(err) => Response::Error(err),
+        };
+        Self::encode(&response)
+    }
+
+    fn decode<L: LayerIndex>(report: &Report) -> Result<Request<L>, Error> {
+        if report[0] != PROTOCOL_VERSION {
+            return Err(Error::UnsupportedVersion);
+        }
+        postcard::from_bytes(&report[1..]).map_err(|_| Error::MalformedRequest)
+    }
+
+    fn encode<L: LayerIndex>(response: &Response<L>) -> Report {
+        let mut report = [0u8; REPORT_SIZE_BYTES];
+        report[0] = PROTOCOL_VERSION;
+        if postcard::to_slice(response, &mut report[1..]).is_err() {
+            defmt::error!("failed to serialize command response: {}", response);
+            postcard::to_slice(
+                &Response::<L>::Error(Error::ResponseSerializationFailed),
+                &mut report[1..],
+            )
+            .ok();
+        }
+        report
+    }
+}
+
+fn handle<L: LayerIndex>(
+    handlers: &mut [&mut dyn Handler<L>],
+    request: &Request<L>,
+) -> Response<L> {
+    handlers
+        .iter_mut()
+        .find_map(|h| h.handle(request))
+        .unwrap_or(Response::Error(Error::Unhandled))
+}
@@ -0,0 +1,126 @@
+use hal::fugit::ExtU32;
+use usb_device::class_prelude::{UsbBus, UsbBusAllocator};
+use usbd_human_interface_device::{
+    device::DeviceClass,
+    interface::{
+        InBytes32, InBytes64, InSize, Interface, InterfaceBuilder, InterfaceConfig, OutBytes32,
+        OutBytes64, OutSize, ReportSingle, UsbAllocatable,
+    },
+    UsbHidError,
+};
+
+#[rustfmt::skip]
+pub const RAW_HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xFF, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,       // Usage (0x01)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x02,       //   Usage (0x02)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x40,       //   Report Count (64)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0x09, 0x03,       //   Usage (0x03)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x40,       //   Report Count (64)
+    0x91, 0x02,       //   Output (Data, Var, Abs)
+    0xC0,             // End Collection
+];
+
+// VIA identifies its interface by this usage page and usage.
+#[rustfmt::skip]
+pub const VIA_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,       // Usage (0x61)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x62,       //   Usage (0x62)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x20,       //   Report Count (32)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0x09, 0x63,       //   Usage (0x63)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x20,       //   Report Count (32)
+    0x91, 0x02,       //   Output (Data, Var, Abs)
+    0xC0,             // End Collection
+];
+
+pub type CommandHid<'a, B> = RawHid<'a, B, InBytes64, OutBytes64>;
+pub type ViaHid<'a, B> = RawHid<'a, B, InBytes32, OutBytes32>;
+
+pub struct RawHid<'a, B: UsbBus, I: InSize, O: OutSize> {
+    interface: Interface<'a, B, I, O, ReportSingle>,
+}
+
+impl<'a, B: UsbBus, I: InSize, O: OutSize> RawHid<'a, B, I, O> {
+    pub fn write_report(&mut self, report: &[u8]) -> Result<(), UsbHidError> {
+        self.interface
+            .write_report(report)
+            .map(|_| ())
+            .map_err(UsbHidError::from)
+    }
+
+    pub fn read_report(&mut self, report: &mut [u8]) -> usb_device::Result<usize> {
+        self.interface.read_report(report)
+    }
+}
+
+impl<'a, B: UsbBus, I: InSize, O: OutSize> DeviceClass<'a> for RawHid<'a, B, I, O> {
+    type I = Interface<'a, B, I, O, ReportSingle>;
+
+    fn interface(&mut self) -> &mut Self::I {
+        &mut self.interface
+    }
+
+    fn reset(&mut self) {}
+
+    fn tick(&mut self) -> Result<(), UsbHidError> {
+        Ok(())
+    }
+}
+
+pub struct RawHidConfig<'a, I: InSize, O: OutSize> {
+    interface: InterfaceConfig<'a, I, O, ReportSingle>,
+}
+
+impl<'a, I: InSize, O: OutSize> RawHidConfig<'a, I, O> {
+    fn new(report_descriptor: &'a [u8], description: &'static str) -> Self {
+        RawHidConfig {
+            interface: InterfaceBuilder::new(report_descriptor)
+                .unwrap()
+                .description(description)
+                .in_endpoint(1.millis())
+                .unwrap()
+                .with_out_endpoint(1.millis())
+                .unwrap()
+                .build(),
+        }
+    }
+}
+
+impl<'a> RawHidConfig<'a, InBytes64, OutBytes64> {
+    pub fn command() -> Self {
+        Self::new(RAW_HID_REPORT_DESCRIPTOR, "kb raw hid")
+    }
+}
+
+impl<'a> RawHidConfig<'a, InBytes32, OutBytes32> {
+    pub fn via() -> Self {
+        Self::new(VIA_REPORT_DESCRIPTOR, "kb via")
+    }
+}
+
+impl<'a, B: UsbBus + 'a, I: InSize, O: OutSize> UsbAllocatable<'a, B> for RawHidConfig<'a, I, O> {
+    type Allocated = RawHid<'a, B, I, O>;
+
+    fn allocate(self, usb_alloc: &'a UsbBusAllocator<B>) -> Self::Allocated {
+        RawHid {
+            interface: self.interface.allocate(usb_alloc),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use core::mem;
+use enum_map::Enum;
+use usbd_human_interface_device::page::Keyboard;
+
+use crate::key::{Action, Control, HostMode, Key, LayerIndex, ModifiedKey};
+
+// QMK keycode ranges, as used by VIA protocol version 12 onwards.
+const KC_NO: u16 = 0x0000;
+const KC_TRANSPARENT: u16 = 0x0001;
+const QK_BASIC: u16 = 0x0002;
+const QK_BASIC_MAX: u16 = 0x00FF;
+const QK_MODS: u16 = 0x0100;
+const QK_MODS_MAX: u16 = 0x1FFF;
+const QK_MOMENTARY: u16 = 0x5220;
+const QK_MOMENTARY_MAX: u16 = 0x523F;
+const QK_RGB_MODE_FORWARD: u16 = 0x7821;
+const QK_RGB_MODE_REVERSE: u16 = 0x7822;
+const QK_RGB_VALUE_UP: u16 = 0x7827;
+const QK_RGB_VALUE_DOWN: u16 = 0x7828;
+const QK_RGB_SPEED_UP: u16 = 0x7829;
+const QK_RGB_SPEED_DOWN: u16 = 0x782A;
+const QK_BOOTLOADER: u16 = 0x7C00;
+const QK_KB_0: u16 = 0x7E00;
+const QK_KB_1: u16 = 0x7E01;
+const QK_KB_2: u16 = 0x7E02;
+// QK_KB_3 to QK_KB_10 toggle a processor each.
+const QK_KB_3: u16 = 0x7E03;
+const QK_KB_10: u16 = 0x7E0A;
+// QK_KB_11 cycles through the host modes.
+const QK_KB_11: u16 = 0x7E0B;
+// QK_KB_12 to QK_KB_15 select a host mode each.
+const QK_KB_12: u16 = 0x7E0C;
+const QK_KB_13: u16 = 0x7E0D;
+const QK_KB_14: u16 = 0x7E0E;
+const QK_KB_15: u16 = 0x7E0F;
+// QK_KB_16 onwards select a profile each.
+const QK_KB_16: u16 = 0x7E10;
+const QK_KB_31: u16 = 0x7E1F;
+
+// QMK modifier bits, right-hand modifiers set QMK_MOD_RIGHT alongside the base bits.
+const QMK_MOD_CONTROL: u8 = 0x01;
+const QMK_MOD_SHIFT: u8 = 0x02;
+const QMK_MOD_ALT: u8 = 0x04;
+const QMK_MOD_GUI: u8 = 0x08;
+const QMK_MOD_RIGHT: u8 = 0x10;
+
+pub fn from_action<L: LayerIndex>(action: Action<L>) -> u16 {
+    match action {
+        Action::Pass => KC_TRANSPARENT,
+        Action::None => KC_NO,
+        Action::Key(k) => from_key(k),
+        Action::ModifiedKey(mk) => {
+            (to_qmk_modifiers((mk.0 >> 8) as u8) as u16) << 8 | from_key(mk.get_key())
+        }
+        Action::Control(c) => match c {
+            Control::U2FBootloaderJump => QK_BOOTLOADER,
+            Control::RGBAnimationNext => QK_RGB_MODE_FORWARD,
+            Control::RGBAnimationPrevious => QK_RGB_MODE_REVERSE,
+            Control::RGBSpeedUp => QK_RGB_SPEED_UP,
+            Control::RGBSpeedDown => QK_RGB_SPEED_DOWN,
+            Control::RGBBrightnessUp => QK_RGB_VALUE_UP,
+            Control::RGBBrightnessDown => QK_RGB_VALUE_DOWN,
+            Control::RGBDirectionToggle => QK_KB_0,
+            Control::ProfileNext => QK_KB_1,
+            Control::ProfileSelect(p) => QK_KB_16 + (p as u16).min(QK_KB_31 - QK_KB_16),
+            Control::SocdToggle => QK_KB_2,
+            Control::ProcessorToggle(i) => QK_KB_3 + (i as u16).min(QK_KB_10 - QK_KB_3),
+            Control::HostModeNext => QK_KB_11,
+            Control::HostModeSelect(mode) => match mode {
+                HostMode::Standard => QK_KB_12,
+                HostMode::MacOS => QK_KB_13,
+                HostMode::AltGUISwap => QK_KB_14,
+                HostMode::GUILock => QK_KB_15,
+            },
+        },
+        Action::LayerModifier(l) => QK_MOMENTARY | l.into_usize() as u16,
+    }
+}
+
+pub fn to_action<L: LayerIndex>(keycode: u16) -> Option<Action<L>> {
+    match keycode {
+        KC_NO => Some(Action::None),
+        KC_TRANSPARENT => Some(Action::Pass),
+        QK_BASIC..=QK_BASIC_MAX => to_key(keycode).map(Action::Key),
+        QK_MODS..=QK_MODS_MAX => {
+            let key = to_key(keycode & 0x00FF)?;
+            let modifiers = from_qmk_modifiers((keycode >> 8) as u8);
+            Some(Action::ModifiedKey(ModifiedKey(
+                key as u16 | (modifiers as u16) << 8,
+            )))
+        }
+        QK_MOMENTARY..=QK_MOMENTARY_MAX => {
+            let layer = (keycode - QK_MOMENTARY) as usize;
+            if layer < L::LENGTH {
+                Some(Action::LayerModifier(L::from_usize(layer)))
+            } else {
+                None
+            }
+        }
+        QK_BOOTLOADER => Some(Action::Control(Control::U2FBootloaderJump)),
+        QK_RGB_MODE_FORWARD => Some(Action::Control(Control::RGBAnimationNext)),
+        QK_RGB_MODE_REVERSE => Some(Action::Control(Control::RGBAnimationPrevious)),
+        QK_RGB_SPEED_UP => Some(Action::Control(Control::RGBSpeedUp)),
+        QK_RGB_SPEED_DOWN => Some(Action::Control(Control::RGBSpeedDown)),
+        QK_RGB_VALUE_UP => Some(Action::Control(Control::RGBBrightnessUp)),
+        QK_RGB_VALUE_DOWN => Some(Action::Control(Control::RGBBrightnessDown)),
+        QK_KB_0 => Some(Action::Control(Control::RGBDirectionToggle)),
+        QK_KB_1 => Some(Action::Control(Control::ProfileNext)),
+        QK_KB_2 => Some(Action::Control(Control::SocdToggle)),
+        QK_KB_3..=QK_KB_10 => Some(Action::Control(Control::ProcessorToggle(
+            (keycode - QK_KB_3) as u8,
+        ))),
+        QK_KB_11 => Some(Action::Control(Control::HostModeNext)),
+        QK_KB_12 => Some(Action::Control(Control::HostModeSelect(HostMode::Standard))),
+        QK_KB_13 => Some(Action::Control(Control::HostModeSelect(HostMode::MacOS))),
+        QK_KB_14 => Some(Action::Control(Control::HostModeSelect(
+            HostMode::AltGUISwap,
+        ))),
+        QK_KB_15 => Some(Action::Control(Control::HostModeSelect(HostMode::GUILock))),
+        QK_KB_16..=QK_KB_31 => Some(Action::Control(Control::ProfileSelect(
+            (keycode - QK_KB_16) as u8,
+        ))),
+        _ => None,
+    }
+}
+
+fn from_key(key: Key) -> u16 {
+    Keyboard::from(key) as u16
+}
+
+fn to_key(usage: u16) -> Option<Key> {
+    (0..mem::variant_count::<Key>() as u16)
+        .map(|i| unsafe { mem::transmute::<u16, Key>(i) })
+        .find(|&k| from_key(k) == usage)
+}
+
+fn to_qmk_modifiers(modifiers: u8) -> u8 {
+    // QMK cannot mix sides in a single keycode, left-hand modifiers take precedence
+    let (bits, side) = if modifiers & 0x0F != 0 {
+        (modifiers & 0x0F, 0)
+    } else {
+        (modifiers >> 4, QMK_MOD_RIGHT)
+    };
+    let mut qmk_modifiers = side;
+    if bits & 0b0001 != 0 {
+        qmk_modifiers |= QMK_MOD_SHIFT;
+    }
+    if bits & 0b0010 != 0 {
+        qmk_modifiers |= QMK_MOD_CONTROL;
+    }
+    if bits & 0b0100 != 0 {
+        qmk_modifiers |= QMK_MOD_ALT;
+    }
+    if bits & 0b1000 != 0 {
+        qmk_modifiers |= QMK_MOD_GUI;
+    }
+    qmk_modifiers
+}
+
+fn from_qmk_modifiers(qmk_modifiers: u8) -> u8 {
+    let mut bits = 0;
+    if qmk_modifiers & QMK_MOD_SHIFT != 0 {
+        bits |= 0b0001;
+    }
+    if qmk_modifiers & QMK_MOD_CONTROL != 0 {
+        bits |= 0b0010;
+    }
+    if qmk_modifiers & QMK_MOD_ALT != 0 {
+        bits |= 0b0100;
+    }
+    if qmk_modifiers & QMK_MOD_GUI != 0 {
+        bits |= 0b1000;
+    }
+    if qmk_modifiers & QMK_MOD_RIGHT != 0 {
+        bits << 4
+    } else {
+        bits
+    }
+}
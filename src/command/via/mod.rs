@@ -0,0 +1,385 @@
+pub mod keycode;
+
+use rtic_monotonics::Monotonic;
+
+use crate::{
+    debug,
+    kb::Mono,
+    key::{Action, LayerIndex},
+    rotary::Direction,
+};
+
+use super::{handle, Handler, Request, Response};
+
+pub const PROTOCOL_VERSION: u16 = 0x000C;
+pub const REPORT_SIZE_BYTES: usize = 32;
+
+pub type Report = [u8; REPORT_SIZE_BYTES];
+
+/*
+    Report byte layout
+       +-----------------------------+
+    0  |         command id          |
+       +-----------------------------+
+    1  |                             |
+    .  |    command data             |
+    .  |                             |
+   31  |                             |
+       +-----------------------------+
+
+    Responses echo the request, with the command data overwritten in place. Keycodes are
+    QMK keycodes in big-endian, see the keycode module for the translation from Action.
+*/
+
+const ID_GET_PROTOCOL_VERSION: u8 = 0x01;
+const ID_GET_KEYBOARD_VALUE: u8 = 0x02;
+const ID_SET_KEYBOARD_VALUE: u8 = 0x03;
+const ID_DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+const ID_DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+const ID_DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+const ID_CUSTOM_SET_VALUE: u8 = 0x07;
+const ID_CUSTOM_GET_VALUE: u8 = 0x08;
+const ID_CUSTOM_SAVE: u8 = 0x09;
+const ID_BOOTLOADER_JUMP: u8 = 0x0B;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+const ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const ID_DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+const ID_DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+const ID_DYNAMIC_KEYMAP_GET_ENCODER: u8 = 0x14;
+const ID_DYNAMIC_KEYMAP_SET_ENCODER: u8 = 0x15;
+const ID_UNHANDLED: u8 = 0xFF;
+
+const KEYBOARD_VALUE_UPTIME: u8 = 0x01;
+const KEYBOARD_VALUE_LAYOUT_OPTIONS: u8 = 0x02;
+
+const CHANNEL_RGB_MATRIX: u8 = 0x03;
+const RGB_MATRIX_VALUE_BRIGHTNESS: u8 = 0x01;
+const RGB_MATRIX_VALUE_EFFECT: u8 = 0x02;
+const RGB_MATRIX_VALUE_EFFECT_SPEED: u8 = 0x03;
+
+// Header of the buffer commands is the command id, 2 bytes of offset, and 1 byte of size.
+const BUFFER_HEADER_SIZE_BYTES: usize = 4;
+
+pub struct Via {
+    layer_count: u8,
+    row_count: u8,
+    col_count: u8,
+}
+
+impl Via {
+    pub fn new(layer_count: u8, row_count: u8, col_count: u8) -> Self {
+        Via {
+            layer_count,
+            row_count,
+            col_count,
+        }
+    }
+
+    pub fn dispatch<L: LayerIndex>(
+        &mut self,
+        report: &Report,
+        handlers: &mut [&mut dyn Handler<L>],
+    ) -> Report {
+        debug::increment_counter(debug::CounterTag::CommandRequest);
+        let mut response = *report;
+        let handled = match report[0] {
+            ID_GET_PROTOCOL_VERSION => {
+                response[1..3].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+                true
+            }
+            ID_GET_KEYBOARD_VALUE => match report[1] {
+                KEYBOARD_VALUE_UPTIME => {
+                    let uptime_millis = (Mono::now().ticks() / 1_000) as u32;
+                    response[2..6].copy_from_slice(&uptime_millis.to_be_bytes());
+                    true
+                }
+                KEYBOARD_VALUE_LAYOUT_OPTIONS => {
+                    response[2..6].fill(0);
+                    true
+                }
+                _ => false,
+            },
+            ID_SET_KEYBOARD_VALUE => report[1] == KEYBOARD_VALUE_LAYOUT_OPTIONS,
+            ID_DYNAMIC_KEYMAP_GET_KEYCODE => {
+                match Self::get_keycode(handlers, report[1], report[2], report[3]) {
+                    Some(keycode) => {
+                        response[4..6].copy_from_slice(&keycode.to_be_bytes());
+                        true
+                    }
+                    None => false,
+                }
+            }
+            ID_DYNAMIC_KEYMAP_SET_KEYCODE => {
+                let keycode = u16::from_be_bytes([report[4], report[5]]);
+                Self::set_keycode(handlers, report[1], report[2], report[3], keycode)
+            }
+            ID_DYNAMIC_KEYMAP_RESET => {
+                matches!(handle(handlers, &Request::ResetKeymap), Response::Ack)
+            }
+            ID_CUSTOM_SET_VALUE => {
+                report[1] == CHANNEL_RGB_MATRIX
+                    && Self::set_rgb_matrix_value(handlers, report[2], report[3])
+            }
+            ID_CUSTOM_GET_VALUE => {
+                match (report[1] == CHANNEL_RGB_MATRIX)
+                    .then(|| Self::get_rgb_matrix_value(handlers, report[2]))
+                    .flatten()
+                {
+                    Some(value) => {
+                        response[3] = value;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            // settings are applied immediately and saved once they settle
+            ID_CUSTOM_SAVE => report[1] == CHANNEL_RGB_MATRIX,
+            ID_BOOTLOADER_JUMP => {
+                matches!(handle(handlers, &Request::BootloaderJump), Response::Ack)
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT => {
+                response[1] = 0;
+                true
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+                response[1..3].fill(0);
+                true
+            }
+            ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT => {
+                response[1] = self.layer_count;
+                true
+            }
+            ID_DYNAMIC_KEYMAP_GET_BUFFER => self.get_buffer(handlers, report, &mut response),
+            ID_DYNAMIC_KEYMAP_SET_BUFFER => self.set_buffer(handlers, report),
+            ID_DYNAMIC_KEYMAP_GET_ENCODER => {
+                match Self::get_encoder_keycode(handlers, report[1], report[2], report[3]) {
+                    Some(keycode) => {
+                        response[4..6].copy_from_slice(&keycode.to_be_bytes());
+                        true
+                    }
+                    None => false,
+                }
+            }
+            ID_DYNAMIC_KEYMAP_SET_ENCODER => {
+                let keycode = u16::from_be_bytes([report[4], report[5]]);
+                Self::set_encoder_keycode(handlers, report[1], report[2], report[3], keycode)
+            }
+            _ => false,
+        };
+        if !handled {
+            defmt::warn!("unhandled via command: {=u8:#04x}", report[0]);
+            response[0] = ID_UNHANDLED;
+        }
+        response
+    }
+
+    fn get_keycode<L: LayerIndex>(
+        handlers: &mut [&mut dyn Handler<L>],
+        layer: u8,
+        row: u8,
+        col: u8,
+    ) -> Option<u16> {
+        match handle(handlers, &Request::GetKeymapEntry { layer, row, col }) {
+            Response::KeymapEntry { action, .. } => Some(keycode::from_action(action)),
+            _ => None,
+        }
+    }
+
+    fn set_keycode<L: LayerIndex>(
+        handlers: &mut [&mut dyn Handler<L>],
+        layer: u8,
+        row: u8,
+        col: u8,
+        keycode: u16,
+    ) -> bool {
+        let Some(action) = Self::to_action(keycode) else {
+            return false;
+        };
+        matches!(
+            handle(
+                handlers,
+                &Request::SetKeymapEntry {
+                    layer,
+                    row,
+                    col,
+                    action,
+                },
+            ),
+            Response::Ack
+        )
+    }
+
+    fn get_encoder_keycode<L: LayerIndex>(
+        handlers: &mut [&mut dyn Handler<L>],
+        layer: u8,
+        encoder_id: u8,
+        clockwise: u8,
+    ) -> Option<u16> {
+        // only a single rotary encoder is supported
+        if encoder_id != 0 {
+            return None;
+        }
+        let direction = Self::to_direction(clockwise);
+        match handle(handlers, &Request::GetEncoderEntry { layer, direction }) {
+            Response::EncoderEntry { action, .. } => Some(keycode::from_action(action)),
+            _ => None,
+        }
+    }
+
+    fn set_encoder_keycode<L: LayerIndex>(
+        handlers: &mut [&mut dyn Handler<L>],
+        layer: u8,
+        encoder_id: u8,
+        clockwise: u8,
+        keycode: u16,
+    ) -> bool {
+        if encoder_id != 0 {
+            return false;
+        }
+        let direction = Self::to_direction(clockwise);
+        let Some(action) = Self::to_action(keycode) else {
+            return false;
+        };
+        matches!(
+            handle(
+                handlers,
+                &Request::SetEncoderEntry {
+                    layer,
+                    direction,
+                    action,
+                },
+            ),
+            Response::Ack
+        )
+    }
+
+    fn get_rgb_matrix_value<L: LayerIndex>(
+        handlers: &mut [&mut dyn Handler<L>],
+        value_id: u8,
+    ) -> Option<u8> {
+        let Response::RGBSettings(settings) = handle(handlers, &Request::GetRGBSettings) else {
+            return None;
+        };
+        match value_id {
+            RGB_MATRIX_VALUE_BRIGHTNESS => Some(settings.brightness),
+            RGB_MATRIX_VALUE_EFFECT => Some(settings.animation_idx),
+            RGB_MATRIX_VALUE_EFFECT_SPEED => Some(settings.get_speed()),
+            _ => None,
+        }
+    }
+
+    fn set_rgb_matrix_value<L: LayerIndex>(
+        handlers: &mut [&mut dyn Handler<L>],
+        value_id: u8,
+        value: u8,
+    ) -> bool {
+        let Response::RGBSettings(mut settings) = handle(handlers, &Request::GetRGBSettings) else {
+            return false;
+        };
+        match value_id {
+            RGB_MATRIX_VALUE_BRIGHTNESS => settings.brightness = value,
+            RGB_MATRIX_VALUE_EFFECT => settings.animation_idx = value,
+            RGB_MATRIX_VALUE_EFFECT_SPEED => settings.set_speed(value),
+            _ => return false,
+        }
+        matches!(
+            handle(handlers, &Request::SetRGBSettings(settings)),
+            Response::Ack
+        )
+    }
+
+    // The dynamic keymap buffer is the keycodes of every layer, row, and col, in that order.
+    fn get_buffer<L: LayerIndex>(
+        &self,
+        handlers: &mut [&mut dyn Handler<L>],
+        report: &Report,
+        response: &mut Report,
+    ) -> bool {
+        let Some((offset, size)) = self.buffer_range(report) else {
+            return false;
+        };
+        for i in (0..size).step_by(2) {
+            let (layer, row, col) = self.buffer_position(offset + i);
+            let Some(keycode) = Self::get_keycode(handlers, layer, row, col) else {
+                return false;
+            };
+            response[BUFFER_HEADER_SIZE_BYTES + i..BUFFER_HEADER_SIZE_BYTES + i + 2]
+                .copy_from_slice(&keycode.to_be_bytes());
+        }
+        true
+    }
+
+    fn set_buffer<L: LayerIndex>(
+        &self,
+        handlers: &mut [&mut dyn Handler<L>],
+        report: &Report,
+    ) -> bool {
+        let Some((offset, size)) = self.buffer_range(report) else {
+            return false;
+        };
+        let keycode = |i: usize| {
+            u16::from_be_bytes([
+                report[BUFFER_HEADER_SIZE_BYTES + i],
+                report[BUFFER_HEADER_SIZE_BYTES + i + 1],
+            ])
+        };
+        // refuse the whole report rather than apply part of it
+        if (0..size)
+            .step_by(2)
+            .any(|i| Self::to_action::<L>(keycode(i)).is_none())
+        {
+            return false;
+        }
+        (0..size).step_by(2).all(|i| {
+            let (layer, row, col) = self.buffer_position(offset + i);
+            Self::set_keycode(handlers, layer, row, col, keycode(i))
+        })
+    }
+
+    fn buffer_range(&self, report: &Report) -> Option<(usize, usize)> {
+        let offset = u16::from_be_bytes([report[1], report[2]]) as usize;
+        let size = report[3] as usize;
+        let buffer_size_bytes =
+            self.layer_count as usize * self.row_count as usize * self.col_count as usize * 2;
+        // keycodes must not be split across reports
+        if offset % 2 != 0
+            || size % 2 != 0
+            || size > REPORT_SIZE_BYTES - BUFFER_HEADER_SIZE_BYTES
+            || offset + size > buffer_size_bytes
+        {
+            return None;
+        }
+        Some((offset, size))
+    }
+
+    fn buffer_position(&self, offset: usize) -> (u8, u8, u8) {
+        let idx = offset / 2;
+        let layer_size = self.row_count as usize * self.col_count as usize;
+        (
+            (idx / layer_size) as u8,
+            (idx % layer_size / self.col_count as usize) as u8,
+            (idx % self.col_count as usize) as u8,
+        )
+    }
+
+    // None for keycodes without an action, which are refused so that the entry is kept.
+    fn to_action<L: LayerIndex>(keycode: u16) -> Option<Action<L>> {
+        let action = keycode::to_action(keycode);
+        if action.is_none() {
+            defmt::warn!(
+                "unsupported keycode {=u16:#06x}, keeping the entry",
+                keycode
+            );
+        }
+        action
+    }
+
+    fn to_direction(clockwise: u8) -> Direction {
+        if clockwise != 0 {
+            Direction::Clockwise
+        } else {
+            Direction::CounterClockwise
+        }
+    }
+}
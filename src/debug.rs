@@ -1,4 +1,4 @@
-use alloc::string::String;
+use core::{fmt, mem};
 use defmt::Format;
 use rtic_monotonics::Monotonic;
 
@@ -94,7 +94,8 @@ pub enum LogStringTag {
 
 static mut LOG_STRING_COUNTER: u32 = 0;
 
-pub fn log_string(tag: LogStringTag, str: String) {
+// Takes format arguments rather than a string, so that skipped samples are never formatted.
+pub fn log_string(tag: LogStringTag, args: fmt::Arguments) {
     if !ENABLE_LOG_STRING {
         return;
     }
@@ -103,6 +104,36 @@ pub fn log_string(tag: LogStringTag, str: String) {
         LOG_STRING_COUNTER
     };
     if counter % LOG_STRING_SAMPLING_RATE == 0 {
-        defmt::trace!("[{}] ========= {}: {}", counter, tag, str.as_str());
+        defmt::trace!(
+            "[{}] ========= {}: {}",
+            counter,
+            tag,
+            defmt::Display2Format(&args)
+        );
     }
 }
+
+#[derive(Clone, Copy, Debug, Format)]
+#[repr(u8)]
+pub enum CounterTag {
+    InputScan,
+    InputWait,
+    ProcessorScan,
+    ProcessorError,
+    KeysDrop,
+    CommandRequest,
+}
+
+pub const COUNTER_COUNT: usize = mem::variant_count::<CounterTag>();
+
+static mut COUNTERS: [u32; COUNTER_COUNT] = [0; COUNTER_COUNT];
+
+pub fn increment_counter(tag: CounterTag) {
+    unsafe {
+        COUNTERS[tag as usize] = COUNTERS[tag as usize].wrapping_add(1);
+    }
+}
+
+pub fn get_counters() -> [u32; COUNTER_COUNT] {
+    unsafe { COUNTERS }
+}
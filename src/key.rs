@@ -1,10 +1,14 @@
 use core::mem;
 use defmt::Format;
 use enum_map::Enum;
+use serde::{
+    de::{self, DeserializeOwned},
+    Deserialize, Deserializer, Serialize,
+};
 use usbd_human_interface_device::page::Keyboard;
 
 #[allow(dead_code)]
-#[derive(Clone, Copy, Debug, Default, Format)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Format, PartialEq, Serialize)]
 pub enum Action<L: LayerIndex> {
     #[default]
     Pass,
@@ -16,7 +20,7 @@ pub enum Action<L: LayerIndex> {
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Copy, Debug, Format, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Format, PartialEq, Serialize)]
 #[repr(u16)]
 pub enum Key {
     Escape,
@@ -106,6 +110,45 @@ pub enum Key {
     VolumeDown,
 }
 
+impl Key {
+    // Keys are numbered from 0 in declaration order.
+    pub fn from_code(code: u16) -> Option<Key> {
+        if (code as usize) < mem::variant_count::<Key>() {
+            Some(unsafe { mem::transmute::<u16, Key>(code) })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_modifier(&self) -> bool {
+        matches!(
+            self,
+            Key::LeftShift
+                | Key::LeftControl
+                | Key::LeftAlt
+                | Key::LeftGUI
+                | Key::RightShift
+                | Key::RightControl
+                | Key::RightAlt
+                | Key::RightGUI
+        )
+    }
+
+    pub fn to_modifier(self) -> Modifier {
+        match self {
+            Key::LeftControl => Modifier::LeftControl,
+            Key::LeftShift => Modifier::LeftShift,
+            Key::LeftAlt => Modifier::LeftAlt,
+            Key::LeftGUI => Modifier::LeftGUI,
+            Key::RightControl => Modifier::RightControl,
+            Key::RightShift => Modifier::RightShift,
+            Key::RightAlt => Modifier::RightAlt,
+            Key::RightGUI => Modifier::RightGUI,
+            _ => Modifier::None,
+        }
+    }
+}
+
 impl<L: LayerIndex> From<Key> for Action<L> {
     fn from(from: Key) -> Action<L> {
         Action::Key(from)
@@ -220,25 +263,45 @@ pub enum Modifier {
     RightGUI = 1 << 7,
 }
 
+impl Modifier {
+    pub fn to_key(self) -> Option<Key> {
+        match self {
+            Modifier::LeftControl => Some(Key::LeftControl),
+            Modifier::LeftShift => Some(Key::LeftShift),
+            Modifier::LeftAlt => Some(Key::LeftAlt),
+            Modifier::LeftGUI => Some(Key::LeftGUI),
+            Modifier::RightControl => Some(Key::RightControl),
+            Modifier::RightShift => Some(Key::RightShift),
+            Modifier::RightAlt => Some(Key::RightAlt),
+            Modifier::RightGUI => Some(Key::RightGUI),
+            Modifier::None => None,
+        }
+    }
+}
+
 impl<L: LayerIndex> From<Modifier> for Action<L> {
     fn from(from: Modifier) -> Self {
-        match from {
-            Modifier::LeftControl => Action::Key(Key::LeftControl),
-            Modifier::LeftShift => Action::Key(Key::LeftShift),
-            Modifier::LeftAlt => Action::Key(Key::LeftAlt),
-            Modifier::LeftGUI => Action::Key(Key::LeftGUI),
-            Modifier::RightControl => Action::Key(Key::RightControl),
-            Modifier::RightShift => Action::Key(Key::RightShift),
-            Modifier::RightAlt => Action::Key(Key::RightAlt),
-            Modifier::RightGUI => Action::Key(Key::RightGUI),
-            _ => Action::None,
+        match from.to_key() {
+            Some(key) => Action::Key(key),
+            None => Action::None,
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Format)]
+#[derive(Clone, Copy, Debug, Format, PartialEq, Serialize)]
 pub struct ModifiedKey(pub u16);
 
+// The key is transmuted from the low byte, so codes that are no key must never get in.
+impl<'de> Deserialize<'de> for ModifiedKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = u16::deserialize(deserializer)?;
+        match Key::from_code(value & 0x00FF) {
+            Some(_) => Ok(ModifiedKey(value)),
+            None => Err(de::Error::custom("unknown key code")),
+        }
+    }
+}
+
 impl ModifiedKey {
     pub fn get_modifiers(self) -> [Modifier; 8] {
         let mut mods = [Default::default(); 8];
@@ -255,6 +318,14 @@ impl ModifiedKey {
     pub fn get_key(self) -> Key {
         unsafe { mem::transmute(self.0 & 0x00FF) }
     }
+
+    pub fn with_key(self, key: Key) -> Self {
+        ModifiedKey(self.0 & 0xFF00 | key as u16)
+    }
+
+    pub fn with_modifiers(self, modifiers: u8) -> Self {
+        ModifiedKey(self.0 & 0x00FF | (modifiers as u16) << 8)
+    }
 }
 
 macro_rules! LS {
@@ -306,7 +377,7 @@ macro_rules! RG {
 }
 
 #[allow(dead_code, clippy::enum_variant_names)]
-#[derive(Clone, Copy, Debug, Format, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Format, PartialEq, Serialize)]
 pub enum Control {
     U2FBootloaderJump,
     RGBAnimationNext,
@@ -316,9 +387,34 @@ pub enum Control {
     RGBBrightnessUp,
     RGBBrightnessDown,
     RGBDirectionToggle,
+    ProfileNext,
+    ProfileSelect(u8),
+    SocdToggle,
+    // Index into the processor pipeline, input stages first.
+    ProcessorToggle(u8),
+    HostModeNext,
+    HostModeSelect(HostMode),
 }
 
-pub trait LayerIndex: Copy + Default + PartialEq + PartialOrd + Enum + Format {}
+// Operating system of the host, which decides how modifiers are adapted to it.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Format, PartialEq, Serialize)]
+pub enum HostMode {
+    // Windows and Linux, keys as mapped
+    #[default]
+    Standard,
+    // Ctrl and GUI swap, so that Ctrl shortcuts land on Cmd
+    MacOS,
+    // Alt and GUI swap, so that Option and Cmd sit where they do on Mac keyboards
+    AltGUISwap,
+    // GUI keys do nothing, e.g. for gaming
+    GUILock,
+}
+
+pub trait LayerIndex:
+    Copy + Default + PartialEq + PartialOrd + Enum + Format + Serialize + DeserializeOwned
+{
+}
 
 #[derive(Clone, Copy, Debug, Default, Format, PartialEq)]
 pub enum Edge {
@@ -1,5 +1,6 @@
 use defmt::Format;
 use enum_map::{enum_map, Enum};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     key::{
@@ -11,7 +12,7 @@ use crate::{
     rotary::Direction,
 };
 
-#[derive(Clone, Copy, Default, Enum, Format, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Default, Deserialize, Enum, Format, PartialEq, PartialOrd, Serialize)]
 pub enum Layer {
     #[default]
     Base,
@@ -27,15 +28,16 @@ pub fn get_input_map() -> InputMap<
     <super::super::Keyboard as Configurator>::Layer,
 > {
     #[rustfmt::skip]
-    InputMap::new(
+    InputMap::from_layout(
+        <super::super::Keyboard as Configurator>::PHYSICAL_LAYOUT,
         enum_map! {
             Layer::Base => [
-                [K(Key::A),                    K(Key::B)],
-                [___________,                  LM(Layer::Function1)],
+                K(Key::A),                    K(Key::B),
+                ___________,                  LM(Layer::Function1),
             ],
             Layer::Function1 => [
-                [K(Key::C),                    K(Key::D)],
-                [C(Control::RGBAnimationNext), ___________],
+                K(Key::C),                    K(Key::D),
+                C(Control::RGBAnimationNext), ___________,
             ],
         },
         enum_map! {
@@ -9,8 +9,13 @@ use ws2812_pio::Ws2812Direct;
 
 use crate::{
     heartbeat::HeartbeatLED,
-    keyboard::{Configuration, Configurator},
-    matrix::BasicVerticalSwitchMatrix,
+    keyboard::{Configuration, Configurator, KeyMatrix},
+    matrix::{
+        direct::{DirectPinMatrix, DirectPinMatrixConfig},
+        switch::{SwitchMatrix, SwitchMatrixConfig},
+        AnyScanner,
+    },
+    physical::PhysicalKey,
     processor::events::rgb::RGBMatrix,
     remote::transport::uart::{UartReceiver, UartSender},
     rotary::{Mode, RotaryEncoder},
@@ -18,6 +23,7 @@ use crate::{
 
 const ENABLE_HEARTBEAT_LED: bool = true;
 const ENABLE_KEY_MATRIX: bool = true;
+const ENABLE_KEY_MATRIX_DIRECT_PINS: bool = false;
 const ENABLE_ROTARY_ENCODER: bool = true;
 const ENABLE_RGB_MATRIX: bool = true;
 
@@ -29,6 +35,12 @@ impl Configurator for Keyboard {
     const KEY_MATRIX_ROW_COUNT: usize = 2;
     const KEY_MATRIX_COL_COUNT: usize = 2;
 
+    #[rustfmt::skip]
+    const PHYSICAL_LAYOUT: &'static [PhysicalKey] = &[
+        PhysicalKey::new(0, 0, 50, 50), PhysicalKey::new(0, 1, 150, 50),
+        PhysicalKey::new(1, 0, 50, 150), PhysicalKey::new(1, 1, 150, 150),
+    ];
+
     const RGB_MATRIX_LED_COUNT: usize = 4;
 
     fn init(
@@ -36,6 +48,7 @@ impl Configurator for Keyboard {
         mut slices: pwm::Slices,
         mut pio0: pio::PIO<pac::PIO0>,
         sm0: pio::UninitStateMachine<(pac::PIO0, pio::SM0)>,
+        _sm1: pio::UninitStateMachine<(pac::PIO0, pio::SM1)>,
         _i2c1: pac::I2C1,
         _uart0: pac::UART0,
         _resets: &mut pac::RESETS,
@@ -46,20 +59,31 @@ impl Configurator for Keyboard {
         Option<(Arbiter<Rc<RefCell<UartSender>>>, UartReceiver)>,
     ) {
         #[rustfmt::skip]
-        let key_matrix = if ENABLE_KEY_MATRIX {
-            Some(BasicVerticalSwitchMatrix::new(
-                [
-                    Box::new(pins.gpio21.into_pull_down_input()),
-                    Box::new(pins.gpio20.into_pull_down_input()),
-                ],
-                [
-                    Box::new(pins.gpio0.into_push_pull_output()),
-                    Box::new(pins.gpio1.into_push_pull_output()),
-                ],
-            ))
-        } else {
-            None
-        };
+        let key_matrix: Option<KeyMatrix> =
+            if ENABLE_KEY_MATRIX && ENABLE_KEY_MATRIX_DIRECT_PINS {
+                // Same four pins, each wired to its own switch and ground
+                Some(AnyScanner::Direct(DirectPinMatrix::new(
+                    [
+                        [Some(pins.gpio0.into_dyn_pin()), Some(pins.gpio1.into_dyn_pin())],
+                        [Some(pins.gpio20.into_dyn_pin()), Some(pins.gpio21.into_dyn_pin())],
+                    ],
+                    DirectPinMatrixConfig::default(),
+                )))
+            } else if ENABLE_KEY_MATRIX {
+                Some(AnyScanner::Switch(SwitchMatrix::new(
+                    [
+                        pins.gpio21.into_dyn_pin(),
+                        pins.gpio20.into_dyn_pin(),
+                    ],
+                    [
+                        pins.gpio0.into_dyn_pin(),
+                        pins.gpio1.into_dyn_pin(),
+                    ],
+                    SwitchMatrixConfig::default(),
+                )))
+            } else {
+                None
+            };
 
         let rotary_encoder = if ENABLE_ROTARY_ENCODER {
             Some(RotaryEncoder::new(
@@ -9,8 +9,12 @@ use ws2812_pio::Ws2812Direct;
 
 use crate::{
     heartbeat::HeartbeatLED,
-    keyboard::{Configuration, Configurator},
-    matrix::BasicVerticalSwitchMatrix,
+    keyboard::{Configuration, Configurator, KeyMatrix},
+    matrix::{
+        switch::{SwitchMatrix, SwitchMatrixConfig},
+        AnyScanner,
+    },
+    physical::PhysicalKey,
     processor::events::rgb::RGBMatrix,
     remote::transport::uart::{UartReceiver, UartSender},
     rotary::{Mode, RotaryEncoder},
@@ -29,6 +33,15 @@ impl Configurator for Keyboard {
     const KEY_MATRIX_ROW_COUNT: usize = 5;
     const KEY_MATRIX_COL_COUNT: usize = 15;
 
+    #[rustfmt::skip]
+    const PHYSICAL_LAYOUT: &'static [PhysicalKey] = &[
+        PhysicalKey::new(0, 0, 50, 50), PhysicalKey::new(0, 1, 150, 50), PhysicalKey::new(0, 2, 250, 50), PhysicalKey::new(0, 3, 350, 50), PhysicalKey::new(0, 4, 450, 50), PhysicalKey::new(0, 5, 550, 50), PhysicalKey::new(0, 6, 650, 50), PhysicalKey::new(0, 7, 750, 50), PhysicalKey::new(0, 8, 850, 50), PhysicalKey::new(0, 9, 950, 50), PhysicalKey::new(0, 10, 1050, 50), PhysicalKey::new(0, 11, 1150, 50), PhysicalKey::new(0, 12, 1250, 50), PhysicalKey::new(0, 13, 1400, 50), PhysicalKey::new(0, 14, 1550, 50),
+        PhysicalKey::new(1, 0, 75, 150), PhysicalKey::new(1, 1, 200, 150), PhysicalKey::new(1, 2, 300, 150), PhysicalKey::new(1, 3, 400, 150), PhysicalKey::new(1, 4, 500, 150), PhysicalKey::new(1, 5, 600, 150), PhysicalKey::new(1, 6, 700, 150), PhysicalKey::new(1, 7, 800, 150), PhysicalKey::new(1, 8, 900, 150), PhysicalKey::new(1, 9, 1000, 150), PhysicalKey::new(1, 10, 1100, 150), PhysicalKey::new(1, 11, 1200, 150), PhysicalKey::new(1, 12, 1300, 150), PhysicalKey::new(1, 13, 1425, 150), PhysicalKey::new(1, 14, 1550, 150),
+        PhysicalKey::new(2, 0, 87, 250), PhysicalKey::new(2, 1, 225, 250), PhysicalKey::new(2, 2, 325, 250), PhysicalKey::new(2, 3, 425, 250), PhysicalKey::new(2, 4, 525, 250), PhysicalKey::new(2, 5, 625, 250), PhysicalKey::new(2, 6, 725, 250), PhysicalKey::new(2, 7, 825, 250), PhysicalKey::new(2, 8, 925, 250), PhysicalKey::new(2, 9, 1025, 250), PhysicalKey::new(2, 10, 1125, 250), PhysicalKey::new(2, 11, 1225, 250), PhysicalKey::new(2, 13, 1387, 250), PhysicalKey::new(2, 14, 1550, 250),
+        PhysicalKey::new(3, 0, 112, 350), PhysicalKey::new(3, 1, 275, 350), PhysicalKey::new(3, 2, 375, 350), PhysicalKey::new(3, 3, 475, 350), PhysicalKey::new(3, 4, 575, 350), PhysicalKey::new(3, 5, 675, 350), PhysicalKey::new(3, 6, 775, 350), PhysicalKey::new(3, 7, 875, 350), PhysicalKey::new(3, 8, 975, 350), PhysicalKey::new(3, 9, 1075, 350), PhysicalKey::new(3, 10, 1175, 350), PhysicalKey::new(3, 12, 1312, 350), PhysicalKey::new(3, 13, 1450, 350), PhysicalKey::new(3, 14, 1550, 350),
+        PhysicalKey::new(4, 0, 62, 450), PhysicalKey::new(4, 1, 187, 450), PhysicalKey::new(4, 2, 312, 450), PhysicalKey::new(4, 6, 687, 450), PhysicalKey::new(4, 10, 1050, 450), PhysicalKey::new(4, 11, 1150, 450), PhysicalKey::new(4, 12, 1350, 450), PhysicalKey::new(4, 13, 1450, 450), PhysicalKey::new(4, 14, 1550, 450),
+    ];
+
     const RGB_MATRIX_LED_COUNT: usize = 67;
 
     fn init(
@@ -36,6 +49,7 @@ impl Configurator for Keyboard {
         mut slices: pwm::Slices,
         mut pio0: pio::PIO<pac::PIO0>,
         sm0: pio::UninitStateMachine<(pac::PIO0, pio::SM0)>,
+        _sm1: pio::UninitStateMachine<(pac::PIO0, pio::SM1)>,
         _i2c1: pac::I2C1,
         _uart0: pac::UART0,
         _resets: &mut pac::RESETS,
@@ -46,33 +60,34 @@ impl Configurator for Keyboard {
         Option<(Arbiter<Rc<RefCell<UartSender>>>, UartReceiver)>,
     ) {
         #[rustfmt::skip]
-        let key_matrix = if ENABLE_KEY_MATRIX {
-            Some(BasicVerticalSwitchMatrix::new(
+        let key_matrix: Option<KeyMatrix> = if ENABLE_KEY_MATRIX {
+            Some(AnyScanner::Switch(SwitchMatrix::new(
                 [
-                    Box::new(pins.gpio24.into_pull_down_input()),
-                    Box::new(pins.gpio23.into_pull_down_input()),
-                    Box::new(pins.gpio22.into_pull_down_input()),
-                    Box::new(pins.gpio21.into_pull_down_input()),
-                    Box::new(pins.gpio20.into_pull_down_input()),
+                    pins.gpio24.into_dyn_pin(),
+                    pins.gpio23.into_dyn_pin(),
+                    pins.gpio22.into_dyn_pin(),
+                    pins.gpio21.into_dyn_pin(),
+                    pins.gpio20.into_dyn_pin(),
                 ],
                 [
-                    Box::new(pins.gpio0.into_push_pull_output()),
-                    Box::new(pins.gpio1.into_push_pull_output()),
-                    Box::new(pins.gpio2.into_push_pull_output()),
-                    Box::new(pins.gpio3.into_push_pull_output()),
-                    Box::new(pins.gpio4.into_push_pull_output()),
-                    Box::new(pins.gpio5.into_push_pull_output()),
-                    Box::new(pins.gpio6.into_push_pull_output()),
-                    Box::new(pins.gpio7.into_push_pull_output()),
-                    Box::new(pins.gpio8.into_push_pull_output()),
-                    Box::new(pins.gpio9.into_push_pull_output()),
-                    Box::new(pins.gpio10.into_push_pull_output()),
-                    Box::new(pins.gpio11.into_push_pull_output()),
-                    Box::new(pins.gpio12.into_push_pull_output()),
-                    Box::new(pins.gpio13.into_push_pull_output()),
-                    Box::new(pins.gpio14.into_push_pull_output()),
+                    pins.gpio0.into_dyn_pin(),
+                    pins.gpio1.into_dyn_pin(),
+                    pins.gpio2.into_dyn_pin(),
+                    pins.gpio3.into_dyn_pin(),
+                    pins.gpio4.into_dyn_pin(),
+                    pins.gpio5.into_dyn_pin(),
+                    pins.gpio6.into_dyn_pin(),
+                    pins.gpio7.into_dyn_pin(),
+                    pins.gpio8.into_dyn_pin(),
+                    pins.gpio9.into_dyn_pin(),
+                    pins.gpio10.into_dyn_pin(),
+                    pins.gpio11.into_dyn_pin(),
+                    pins.gpio12.into_dyn_pin(),
+                    pins.gpio13.into_dyn_pin(),
+                    pins.gpio14.into_dyn_pin(),
                 ],
-            ))
+                SwitchMatrixConfig::default(),
+            )))
         } else {
             None
         };
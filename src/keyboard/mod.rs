@@ -8,9 +8,15 @@ use ws2812_pio::Ws2812Direct;
 use crate::{
     heartbeat::HeartbeatLED,
     key::LayerIndex,
-    matrix::{BasicVerticalSwitchMatrix, SplitSwitchMatrix},
+    matrix::{AnyScanner, SplitHalf, SplitSwitchMatrix},
     oled::OLEDDisplay,
-    processor::{events::rgb::RGBMatrix, mapper::InputMap},
+    physical::PhysicalKey,
+    processor::{
+        events::rgb::RGBMatrix,
+        input::{debounce::DebounceAlgorithm, socd::SocdPair},
+        mapper::InputMap,
+        pipeline::{ErrorPolicy, EventsStage, InputStage},
+    },
     remote::transport::uart::{UartReceiver, UartSender},
     rotary::RotaryEncoder,
     status::StatusLED,
@@ -33,20 +39,22 @@ mod quadax_rift;
 #[cfg(keyboard = "quadax_rift")]
 use quadax_rift as selected_keyboard;
 
+pub type KeyMatrix = AnyScanner<
+    { selected_keyboard::Keyboard::KEY_MATRIX_ROW_COUNT },
+    { selected_keyboard::Keyboard::KEY_MATRIX_COL_COUNT },
+>;
+
+pub type KeyMatrixSplit = SplitSwitchMatrix<
+    { selected_keyboard::Keyboard::KEY_MATRIX_ROW_COUNT },
+    { selected_keyboard::Keyboard::KEY_MATRIX_COL_COUNT },
+    { selected_keyboard::Keyboard::KEY_MATRIX_HALF_ROW_COUNT },
+    { selected_keyboard::Keyboard::KEY_MATRIX_HALF_COL_COUNT },
+>;
+
 #[derive(Default)]
 pub struct Configuration {
-    pub key_matrix: Option<
-        BasicVerticalSwitchMatrix<
-            { selected_keyboard::Keyboard::KEY_MATRIX_ROW_COUNT },
-            { selected_keyboard::Keyboard::KEY_MATRIX_COL_COUNT },
-        >,
-    >,
-    pub key_matrix_split: Option<
-        SplitSwitchMatrix<
-            { selected_keyboard::Keyboard::KEY_MATRIX_ROW_COUNT },
-            { selected_keyboard::Keyboard::KEY_MATRIX_COL_COUNT },
-        >,
-    >,
+    pub key_matrix: Option<KeyMatrix>,
+    pub key_matrix_split: Option<KeyMatrixSplit>,
     pub rotary_encoder: Option<RotaryEncoder>,
     pub heartbeat_led: Option<HeartbeatLED>,
     // TODO: configurable RGB matrix pinout
@@ -92,13 +100,84 @@ pub trait Configurator {
     const KEY_MATRIX_ROW_COUNT: usize;
     const KEY_MATRIX_COL_COUNT: usize;
 
+    // Matrix of each half of a split keyboard and its place in the merged matrix. Halves
+    // default to splitting the columns evenly.
+    const KEY_MATRIX_SPLIT_LEFT: SplitHalf = SplitHalf::new(
+        Self::KEY_MATRIX_ROW_COUNT,
+        Self::KEY_MATRIX_COL_COUNT / 2,
+        0,
+        0,
+    );
+    const KEY_MATRIX_SPLIT_RIGHT: SplitHalf = SplitHalf::new(
+        Self::KEY_MATRIX_ROW_COUNT,
+        Self::KEY_MATRIX_COL_COUNT - Self::KEY_MATRIX_COL_COUNT / 2,
+        0,
+        Self::KEY_MATRIX_COL_COUNT / 2,
+    );
+    // Size every half is scanned as, large enough for either of them.
+    const KEY_MATRIX_HALF_ROW_COUNT: usize =
+        if Self::KEY_MATRIX_SPLIT_LEFT.row_count > Self::KEY_MATRIX_SPLIT_RIGHT.row_count {
+            Self::KEY_MATRIX_SPLIT_LEFT.row_count
+        } else {
+            Self::KEY_MATRIX_SPLIT_RIGHT.row_count
+        };
+    const KEY_MATRIX_HALF_COL_COUNT: usize =
+        if Self::KEY_MATRIX_SPLIT_LEFT.col_count > Self::KEY_MATRIX_SPLIT_RIGHT.col_count {
+            Self::KEY_MATRIX_SPLIT_LEFT.col_count
+        } else {
+            Self::KEY_MATRIX_SPLIT_RIGHT.col_count
+        };
+
+    const KEY_MATRIX_DEBOUNCE_ALGORITHM: DebounceAlgorithm = DebounceAlgorithm::EagerPerKey;
+    const KEY_MATRIX_DEBOUNCE_DELAY_MICROS: u64 = 10_000;
+    // Keys that need a different delay than the rest, as (row, col, delay in microseconds).
+    const KEY_MATRIX_DEBOUNCE_OVERRIDES: &'static [(usize, usize, u64)] = &[];
+
+    // Contacts closer than this to the previous release of the same key count as bounces.
+    const KEY_MATRIX_CHATTER_WINDOW_MICROS: u64 = 30_000;
+    // Locks chattering keys out for longer after each change instead of only reporting them.
+    const KEY_MATRIX_CHATTER_ADAPTIVE_DEBOUNCE: bool = false;
+
+    // Opposing key pairs cleaned by the SOCD processor, and whether it starts enabled. It can
+    // be toggled with `Control::SocdToggle`.
+    const SOCD_PAIRS: &'static [SocdPair] = &[];
+    const SOCD_ENABLED: bool = false;
+
+    // Processors the key matrix and then the mapped events go through, in order. Chatter is
+    // looked for in the raw scans ahead of debouncing, SOCD cleans the end result. Add
+    // `InputStage::AntiGhost` for matrices wired without diodes.
+    const INPUT_PROCESSORS: &'static [InputStage] =
+        &[InputStage::Chatter, InputStage::Debounce, InputStage::Socd];
+    const EVENTS_PROCESSORS: &'static [EventsStage] = &[
+        EventsStage::RGB,
+        EventsStage::Profile,
+        EventsStage::System,
+        EventsStage::Socd,
+        EventsStage::HostMode,
+    ];
+    // Stages that recover from failing other than by dropping the pass, as (stage index, policy).
+    const PROCESSOR_ERROR_POLICIES: &'static [(usize, ErrorPolicy)] = &[];
+
+    // Every key on the board, in the order the layouts list their actions.
+    const PHYSICAL_LAYOUT: &'static [PhysicalKey];
+
     const RGB_MATRIX_LED_COUNT: usize;
 
+    const PROFILE_COUNT: usize = 1;
+
+    // GPIO the bootloader blinks on USB activity.
+    const U2F_ACTIVITY_PIN: u8 = 29;
+
+    // Local key matrix positions held at power-on, as (row, col).
+    const BOOTMAGIC_BOOTLOADER_KEY: Option<(usize, usize)> = None;
+    const BOOTMAGIC_CLEAR_SETTINGS_KEY: Option<(usize, usize)> = None;
+
     fn init(
         pins: gpio::Pins,
         slices: pwm::Slices,
         pio0: pio::PIO<pac::PIO0>,
         sm0: pio::UninitStateMachine<(pac::PIO0, pio::SM0)>,
+        sm1: pio::UninitStateMachine<(pac::PIO0, pio::SM1)>,
         i2c1: pac::I2C1,
         uart0: pac::UART0,
         resets: &mut pac::RESETS,
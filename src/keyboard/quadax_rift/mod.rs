@@ -13,8 +13,13 @@ use ws2812_pio::Ws2812Direct;
 use crate::{
     heartbeat::HeartbeatLED,
     keyboard::{Configuration, Configurator},
-    matrix::{BasicVerticalSwitchMatrix, SplitSwitchMatrix},
+    matrix::{
+        pio::{PioMatrix, PioMatrixConfig},
+        switch::{SwitchMatrix, SwitchMatrixConfig},
+        AnyScanner, SplitHalf, SplitSwitchMatrix,
+    },
     oled::OLEDDisplay,
+    physical::PhysicalKey,
     processor::events::rgb::RGBMatrix,
     remote::transport::uart::{UartReceiver, UartSender},
     rotary::{Mode, RotaryEncoder},
@@ -24,6 +29,7 @@ use crate::{
 
 const ENABLE_HEARTBEAT_LED: bool = true;
 const ENABLE_KEY_MATRIX: bool = true;
+const ENABLE_KEY_MATRIX_PIO: bool = false;
 const ENABLE_ROTARY_ENCODER: bool = true;
 const ENABLE_RGB_MATRIX: bool = true;
 const ENABLE_OLED_SCREEN: bool = true;
@@ -37,13 +43,31 @@ impl Configurator for Keyboard {
     const KEY_MATRIX_ROW_COUNT: usize = 5;
     const KEY_MATRIX_COL_COUNT: usize = 14;
 
+    const KEY_MATRIX_SPLIT_LEFT: SplitHalf = SplitHalf::new(5, 7, 0, 0);
+    const KEY_MATRIX_SPLIT_RIGHT: SplitHalf = SplitHalf::new(5, 7, 0, 7);
+
+    #[rustfmt::skip]
+    const PHYSICAL_LAYOUT: &'static [PhysicalKey] = &[
+        PhysicalKey::new(0, 0, 50, 50), PhysicalKey::new(0, 1, 150, 50), PhysicalKey::new(0, 2, 250, 50), PhysicalKey::new(0, 3, 350, 50), PhysicalKey::new(0, 4, 450, 50), PhysicalKey::new(0, 5, 550, 50), PhysicalKey::new(0, 12, 950, 50), PhysicalKey::new(0, 11, 1050, 50), PhysicalKey::new(0, 10, 1150, 50), PhysicalKey::new(0, 9, 1250, 50), PhysicalKey::new(0, 8, 1350, 50), PhysicalKey::new(0, 7, 1450, 50),
+        PhysicalKey::new(1, 0, 50, 150), PhysicalKey::new(1, 1, 150, 150), PhysicalKey::new(1, 2, 250, 150), PhysicalKey::new(1, 3, 350, 150), PhysicalKey::new(1, 4, 450, 150), PhysicalKey::new(1, 5, 550, 150), PhysicalKey::new(1, 12, 950, 150), PhysicalKey::new(1, 11, 1050, 150), PhysicalKey::new(1, 10, 1150, 150), PhysicalKey::new(1, 9, 1250, 150), PhysicalKey::new(1, 8, 1350, 150), PhysicalKey::new(1, 7, 1450, 150),
+        PhysicalKey::new(2, 0, 50, 250), PhysicalKey::new(2, 1, 150, 250), PhysicalKey::new(2, 2, 250, 250), PhysicalKey::new(2, 3, 350, 250), PhysicalKey::new(2, 4, 450, 250), PhysicalKey::new(2, 5, 550, 250), PhysicalKey::new(2, 12, 950, 250), PhysicalKey::new(2, 11, 1050, 250), PhysicalKey::new(2, 10, 1150, 250), PhysicalKey::new(2, 9, 1250, 250), PhysicalKey::new(2, 8, 1350, 250), PhysicalKey::new(2, 7, 1450, 250),
+        PhysicalKey::new(3, 0, 50, 350), PhysicalKey::new(3, 1, 150, 350), PhysicalKey::new(3, 2, 250, 350), PhysicalKey::new(3, 3, 350, 350), PhysicalKey::new(3, 4, 450, 350), PhysicalKey::new(3, 5, 550, 350), PhysicalKey::new(3, 6, 650, 350), PhysicalKey::new(3, 12, 950, 350), PhysicalKey::new(3, 11, 1050, 350), PhysicalKey::new(3, 10, 1150, 350), PhysicalKey::new(3, 9, 1250, 350), PhysicalKey::new(3, 8, 1350, 350), PhysicalKey::new(3, 7, 1450, 350),
+        PhysicalKey::new(4, 0, 50, 450), PhysicalKey::new(4, 1, 150, 450), PhysicalKey::new(4, 2, 250, 450), PhysicalKey::new(4, 3, 350, 450), PhysicalKey::new(4, 4, 450, 450), PhysicalKey::new(4, 5, 550, 450), PhysicalKey::new(4, 12, 950, 450), PhysicalKey::new(4, 11, 1050, 450), PhysicalKey::new(4, 10, 1150, 450), PhysicalKey::new(4, 9, 1250, 450), PhysicalKey::new(4, 8, 1350, 450),
+    ];
+
     const RGB_MATRIX_LED_COUNT: usize = 67;
 
+    const PROFILE_COUNT: usize = 2;
+
+    const BOOTMAGIC_BOOTLOADER_KEY: Option<(usize, usize)> = Some((0, 0));
+    const BOOTMAGIC_CLEAR_SETTINGS_KEY: Option<(usize, usize)> = Some((1, 0));
+
     fn init(
         pins: gpio::Pins,
         mut slices: pwm::Slices,
         mut pio0: pio::PIO<pac::PIO0>,
         sm0: pio::UninitStateMachine<(pac::PIO0, pio::SM0)>,
+        sm1: pio::UninitStateMachine<(pac::PIO0, pio::SM1)>,
         i2c1: pac::I2C1,
         uart0: pac::UART0,
         resets: &mut pac::RESETS,
@@ -57,24 +81,39 @@ impl Configurator for Keyboard {
 
         #[rustfmt::skip]
         let key_matrix_split = if ENABLE_KEY_MATRIX {
-            Some(SplitSwitchMatrix::new(BasicVerticalSwitchMatrix::new(
-                [
-                    Box::new(pins.gpio10.into_pull_down_input()),
-                    Box::new(pins.gpio11.into_pull_down_input()),
-                    Box::new(pins.gpio12.into_pull_down_input()),
-                    Box::new(pins.gpio13.into_pull_down_input()),
-                    Box::new(pins.gpio14.into_pull_down_input()),
-                ],
-                [
-                    Box::new(pins.gpio3.into_push_pull_output()),
-                    Box::new(pins.gpio4.into_push_pull_output()),
-                    Box::new(pins.gpio5.into_push_pull_output()),
-                    Box::new(pins.gpio6.into_push_pull_output()),
-                    Box::new(pins.gpio7.into_push_pull_output()),
-                    Box::new(pins.gpio8.into_push_pull_output()),
-                    Box::new(pins.gpio9.into_push_pull_output()),
-                ],
-            )))
+            let rows = [
+                pins.gpio10.into_dyn_pin(),
+                pins.gpio11.into_dyn_pin(),
+                pins.gpio12.into_dyn_pin(),
+                pins.gpio13.into_dyn_pin(),
+                pins.gpio14.into_dyn_pin(),
+            ];
+            let cols = [
+                pins.gpio3.into_dyn_pin(),
+                pins.gpio4.into_dyn_pin(),
+                pins.gpio5.into_dyn_pin(),
+                pins.gpio6.into_dyn_pin(),
+                pins.gpio7.into_dyn_pin(),
+                pins.gpio8.into_dyn_pin(),
+                pins.gpio9.into_dyn_pin(),
+            ];
+            let local_matrix = if ENABLE_KEY_MATRIX_PIO {
+                AnyScanner::Pio(PioMatrix::new(
+                    rows,
+                    cols,
+                    PioMatrixConfig::default(),
+                    &mut pio0,
+                    sm1,
+                    clock_freq,
+                ))
+            } else {
+                AnyScanner::Switch(SwitchMatrix::new(rows, cols, SwitchMatrixConfig::default()))
+            };
+            Some(SplitSwitchMatrix::new(
+                local_matrix,
+                Keyboard::KEY_MATRIX_SPLIT_LEFT,
+                Keyboard::KEY_MATRIX_SPLIT_RIGHT,
+            ))
         } else {
             None
         };
@@ -1,8 +1,7 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(type_alias_impl_trait)]
 #![feature(associated_type_defaults)]
-#![feature(trait_alias)]
 #![feature(async_closure)]
 #![feature(generic_const_exprs)]
 #![feature(future_join)]
@@ -14,6 +13,8 @@
 #![allow(clippy::type_complexity)]
 #![allow(clippy::too_many_arguments)]
 #![allow(clippy::await_holding_refcell_ref)]
+mod bootmagic;
+mod command;
 mod debug;
 mod heartbeat;
 #[macro_use]
@@ -21,18 +22,51 @@ mod key;
 mod keyboard;
 mod matrix;
 mod oled;
+mod physical;
 mod processor;
 mod remote;
+mod report;
 mod rotary;
 mod split;
 mod status;
+mod storage;
 mod util;
 
 #[macro_use]
 extern crate alloc;
 extern crate rp2040_hal as hal;
+#[cfg(not(test))]
 use {defmt_rtt as _, panic_probe as _};
 
+// Host builds of the unit tests get just the pieces of the app that other
+// modules reach into; the RTIC app itself only exists on the target.
+#[cfg(test)]
+mod kb {
+    use rtic_monotonics::rp2040::prelude::*;
+
+    rp2040_timer_monotonic!(Mono);
+
+    pub static HEAP: embedded_alloc::Heap = embedded_alloc::Heap::empty();
+
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    #[defmt::panic_handler]
+    fn defmt_panic() -> ! {
+        core::panic!()
+    }
+
+    defmt::timestamp!("{=u64:us}", 0);
+}
+
+#[cfg(not(test))]
 #[rtic::app(
     device = hal::pac,
     dispatchers = [TIMER_IRQ_1, TIMER_IRQ_2, TIMER_IRQ_3]
@@ -50,19 +84,20 @@ mod kb {
     static mut HEAP_MEM: [core::mem::MaybeUninit<u8>; HEAP_SIZE_BYTES] =
         [core::mem::MaybeUninit::uninit(); HEAP_SIZE_BYTES];
 
-    use alloc::{boxed::Box, rc::Rc, vec::Vec};
+    use alloc::{boxed::Box, rc::Rc, string::String, vec::Vec};
     use core::{cell::RefCell, fmt::Write};
     use hal::{
         clocks::init_clocks_and_plls,
         gpio, pac,
         pio::{self, PIOExt},
-        pwm, sio, usb, Clock, Sio, Watchdog,
+        pwm, rom_data, sio, usb, Clock, Sio, Watchdog,
     };
     use rtic_monotonics::rp2040::prelude::*;
     use rtic_sync::{
         arbiter::Arbiter,
         channel::{Receiver, Sender},
     };
+    use ssd1306::prelude::I2CInterface;
     use usb_device::{class_prelude::*, prelude::*, UsbError};
     use usbd_human_interface_device::{
         device::keyboard::{NKROBootKeyboard, NKROBootKeyboardConfig},
@@ -71,19 +106,38 @@ mod kb {
     };
 
     use crate::{
+        bootmagic::{self, Bootmagic},
+        command::{
+            self,
+            raw_hid::{CommandHid, RawHidConfig, ViaHid},
+            via::Via,
+            Dispatcher, FirmwareInfo, Frame, Handler, PROTOCOL_VERSION,
+        },
         debug,
         heartbeat::HeartbeatLED,
-        key::{Action, Edge, Key},
-        keyboard::{Configuration, Configurator, Keyboard},
-        matrix::{SplitScanner, SplitSwitchMatrix},
+        key::{Edge, HostMode},
+        keyboard::{Configuration, Configurator, KeyMatrix, KeyMatrixSplit, Keyboard},
+        matrix::{Scanner, SplitScanner},
+        oled::OLEDDisplay,
         processor::{
             events::{
-                rgb::{FrameIterator, RGBMatrix, RGBProcessor},
+                hold::HoldLimitProcessor,
+                host::HostModeProcessor,
+                profile::{ActiveProfile, ProfileProcessor},
+                replace::KeyReplaceProcessor,
+                rgb::{Frame as RGBFrame, RGBMatrix, RGBProcessor, RGBSettings},
                 system::SystemProcessor,
+                tap_hold::TapHoldProcessor,
+            },
+            input::{
+                chatter::ChatterProcessor,
+                debounce::KeyMatrixDebounceProcessor,
+                ghost::AntiGhostProcessor,
+                socd::{SocdProcessor, SocdSettings},
             },
-            input::debounce::KeyMatrixRisingFallingDebounceProcessor,
-            mapper::{Input, Mapper},
-            Event, EventsProcessor, InputProcessor,
+            mapper::{Input, InputMap, InputMapRecord, Mapper},
+            pipeline::{ErrorPolicy, EventsStage, InputStage, Pipeline, PipelineSettings},
+            EventQueue, Events, EventsProcessor, InputProcessor,
         },
         remote::{
             self,
@@ -93,9 +147,11 @@ mod kb {
             },
             Server,
         },
+        report::{Report, ReportState},
         rotary::RotaryEncoder,
         split,
         status::StatusLED,
+        storage::{flash::Rp2040Flash, Autosave, Store},
     };
 
     rp2040_timer_monotonic!(Mono);
@@ -103,7 +159,8 @@ mod kb {
     const XOSC_CRYSTAL_FREQ: u32 = 12_000_000;
 
     const INPUT_CHANNEL_BUFFER_SIZE: usize = 1;
-    const KEYS_CHANNEL_BUFFER_SIZE: usize = 1;
+    const KEYS_CHANNEL_BUFFER_SIZE: usize = 8;
+    const COMMAND_CHANNEL_BUFFER_SIZE: usize = 4;
 
     const INPUT_SCANNER_TARGET_POLL_FREQ: u64 = 1000;
     const HID_REPORTER_TARGET_POLL_FREQ: u64 = 1000;
@@ -112,6 +169,13 @@ mod kb {
     const HID_REPORTER_TARGET_POLL_PERIOD_MICROS: u64 =
         1_000_000u64 / HID_REPORTER_TARGET_POLL_FREQ;
 
+    const SETTINGS_AUTOSAVE_DELAY_MICROS: u64 = 5_000_000;
+
+    // Keys are all released when no input comes from the scanner, or no report goes out to the
+    // host, for this long, e.g. because the split link or USB stalled.
+    const INPUT_STALL_TIMEOUT_MICROS: u64 = 100_000;
+    const HID_STALL_TIMEOUT_MICROS: u64 = 100_000;
+
     #[shared]
     struct Shared {
         is_usb_connected: bool,
@@ -119,7 +183,11 @@ mod kb {
         usb_keyboard: UsbHidClass<
             'static,
             usb::UsbBus,
-            frunk::HList!(NKROBootKeyboard<'static, usb::UsbBus>),
+            frunk::HList!(
+                NKROBootKeyboard<'static, usb::UsbBus>,
+                CommandHid<'static, usb::UsbBus>,
+                ViaHid<'static, usb::UsbBus>,
+            ),
         >,
         transport_sender: Option<Arbiter<Rc<RefCell<UartSender>>>>,
     }
@@ -127,6 +195,7 @@ mod kb {
     #[local]
     struct Local {
         transport_receiver: Option<UartReceiver>,
+        command_sender: Sender<'static, Frame, COMMAND_CHANNEL_BUFFER_SIZE>,
     }
 
     #[init(local = [usb_allocator: Option<UsbBusAllocator<usb::UsbBus>> = None])]
@@ -162,8 +231,14 @@ mod kb {
         // Init channels
         let (input_sender, input_receiver) = rtic_sync::make_channel!(Input<{<Keyboard as Configurator>::KEY_MATRIX_ROW_COUNT}, {<Keyboard as Configurator>::KEY_MATRIX_COL_COUNT}>, INPUT_CHANNEL_BUFFER_SIZE);
         let (keys_sender, keys_receiver) =
-            rtic_sync::make_channel!(Vec<Key>, KEYS_CHANNEL_BUFFER_SIZE);
-        let (frame_sender, frame_receiver) = rtic_sync::make_channel!(Box<dyn FrameIterator>, 1);
+            rtic_sync::make_channel!(Report, KEYS_CHANNEL_BUFFER_SIZE);
+        let (frame_sender, frame_receiver) = rtic_sync::make_channel!(
+            RGBFrame<{ <Keyboard as Configurator>::RGB_MATRIX_LED_COUNT }>,
+            1
+        );
+        let (oled_sender, oled_receiver) = rtic_sync::make_channel!(String, 1);
+        let (command_sender, command_receiver) =
+            rtic_sync::make_channel!(Frame, COMMAND_CHANNEL_BUFFER_SIZE);
 
         // Init HID device
         defmt::info!("init usb allocator");
@@ -181,6 +256,8 @@ mod kb {
         defmt::info!("init usb keyboard");
         let usb_keyboard = UsbHidClassBuilder::new()
             .add_device(NKROBootKeyboardConfig::default())
+            .add_device(RawHidConfig::command())
+            .add_device(RawHidConfig::via())
             .build(usb_allocator);
 
         defmt::info!("init usb device");
@@ -193,7 +270,7 @@ mod kb {
             .build();
 
         // Init keyboard
-        let (pio0, sm0, _, _, _) = ctx.device.PIO0.split(&mut ctx.device.RESETS);
+        let (pio0, sm0, sm1, _, _) = ctx.device.PIO0.split(&mut ctx.device.RESETS);
         let (config, transport) = Keyboard::init(
             gpio::Pins::new(
                 ctx.device.IO_BANK0,
@@ -204,6 +281,7 @@ mod kb {
             pwm::Slices::new(ctx.device.PWM, &mut ctx.device.RESETS),
             pio0,
             sm0,
+            sm1,
             ctx.device.I2C1,
             ctx.device.UART0,
             &mut ctx.device.RESETS,
@@ -222,6 +300,10 @@ mod kb {
             None => (None, None),
         };
 
+        // Init settings store
+        defmt::info!("init store");
+        let store = Store::new(Rp2040Flash::new());
+
         // Start
         start_wait_usb::spawn(
             1.secs(),
@@ -229,11 +311,15 @@ mod kb {
             input_receiver,
             keys_sender,
             keys_receiver,
+            command_receiver,
             frame_sender,
             frame_receiver,
+            oled_sender,
+            oled_receiver,
             transport_receiver
                 .as_mut()
                 .map(|transport_receiver| transport_receiver.initialize_seq_sender()),
+            store,
             config,
         )
         .ok();
@@ -246,7 +332,10 @@ mod kb {
                 usb_keyboard,
                 transport_sender,
             },
-            Local { transport_receiver },
+            Local {
+                transport_receiver,
+                command_sender,
+            },
         )
     }
 
@@ -280,11 +369,23 @@ mod kb {
             >,
             INPUT_CHANNEL_BUFFER_SIZE,
         >,
-        keys_sender: Sender<'static, Vec<Key>, KEYS_CHANNEL_BUFFER_SIZE>,
-        keys_receiver: Receiver<'static, Vec<Key>, KEYS_CHANNEL_BUFFER_SIZE>,
-        frame_sender: Sender<'static, Box<dyn FrameIterator>, 1>,
-        frame_receiver: Receiver<'static, Box<dyn FrameIterator>, 1>,
+        keys_sender: Sender<'static, Report, KEYS_CHANNEL_BUFFER_SIZE>,
+        keys_receiver: Receiver<'static, Report, KEYS_CHANNEL_BUFFER_SIZE>,
+        command_receiver: Receiver<'static, Frame, COMMAND_CHANNEL_BUFFER_SIZE>,
+        frame_sender: Sender<
+            'static,
+            RGBFrame<{ <Keyboard as Configurator>::RGB_MATRIX_LED_COUNT }>,
+            1,
+        >,
+        frame_receiver: Receiver<
+            'static,
+            RGBFrame<{ <Keyboard as Configurator>::RGB_MATRIX_LED_COUNT }>,
+            1,
+        >,
+        oled_sender: Sender<'static, String, 1>,
+        oled_receiver: Receiver<'static, String, 1>,
         seq_sender: Option<Receiver<'static, Sequence, { remote::REQUEST_SEQUENCE_QUEUE_SIZE }>>,
+        mut store: Store<Rp2040Flash>,
         mut config: Configuration,
     ) {
         defmt::info!("start_wait_usb()");
@@ -292,6 +393,52 @@ mod kb {
             display.write_str("kb").unwrap();
         }
 
+        // Bootmagic
+        let bootmagic = Bootmagic::new(
+            <Keyboard as Configurator>::BOOTMAGIC_BOOTLOADER_KEY,
+            <Keyboard as Configurator>::BOOTMAGIC_CLEAR_SETTINGS_KEY,
+        );
+        let mut bootmagic_action = None;
+        for n in 0..bootmagic::SCAN_COUNT {
+            if n > 0 {
+                Mono::delay(bootmagic::SCAN_INTERVAL_MICROS.micros()).await;
+            }
+            let action = if let Some(ref mut key_matrix_split) = config.key_matrix_split {
+                bootmagic.check(&Scanner::scan(key_matrix_split).await)
+            } else if let Some(ref mut key_matrix) = config.key_matrix {
+                bootmagic.check(&key_matrix.scan().await)
+            } else {
+                None
+            };
+            // Nothing held, or a key that bounced
+            if action.is_none() || (n > 0 && action != bootmagic_action) {
+                bootmagic_action = None;
+                break;
+            }
+            bootmagic_action = action;
+        }
+        match bootmagic_action {
+            Some(bootmagic::Action::BootloaderJump) => {
+                defmt::warn!("bootmagic: jumping to bootloader");
+                rom_data::reset_to_usb_boot(1 << <Keyboard as Configurator>::U2F_ACTIVITY_PIN, 0);
+            }
+            Some(bootmagic::Action::ClearSettings) => {
+                defmt::warn!("bootmagic: clearing settings");
+                if let Err(e) = store.clear() {
+                    defmt::error!("failed to clear settings: {}", e);
+                }
+                if let Some(ref mut display) = config.oled_display {
+                    display.write_str("\nsettings cleared").unwrap();
+                }
+            }
+            None => {}
+        }
+        if let Some(ref mut key_matrix) = config.key_matrix {
+            if let Some(calibration) = store.read(0) {
+                key_matrix.restore_calibration(calibration);
+            }
+        }
+
         // Start USB tasks
         hid_usb_tick::spawn().ok();
         hid_reporter::spawn(keys_receiver).ok();
@@ -330,6 +477,7 @@ mod kb {
             split::Mode::Master => {
                 heartbeat::spawn(config.heartbeat_led, 500.millis()).ok();
                 master_input_scanner::spawn(
+                    config.key_matrix,
                     config.key_matrix_split,
                     config.rotary_encoder,
                     input_sender,
@@ -338,11 +486,15 @@ mod kb {
                 master_processor::spawn(
                     input_receiver,
                     keys_sender,
+                    command_receiver,
                     frame_sender,
+                    oled_sender,
+                    store,
                     config.status_led,
                 )
                 .ok();
                 rgb_matrix_renderer::spawn(config.rgb_matrix, frame_receiver).ok();
+                oled_renderer::spawn(config.oled_display, oled_receiver).ok();
             }
             split::Mode::Slave => {
                 assert!(
@@ -353,7 +505,7 @@ mod kb {
                 // Initialize server and register services
                 let mut server = Server::new(seq_sender.unwrap());
                 if let Some(key_matrix_split) = config.key_matrix_split {
-                    server.register_service(Box::new(key_matrix_split)).await;
+                    server.register_service(key_matrix_split);
                 }
 
                 heartbeat::spawn(config.heartbeat_led, 2000.millis()).ok();
@@ -395,7 +547,7 @@ mod kb {
 
     // ============================= Slave
     #[task (shared=[&transport_sender], priority = 1)]
-    async fn slave_server(ctx: slave_server::Context, mut server: Server) {
+    async fn slave_server(ctx: slave_server::Context, mut server: Server<KeyMatrixSplit>) {
         defmt::info!("slave_server()");
         server
             .listen(ctx.shared.transport_sender.as_ref().unwrap())
@@ -407,12 +559,8 @@ mod kb {
     #[task (shared=[&transport_sender], priority = 1)]
     async fn master_input_scanner(
         ctx: master_input_scanner::Context,
-        mut key_matrix_split: Option<
-            SplitSwitchMatrix<
-                { <Keyboard as Configurator>::KEY_MATRIX_ROW_COUNT },
-                { <Keyboard as Configurator>::KEY_MATRIX_COL_COUNT },
-            >,
-        >,
+        mut key_matrix: Option<KeyMatrix>,
+        mut key_matrix_split: Option<KeyMatrixSplit>,
         mut rotary_encoder: Option<RotaryEncoder>,
         mut input_sender: Sender<
             'static,
@@ -430,22 +578,38 @@ mod kb {
             let scan_start_time = Mono::now();
 
             let transport_sender = ctx.shared.transport_sender.as_ref();
-            let key_matrix_result = match key_matrix_split {
-                Some(ref mut key_matrix_split) => {
-                    key_matrix_split.scan(transport_sender.unwrap()).await
+            let key_matrix_result = match (&mut key_matrix_split, &mut key_matrix) {
+                (Some(key_matrix_split), _) => {
+                    SplitScanner::scan(key_matrix_split, transport_sender.unwrap()).await
                 }
-                None => Default::default(),
+                (None, Some(key_matrix)) => key_matrix.scan().await,
+                (None, None) => Default::default(),
+            };
+            let (key_matrix_travel, key_matrix_calibration) = match key_matrix {
+                Some(ref mut key_matrix) => (key_matrix.travel(), key_matrix.take_calibration()),
+                None => (None, None),
             };
             let rotary_encoder_result = match rotary_encoder {
                 Some(ref mut rotary_encoder) => rotary_encoder.scan(),
                 None => Default::default(),
             };
-            input_sender
-                .try_send(Input {
+            // Wait for the processor rather than dropping a scan, and a key change with it
+            if input_sender.is_full() {
+                debug::increment_counter(debug::CounterTag::InputWait);
+            }
+            if input_sender
+                .send(Input {
                     key_matrix_result,
+                    key_matrix_travel,
+                    key_matrix_calibration,
                     rotary_encoder_result,
                 })
-                .ok(); // drop data if buffer is full
+                .await
+                .is_err()
+            {
+                break;
+            }
+            debug::increment_counter(debug::CounterTag::InputScan);
 
             if debug::ENABLE_LOG_INPUT_SCANNER_ENABLE_TIMING
                 && n % debug::LOG_INPUT_SCANNER_SAMPLING_RATE == 0
@@ -476,9 +640,9 @@ mod kb {
         }
     }
 
-    #[task(priority = 2)]
+    #[task(shared=[usb_keyboard], priority = 2)]
     async fn master_processor(
-        _: master_processor::Context,
+        mut ctx: master_processor::Context,
         mut input_receiver: Receiver<
             'static,
             Input<
@@ -487,41 +651,261 @@ mod kb {
             >,
             INPUT_CHANNEL_BUFFER_SIZE,
         >,
-        mut keys_sender: Sender<'static, Vec<Key>, KEYS_CHANNEL_BUFFER_SIZE>,
-        frame_sender: Sender<'static, Box<dyn FrameIterator>, 1>,
+        mut keys_sender: Sender<'static, Report, KEYS_CHANNEL_BUFFER_SIZE>,
+        mut command_receiver: Receiver<'static, Frame, COMMAND_CHANNEL_BUFFER_SIZE>,
+        frame_sender: Sender<
+            'static,
+            RGBFrame<{ <Keyboard as Configurator>::RGB_MATRIX_LED_COUNT }>,
+            1,
+        >,
+        mut oled_sender: Sender<'static, String, 1>,
+        mut store: Store<Rp2040Flash>,
         mut status_led: Option<StatusLED>,
     ) {
         defmt::info!("master_processor()");
-        let input_processors: &mut [&mut dyn InputProcessor<
-            { <Keyboard as Configurator>::KEY_MATRIX_ROW_COUNT },
-            { <Keyboard as Configurator>::KEY_MATRIX_COL_COUNT },
-        >] = &mut [&mut KeyMatrixRisingFallingDebounceProcessor::new(
-            10.millis(),
-        )];
+        let mut debounce_processor = KeyMatrixDebounceProcessor::new(
+            <Keyboard as Configurator>::KEY_MATRIX_DEBOUNCE_ALGORITHM,
+            <Keyboard as Configurator>::KEY_MATRIX_DEBOUNCE_DELAY_MICROS.micros(),
+        );
+        for &(row, col, delay) in <Keyboard as Configurator>::KEY_MATRIX_DEBOUNCE_OVERRIDES {
+            debounce_processor.set_delay(row, col, delay.micros());
+        }
+        let mut anti_ghost_processor = AntiGhostProcessor::new();
+        let mut chatter_processor = ChatterProcessor::new(
+            <Keyboard as Configurator>::KEY_MATRIX_CHATTER_WINDOW_MICROS.micros(),
+            <Keyboard as Configurator>::KEY_MATRIX_CHATTER_ADAPTIVE_DEBOUNCE,
+        );
+        let mut socd_processor = SocdProcessor::new(
+            <Keyboard as Configurator>::SOCD_PAIRS,
+            <Keyboard as Configurator>::SOCD_ENABLED,
+        );
+        // Stages with their own settings get an instance each, by index in the events chain
+        let mut events_stage_processors: Vec<
+            Option<Box<dyn EventsProcessor<<Keyboard as Configurator>::Layer>>>,
+        > =
+            <Keyboard as Configurator>::EVENTS_PROCESSORS
+                .iter()
+                .map(|stage| match *stage {
+                    EventsStage::HoldLimit { max_hold_micros } => {
+                        Some(Box::new(HoldLimitProcessor::new(max_hold_micros.micros()))
+                            as Box<dyn EventsProcessor<_>>)
+                    }
+                    EventsStage::KeyReplace { from, to } => {
+                        Some(Box::new(KeyReplaceProcessor::new(from, to))
+                            as Box<dyn EventsProcessor<_>>)
+                    }
+                    EventsStage::TapHold {
+                        tap,
+                        hold,
+                        term_micros,
+                    } => Some(
+                        Box::new(TapHoldProcessor::new(tap, hold, term_micros.micros()))
+                            as Box<dyn EventsProcessor<_>>,
+                    ),
+                    _ => None,
+                })
+                .collect();
+        let mut pipeline = Pipeline::new(
+            <Keyboard as Configurator>::INPUT_PROCESSORS,
+            <Keyboard as Configurator>::EVENTS_PROCESSORS,
+            <Keyboard as Configurator>::PROCESSOR_ERROR_POLICIES,
+        );
+        let mut pipeline_settings_autosave =
+            Autosave::new(pipeline.get_settings(), SETTINGS_AUTOSAVE_DELAY_MICROS);
+        let mut socd_settings_autosave = Autosave::new(
+            socd_processor.get_settings(),
+            SETTINGS_AUTOSAVE_DELAY_MICROS,
+        );
         let mut mapper = Mapper::new(<Keyboard as Configurator>::get_input_map());
-        let events_processors: &mut [&mut dyn EventsProcessor<
-            <Keyboard as Configurator>::Layer,
-        >] = &mut [
-            &mut RGBProcessor::<{ <Keyboard as Configurator>::RGB_MATRIX_LED_COUNT }>::new(
-                frame_sender,
-            ),
-            &mut SystemProcessor::new(29),
-        ];
+        let mut input_map_autosave =
+            Autosave::new(mapper.get_revision(), SETTINGS_AUTOSAVE_DELAY_MICROS);
+        let mut rgb_processor =
+            RGBProcessor::<{ <Keyboard as Configurator>::RGB_MATRIX_LED_COUNT }>::new(frame_sender);
+        let mut rgb_settings_autosave =
+            Autosave::new(rgb_processor.get_settings(), SETTINGS_AUTOSAVE_DELAY_MICROS);
+        let mut host_mode_processor = HostModeProcessor::new();
+        let mut host_mode_autosave = Autosave::new(
+            host_mode_processor.get_mode(),
+            SETTINGS_AUTOSAVE_DELAY_MICROS,
+        );
+        let mut profile_processor =
+            ProfileProcessor::new(<Keyboard as Configurator>::PROFILE_COUNT as u8);
+        if let Some(ActiveProfile(profile)) = store.read(0) {
+            profile_processor.set_active(profile);
+        }
+        let mut loaded_profile = None;
+        let mut key_matrix_calibration = None;
+        let mut key_matrix_calibration_autosave =
+            Autosave::new(None, SETTINGS_AUTOSAVE_DELAY_MICROS);
+        let mut system_processor =
+            SystemProcessor::new(<Keyboard as Configurator>::U2F_ACTIVITY_PIN);
+        let mut dispatcher = Dispatcher::new(
+            FirmwareInfo {
+                protocol_version: PROTOCOL_VERSION,
+                name: <Keyboard as Configurator>::NAME,
+                version: env!("CARGO_PKG_VERSION"),
+                layer_count: <Keyboard as Configurator>::LAYER_COUNT as u8,
+                key_matrix_row_count: <Keyboard as Configurator>::KEY_MATRIX_ROW_COUNT as u8,
+                key_matrix_col_count: <Keyboard as Configurator>::KEY_MATRIX_COL_COUNT as u8,
+                key_count: <Keyboard as Configurator>::PHYSICAL_LAYOUT.len() as u8,
+            },
+            <Keyboard as Configurator>::PHYSICAL_LAYOUT,
+        );
+        let mut via = Via::new(
+            <Keyboard as Configurator>::LAYER_COUNT as u8,
+            <Keyboard as Configurator>::KEY_MATRIX_ROW_COUNT as u8,
+            <Keyboard as Configurator>::KEY_MATRIX_COL_COUNT as u8,
+        );
 
         let mut poll_end_time = Mono::now();
         let mut n: u64 = 0;
-        while let Ok(mut input) = input_receiver.recv().await {
+        let mut report_state = ReportState::new();
+        let mut is_input_stalled = false;
+        let mut is_hid_stalled = false;
+        let mut deadline_ticks = None;
+        let mut previous_key_matrix_result = Default::default();
+        let input_stall_timeout: <Mono as Monotonic>::Duration =
+            INPUT_STALL_TIMEOUT_MICROS.micros();
+        let mut input_stall_ticks = (Mono::now() + input_stall_timeout).ticks();
+        loop {
+            // Held events are released on their deadline, and a stalled scanner is noticed, even if
+            // no scan comes in before it
+            let wake_ticks = match deadline_ticks {
+                Some(ticks) if is_input_stalled => Some(ticks),
+                Some(ticks) => Some(input_stall_ticks.min(ticks)),
+                None if is_input_stalled => None,
+                None => Some(input_stall_ticks),
+            };
+            let received = match wake_ticks {
+                Some(ticks) => Mono::timeout_at(
+                    <Mono as Monotonic>::Instant::from_ticks(ticks),
+                    input_receiver.recv(),
+                )
+                .await
+                .ok(),
+                None => Some(input_receiver.recv().await),
+            };
+            let (mut input, is_scan) = match received {
+                Some(Ok(input)) => (input, true),
+                Some(Err(_)) => break,
+                None => (
+                    Input::repeat(&previous_key_matrix_result, Mono::now().ticks()),
+                    false,
+                ),
+            };
+            if is_scan {
+                input_stall_ticks = (Mono::now() + input_stall_timeout).ticks();
+                if is_input_stalled {
+                    defmt::info!("input resumed");
+                    is_input_stalled = false;
+                    if let Some(ref mut status_led) = status_led {
+                        status_led.set_remote_link(true);
+                    }
+                }
+            } else if !is_input_stalled && Mono::now().ticks() >= input_stall_ticks {
+                defmt::warn!("input stalled, releasing all keys");
+                is_input_stalled = true;
+                report_state.release_all();
+                if let Some(ref mut status_led) = status_led {
+                    status_led.set_remote_link(false);
+                }
+            }
             let process_start_time = Mono::now();
-            if input_processors
-                .iter_mut()
-                .try_for_each(|p| p.process(&mut input))
-                .is_err()
+            if let Some(calibration) = input.key_matrix_calibration.take() {
+                key_matrix_calibration = Some(calibration);
+            }
+            debug::increment_counter(debug::CounterTag::ProcessorScan);
+
+            if let Ok(request) = command_receiver.try_recv() {
+                let handlers: &mut [&mut dyn Handler<<Keyboard as Configurator>::Layer>] = &mut [
+                    &mut mapper,
+                    &mut rgb_processor,
+                    &mut profile_processor,
+                    &mut system_processor,
+                    &mut chatter_processor,
+                    &mut pipeline,
+                    &mut host_mode_processor,
+                ];
+                let response = match request {
+                    Frame::Native(report) => Frame::Native(dispatcher.dispatch(&report, handlers)),
+                    Frame::Via(report) => Frame::Via(via.dispatch(&report, handlers)),
+                };
+                ctx.shared.usb_keyboard.lock(|k| {
+                    let result = match response {
+                        Frame::Native(report) => k
+                            .device::<CommandHid<'static, usb::UsbBus>, _>()
+                            .write_report(&report),
+                        Frame::Via(report) => k
+                            .device::<ViaHid<'static, usb::UsbBus>, _>()
+                            .write_report(&report),
+                    };
+                    match result {
+                        Ok(_) => {}
+                        Err(UsbHidError::WouldBlock) => {
+                            defmt::warn!("command response dropped");
+                        }
+                        Err(e) => {
+                            core::panic!("Failed to write raw hid report: {:?}", e);
+                        }
+                    }
+                });
+            }
+
+            // Input processors only run on scans. A failing stage decides with its error policy
+            // whether the pass goes on
+            let mut failure = None;
+            for (index, stage) in <Keyboard as Configurator>::INPUT_PROCESSORS
+                .iter()
+                .enumerate()
             {
+                if !is_scan || !pipeline.is_enabled(index) {
+                    continue;
+                }
+                let processor: &mut dyn InputProcessor<
+                    { <Keyboard as Configurator>::KEY_MATRIX_ROW_COUNT },
+                    { <Keyboard as Configurator>::KEY_MATRIX_COL_COUNT },
+                > = match stage {
+                    InputStage::Debounce => &mut debounce_processor,
+                    InputStage::AntiGhost => &mut anti_ghost_processor,
+                    InputStage::Chatter => &mut chatter_processor,
+                    InputStage::Socd => &mut socd_processor,
+                };
+                if let Err(e) = processor.process(&mut input) {
+                    debug::increment_counter(debug::CounterTag::ProcessorError);
+                    match pipeline.fail(index, e) {
+                        ErrorPolicy::Skip => {}
+                        policy => {
+                            failure = Some(policy);
+                            break;
+                        }
+                    }
+                }
+            }
+            if let Some(policy) = failure {
+                if policy == ErrorPolicy::ReleaseAll || input.has_releases() {
+                    report_state.release_all();
+                }
                 continue;
             }
+            previous_key_matrix_result = input.key_matrix_result;
+            if let Some((row, col)) = chatter_processor.take_newly_flagged() {
+                if let Some(health) = chatter_processor.get_health(row, col) {
+                    oled_sender
+                        .try_send(format!(
+                            "key {},{} chatter\n{} presses\n{} bounces\nlockout +{} ms",
+                            row,
+                            col,
+                            health.press_count,
+                            health.bounce_count,
+                            health.extra_delay_micros / 1_000
+                        ))
+                        .ok();
+                }
+            }
 
-            let mut events = Vec::<Event<<Keyboard as Configurator>::Layer>>::with_capacity(10);
-            mapper.map(&input, &mut events);
+            let mut mapped_events = Events::new();
+            mapper.map(&input, &mut mapped_events);
+            let mut events = EventQueue::new(Mono::now().ticks(), mapped_events);
 
             if debug::ENABLE_LOG_EVENTS {
                 events
@@ -536,25 +920,226 @@ mod kb {
                 status_led.update_remote_activity(!events.is_empty());
             }
 
-            if events_processors
-                .iter_mut()
-                .try_for_each(|p| p.process(&mut events))
-                .is_err()
+            // Toggles apply from the pass they are pressed in, toggling itself never fails
+            EventsProcessor::process(&mut pipeline, &mut events).ok();
+            for (i, stage) in <Keyboard as Configurator>::EVENTS_PROCESSORS
+                .iter()
+                .enumerate()
             {
+                let index = <Keyboard as Configurator>::INPUT_PROCESSORS.len() + i;
+                if !pipeline.is_enabled(index) {
+                    continue;
+                }
+                let processor: &mut dyn EventsProcessor<<Keyboard as Configurator>::Layer> =
+                    match stage {
+                        EventsStage::RGB => &mut rgb_processor,
+                        EventsStage::Profile => &mut profile_processor,
+                        EventsStage::System => &mut system_processor,
+                        EventsStage::Socd => &mut socd_processor,
+                        EventsStage::HostMode => &mut host_mode_processor,
+                        // built along with the pipeline
+                        EventsStage::HoldLimit { .. }
+                        | EventsStage::KeyReplace { .. }
+                        | EventsStage::TapHold { .. } => {
+                            events_stage_processors[i].as_deref_mut().unwrap()
+                        }
+                    };
+                if let Err(e) = processor.process(&mut events) {
+                    debug::increment_counter(debug::CounterTag::ProcessorError);
+                    match pipeline.fail(index, e) {
+                        ErrorPolicy::Skip => {}
+                        policy => {
+                            failure = Some(policy);
+                            break;
+                        }
+                    }
+                }
+            }
+            deadline_ticks = events.deadline_ticks();
+            if let Some(policy) = failure {
+                if policy == ErrorPolicy::ReleaseAll
+                    || events.iter().any(|e| e.edge == Edge::Falling)
+                {
+                    report_state.release_all();
+                }
                 continue;
             }
 
-            keys_sender
-                .try_send(
-                    events
-                        .into_iter()
-                        .filter_map(|e| match e.action {
-                            Action::Key(k) => Some(k),
-                            _ => None,
+            let profile = profile_processor.get_active();
+            if loaded_profile != Some(profile) {
+                if let Some(previous_profile) = loaded_profile {
+                    // keep pending edits of the profile being left
+                    if let Err(e) = input_map_autosave.flush(&mapper.get_revision(), |_| {
+                        store.write(previous_profile, &mapper.get_mapping().to_record())
+                    }) {
+                        defmt::error!("failed to save keymap: {}", e);
+                    }
+                    if let Err(e) = rgb_settings_autosave
+                        .flush(&rgb_processor.get_settings(), |settings| {
+                            store.write(previous_profile, settings)
                         })
-                        .collect(),
-                )
-                .ok(); // drop data if buffer is full
+                    {
+                        defmt::error!("failed to save rgb settings: {}", e);
+                    }
+                    if let Err(e) = host_mode_autosave
+                        .flush(&host_mode_processor.get_mode(), |mode| {
+                            store.write(previous_profile, mode)
+                        })
+                    {
+                        defmt::error!("failed to save host mode: {}", e);
+                    }
+                    if let Err(e) = pipeline_settings_autosave
+                        .flush(&pipeline.get_settings(), |settings| {
+                            store.write(previous_profile, settings)
+                        })
+                    {
+                        defmt::error!("failed to save processor settings: {}", e);
+                    }
+                    if let Err(e) = socd_settings_autosave
+                        .flush(&socd_processor.get_settings(), |settings| {
+                            store.write(previous_profile, settings)
+                        })
+                    {
+                        defmt::error!("failed to save socd settings: {}", e);
+                    }
+                    if let Err(e) = store.write(0, &ActiveProfile(profile)) {
+                        defmt::error!("failed to save active profile: {}", e);
+                    }
+                }
+
+                match store
+                    .read::<InputMapRecord<<Keyboard as Configurator>::Layer>>(profile)
+                    .and_then(|record| InputMap::from_record(&record))
+                {
+                    Some(mapping) => mapper.set_mapping(mapping),
+                    None => {
+                        defmt::warn!("no valid keymap stored, copying compiled keymap");
+                        mapper.reset_mapping();
+                        if let Err(e) = store.write(profile, &mapper.get_mapping().to_record()) {
+                            defmt::error!("failed to save keymap: {}", e);
+                        }
+                    }
+                }
+                input_map_autosave.reset(mapper.get_revision());
+                match store.read::<RGBSettings>(profile) {
+                    Some(settings) => rgb_processor.set_settings(settings),
+                    None => {
+                        if let Err(e) = store.write(profile, &rgb_processor.get_settings()) {
+                            defmt::error!("failed to save rgb settings: {}", e);
+                        }
+                    }
+                }
+                rgb_settings_autosave.reset(rgb_processor.get_settings());
+                host_mode_processor.set_mode(store.read::<HostMode>(profile).unwrap_or_default());
+                host_mode_autosave.reset(host_mode_processor.get_mode());
+                match store.read::<PipelineSettings>(profile) {
+                    Some(settings) => pipeline.set_settings(settings),
+                    None => pipeline.reset_settings(),
+                }
+                pipeline_settings_autosave.reset(pipeline.get_settings());
+                match store.read::<SocdSettings>(profile) {
+                    Some(settings) => socd_processor.set_settings(settings),
+                    None => socd_processor.set_enabled(<Keyboard as Configurator>::SOCD_ENABLED),
+                }
+                socd_settings_autosave.reset(socd_processor.get_settings());
+
+                oled_sender
+                    .try_send(format!(
+                        "{}\n{}\nprofile {}",
+                        <Keyboard as Configurator>::NAME,
+                        split::get_self_mode(),
+                        profile + 1
+                    ))
+                    .ok();
+                loaded_profile = Some(profile);
+            }
+
+            if let Err(e) =
+                input_map_autosave.update(&mapper.get_revision(), Mono::now().ticks(), |_| {
+                    store.write(profile, &mapper.get_mapping().to_record())
+                })
+            {
+                defmt::error!("failed to save keymap: {}", e);
+            }
+            if let Err(e) = rgb_settings_autosave.update(
+                &rgb_processor.get_settings(),
+                Mono::now().ticks(),
+                |settings| store.write(profile, settings),
+            ) {
+                defmt::error!("failed to save rgb settings: {}", e);
+            }
+            if let Err(e) = host_mode_autosave.update(
+                &host_mode_processor.get_mode(),
+                Mono::now().ticks(),
+                |mode| store.write(profile, mode),
+            ) {
+                defmt::error!("failed to save host mode: {}", e);
+            }
+            if let Err(e) = pipeline_settings_autosave.update(
+                &pipeline.get_settings(),
+                Mono::now().ticks(),
+                |settings| store.write(profile, settings),
+            ) {
+                defmt::error!("failed to save processor settings: {}", e);
+            }
+            if let Err(e) = socd_settings_autosave.update(
+                &socd_processor.get_settings(),
+                Mono::now().ticks(),
+                |settings| store.write(profile, settings),
+            ) {
+                defmt::error!("failed to save socd settings: {}", e);
+            }
+            if let Err(e) = key_matrix_calibration_autosave.update(
+                &key_matrix_calibration,
+                Mono::now().ticks(),
+                |calibration| match calibration {
+                    Some(calibration) => store.write(0, calibration),
+                    None => Ok(()),
+                },
+            ) {
+                defmt::error!("failed to save analog calibration: {}", e);
+            }
+            // Flash writes mask interrupts, possibly for longer than the stall timeouts, so neither
+            // the scanner nor the host could keep up meanwhile
+            let is_flash_written = store.take_written();
+            if is_flash_written {
+                input_stall_ticks = (Mono::now() + input_stall_timeout).ticks();
+            }
+
+            // Reports wait for room in the channel, unless the host already stopped taking them
+            let mut pending_events = &events[..];
+            'report: loop {
+                let (reports, count) = report_state.update(pending_events);
+                for keys in reports {
+                    let is_sent = if is_hid_stalled {
+                        keys_sender.try_send(keys).is_ok()
+                    } else {
+                        matches!(
+                            Mono::timeout_after(
+                                HID_STALL_TIMEOUT_MICROS.micros(),
+                                keys_sender.send(keys)
+                            )
+                            .await,
+                            Ok(Ok(_))
+                        )
+                    };
+                    if !is_sent {
+                        debug::increment_counter(debug::CounterTag::KeysDrop);
+                        if !is_hid_stalled && !is_flash_written {
+                            defmt::warn!("hid reports stalled, releasing all keys");
+                            is_hid_stalled = true;
+                            report_state.release_all();
+                        }
+                        report_state.invalidate();
+                        break 'report;
+                    }
+                    is_hid_stalled = false;
+                }
+                pending_events = &pending_events[count..];
+                if pending_events.is_empty() {
+                    break;
+                }
+            }
 
             if debug::ENABLE_LOG_PROCESSOR_ENABLE_TIMING
                 && (n % debug::LOG_PROCESSOR_SAMPLING_RATE == 0)
@@ -589,7 +1174,11 @@ mod kb {
                 >,
             >,
         >,
-        frame_receiver: Receiver<'static, Box<dyn FrameIterator>, 1>,
+        frame_receiver: Receiver<
+            'static,
+            RGBFrame<{ <Keyboard as Configurator>::RGB_MATRIX_LED_COUNT }>,
+            1,
+        >,
     ) {
         defmt::info!("rgb_matrix_renderer()");
         if let Some(ref mut rgb_matrix) = rgb_matrix {
@@ -597,39 +1186,72 @@ mod kb {
         }
     }
 
+    #[task(priority = 1)]
+    async fn oled_renderer(
+        _: oled_renderer::Context,
+        mut oled_display: Option<
+            OLEDDisplay<
+                I2CInterface<
+                    hal::I2C<
+                        pac::I2C1,
+                        (
+                            gpio::Pin<gpio::bank0::Gpio26, gpio::FunctionI2c, gpio::PullUp>,
+                            gpio::Pin<gpio::bank0::Gpio27, gpio::FunctionI2c, gpio::PullUp>,
+                        ),
+                    >,
+                >,
+            >,
+        >,
+        oled_receiver: Receiver<'static, String, 1>,
+    ) {
+        defmt::info!("oled_renderer()");
+        if let Some(ref mut oled_display) = oled_display {
+            oled_display.render(oled_receiver).await;
+        }
+    }
+
     #[task(shared=[usb_keyboard], priority = 2)]
     async fn hid_reporter(
         mut ctx: hid_reporter::Context,
-        mut keys_receiver: Receiver<'static, Vec<Key>, KEYS_CHANNEL_BUFFER_SIZE>,
+        mut keys_receiver: Receiver<'static, Report, KEYS_CHANNEL_BUFFER_SIZE>,
     ) {
         defmt::info!("hid_reporter()");
         while let Ok(keys) = keys_receiver.recv().await {
-            let start_time = Mono::now();
             if debug::ENABLE_LOG_SENT_KEYS {
                 defmt::debug!("keys: {:?}", keys.as_slice());
             }
 
-            ctx.shared.usb_keyboard.lock(|k| {
-                match k.device().write_report(keys.into_iter().map(|k| k.into())) {
-                    Ok(_) => {}
-                    Err(UsbHidError::WouldBlock) => {}
-                    Err(UsbHidError::Duplicate) => {}
-                    Err(e) => {
-                        core::panic!("Failed to write keyboard report: {:?}", e);
+            // Reports only come once each, so keep trying until the host takes it
+            let mut is_sent = false;
+            while !is_sent {
+                let start_time = Mono::now();
+                ctx.shared.usb_keyboard.lock(|k| {
+                    match k
+                        .device::<NKROBootKeyboard<'static, usb::UsbBus>, _>()
+                        .write_report(keys.iter().map(|&k| k.into()))
+                    {
+                        Ok(_) => is_sent = true,
+                        Err(UsbHidError::WouldBlock) => {}
+                        Err(UsbHidError::Duplicate) => is_sent = true,
+                        Err(e) => {
+                            core::panic!("Failed to write keyboard report: {:?}", e);
+                        }
                     }
-                }
-            });
+                });
 
-            Mono::delay_until(start_time + HID_REPORTER_TARGET_POLL_PERIOD_MICROS.micros()).await;
+                Mono::delay_until(start_time + HID_REPORTER_TARGET_POLL_PERIOD_MICROS.micros())
+                    .await;
+            }
         }
     }
 
-    #[task(binds = USBCTRL_IRQ, shared = [usb_device, usb_keyboard, is_usb_connected], priority = 2)]
+    #[task(binds = USBCTRL_IRQ, shared = [usb_device, usb_keyboard, is_usb_connected], local = [command_sender], priority = 2)]
     fn hid_reader(ctx: hid_reader::Context) {
+        let command_sender = ctx.local.command_sender;
         (ctx.shared.usb_device, ctx.shared.usb_keyboard, ctx.shared.is_usb_connected).lock(|usb_device, usb_keyboard, is_usb_connected| {
             if usb_device.poll(&mut [usb_keyboard]) {
                 *is_usb_connected = true; // usb connection detected
-                match usb_keyboard.device().read_report() {
+                match usb_keyboard.device::<NKROBootKeyboard<'static, usb::UsbBus>, _>().read_report() {
                     Ok(leds) => {
                         defmt::debug!(
                             "\nnum_lock: {}\ncaps_lock: {}\nscroll_lock: {}\ncompose: {}\nkana: {}\n",
@@ -645,6 +1267,30 @@ mod kb {
                         core::panic!("Failed to read keyboard report: {:?}", e)
                     }
                 }
+                let mut report = [0u8; command::REPORT_SIZE_BYTES];
+                match usb_keyboard.device::<CommandHid<'static, usb::UsbBus>, _>().read_report(&mut report) {
+                    Ok(_) => {
+                        if command_sender.try_send(Frame::Native(report)).is_err() {
+                            defmt::warn!("command request queue is full, request dropped");
+                        }
+                    }
+                    Err(UsbError::WouldBlock) => {}
+                    Err(e) => {
+                        core::panic!("Failed to read raw hid report: {:?}", e)
+                    }
+                }
+                let mut report = [0u8; command::via::REPORT_SIZE_BYTES];
+                match usb_keyboard.device::<ViaHid<'static, usb::UsbBus>, _>().read_report(&mut report) {
+                    Ok(_) => {
+                        if command_sender.try_send(Frame::Via(report)).is_err() {
+                            defmt::warn!("command request queue is full, request dropped");
+                        }
+                    }
+                    Err(UsbError::WouldBlock) => {}
+                    Err(e) => {
+                        core::panic!("Failed to read via report: {:?}", e)
+                    }
+                }
             }
         });
     }
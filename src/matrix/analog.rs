@@ -0,0 +1,480 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use embedded_hal::digital::{OutputPin, PinState};
+use embedded_hal_0_2::adc::OneShot;
+use hal::adc::{Adc, AdcPin};
+use rp2040_hal::gpio;
+use rtic_monotonics::rp2040::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    kb::Mono,
+    key::Edge,
+    storage::{Record, RecordKind},
+};
+
+use super::{switch::MatrixPin, Bit, Result, Scanner};
+
+// Key travel, from 0 at rest to FULL_TRAVEL when bottomed out.
+pub type Travel<const ROW_COUNT: usize, const COL_COUNT: usize> = [[u16; COL_COUNT]; ROW_COUNT];
+
+pub const FULL_TRAVEL: u16 = 1000;
+
+// Readings have to go this far past a calibrated extreme to move it, so that noise does not
+// keep rewriting the calibration.
+const CALIBRATION_NOISE: u16 = 8;
+
+// Sensors sit behind analog multiplexers sharing their select lines, one ADC input per
+// multiplexer. Sensor `k` is on channel `k % channel_count` of input `k / channel_count`.
+pub trait SensorReader: Send {
+    fn channel_count(&self) -> usize;
+    fn input_count(&self) -> usize;
+    fn select(&mut self, channel: usize);
+    fn read(&mut self, input: usize) -> u16;
+}
+
+type AnalogPin = AdcPin<gpio::Pin<gpio::DynPinId, gpio::FunctionSioInput, gpio::PullNone>>;
+
+// 74HC4067-style multiplexers read through the RP2040 ADC.
+pub struct MuxSensorReader {
+    adc: Adc,
+    select: Vec<Box<dyn OutputPin<Error = gpio::Error> + Sync + Send>>,
+    inputs: Vec<AnalogPin>,
+}
+
+#[allow(dead_code)]
+impl MuxSensorReader {
+    pub fn new<const SELECT_COUNT: usize, const INPUT_COUNT: usize>(
+        adc: Adc,
+        select: [MatrixPin; SELECT_COUNT],
+        inputs: [MatrixPin; INPUT_COUNT],
+    ) -> Self {
+        MuxSensorReader {
+            adc,
+            select: select
+                .into_iter()
+                .map(|p| {
+                    Box::new(p.into_push_pull_output())
+                        as Box<dyn OutputPin<Error = gpio::Error> + Sync + Send>
+                })
+                .collect(),
+            inputs: inputs
+                .into_iter()
+                .map(|p| AdcPin::new(p.into_floating_input()).expect("pin is not an ADC input"))
+                .collect(),
+        }
+    }
+}
+
+impl SensorReader for MuxSensorReader {
+    fn channel_count(&self) -> usize {
+        1 << self.select.len()
+    }
+
+    fn input_count(&self) -> usize {
+        self.inputs.len()
+    }
+
+    fn select(&mut self, channel: usize) {
+        for (k, pin) in self.select.iter_mut().enumerate() {
+            pin.set_state(PinState::from(channel >> k & 1 == 1))
+                .unwrap();
+        }
+    }
+
+    fn read(&mut self, input: usize) -> u16 {
+        let value: u16 = nb::block!(self.adc.read(&mut self.inputs[input])).unwrap();
+        value
+    }
+}
+
+// Readings set by hand, for exercising the analog matrix without hardware.
+pub struct MockSensorReader {
+    channel_count: usize,
+    selected: usize,
+    values: Vec<u16>,
+}
+
+#[allow(dead_code)]
+impl MockSensorReader {
+    pub fn new(channel_count: usize, input_count: usize, value: u16) -> Self {
+        MockSensorReader {
+            channel_count,
+            selected: 0,
+            values: vec![value; channel_count * input_count],
+        }
+    }
+
+    pub fn set(&mut self, sensor: usize, value: u16) {
+        self.values[sensor] = value;
+    }
+}
+
+impl SensorReader for MockSensorReader {
+    fn channel_count(&self) -> usize {
+        self.channel_count
+    }
+
+    fn input_count(&self) -> usize {
+        self.values.len() / self.channel_count
+    }
+
+    fn select(&mut self, channel: usize) {
+        self.selected = channel;
+    }
+
+    fn read(&mut self, input: usize) -> u16 {
+        self.values[input * self.channel_count + self.selected]
+    }
+}
+
+// Raw readings with the key at rest and bottomed out.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct KeyCalibration {
+    pub rest: u16,
+    pub bottom: u16,
+}
+
+// Calibration of every matrix position, row by row.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CalibrationRecord {
+    keys: Vec<Option<KeyCalibration>>,
+}
+
+impl Record for CalibrationRecord {
+    const KIND: RecordKind = RecordKind::AnalogCalibration;
+    const VERSION: u8 = 1;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AnalogMatrixConfig {
+    // Whether readings go up as a key is pressed, depending on the magnet orientation.
+    pub is_rising: bool,
+    // Expected distance between the rest and bottom readings, until a key has been bottomed out.
+    pub default_range: u16,
+    // Travel past which a key is pressed, and back under which it is released.
+    pub actuation_point: u16,
+    pub release_point: u16,
+    // With rapid trigger, a pressed key is released as soon as it moves up by this much, and
+    // pressed again as soon as it moves down by this much, until it goes back under the
+    // release point.
+    pub rapid_trigger_sensitivity: Option<u16>,
+    // Time given to the multiplexer outputs to settle after switching channels.
+    pub settle_delay: <Mono as Monotonic>::Duration,
+}
+
+impl Default for AnalogMatrixConfig {
+    fn default() -> Self {
+        AnalogMatrixConfig {
+            is_rising: true,
+            default_range: 1000,
+            actuation_point: FULL_TRAVEL / 2,
+            release_point: FULL_TRAVEL * 2 / 5,
+            rapid_trigger_sensitivity: None,
+            settle_delay: 5.micros(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct KeyState {
+    pressed: bool,
+    // Set once a key has been partially released under rapid trigger.
+    is_rapid: bool,
+    // Deepest travel while pressed, shallowest while partially released.
+    extreme: u16,
+}
+
+impl KeyState {
+    fn update(&mut self, travel: u16, config: &AnalogMatrixConfig) {
+        if travel <= config.release_point {
+            *self = KeyState {
+                pressed: false,
+                is_rapid: false,
+                extreme: travel,
+            };
+        } else if self.pressed {
+            match config.rapid_trigger_sensitivity {
+                _ if travel > self.extreme => self.extreme = travel,
+                Some(sensitivity) if self.extreme - travel >= sensitivity => {
+                    *self = KeyState {
+                        pressed: false,
+                        is_rapid: true,
+                        extreme: travel,
+                    };
+                }
+                _ => {}
+            }
+        } else if self.is_rapid {
+            match config.rapid_trigger_sensitivity {
+                _ if travel < self.extreme => self.extreme = travel,
+                Some(sensitivity) if travel - self.extreme >= sensitivity => {
+                    self.pressed = true;
+                    self.extreme = travel;
+                }
+                _ => {}
+            }
+        } else if travel >= config.actuation_point {
+            self.pressed = true;
+            self.extreme = travel;
+        }
+    }
+}
+
+// Hall effect sensors read through multiplexers. Calibration is learned as keys are used: the
+// rest reading is taken at the first scan and both extremes follow the readings from then on.
+pub struct AnalogMatrix<const ROW_COUNT: usize, const COL_COUNT: usize> {
+    reader: Box<dyn SensorReader>,
+    // Sensor index at each matrix position, if any
+    sensors: [[Option<usize>; COL_COUNT]; ROW_COUNT],
+    // Readings of the current scan, allocated once for every sensor
+    values: Vec<Option<u16>>,
+    config: AnalogMatrixConfig,
+    calibration: [[Option<KeyCalibration>; COL_COUNT]; ROW_COUNT],
+    is_calibration_changed: bool,
+    states: [[KeyState; COL_COUNT]; ROW_COUNT],
+    travel: Travel<ROW_COUNT, COL_COUNT>,
+    previous_result: Result<{ ROW_COUNT }, { COL_COUNT }>,
+}
+
+#[allow(dead_code)]
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> AnalogMatrix<ROW_COUNT, COL_COUNT> {
+    pub fn new(
+        reader: Box<dyn SensorReader>,
+        sensors: [[Option<usize>; COL_COUNT]; ROW_COUNT],
+        config: AnalogMatrixConfig,
+    ) -> Self {
+        assert!(
+            config.release_point < config.actuation_point,
+            "release point has to be above the actuation point"
+        );
+        let sensor_count = reader.channel_count() * reader.input_count();
+        assert!(
+            sensors
+                .iter()
+                .flatten()
+                .flatten()
+                .all(|&k| k < sensor_count),
+            "analog matrix refers to a sensor that does not exist"
+        );
+        AnalogMatrix {
+            reader,
+            sensors,
+            values: vec![None; sensor_count],
+            config,
+            calibration: [[None; COL_COUNT]; ROW_COUNT],
+            is_calibration_changed: false,
+            states: [[KeyState::default(); COL_COUNT]; ROW_COUNT],
+            travel: [[0; COL_COUNT]; ROW_COUNT],
+            previous_result: Result::default(),
+        }
+    }
+
+    fn calibrate(&mut self, i: usize, j: usize, value: u16) -> KeyCalibration {
+        let is_rising = self.config.is_rising;
+        let calibration = self.calibration[i][j].get_or_insert_with(|| {
+            self.is_calibration_changed = true;
+            KeyCalibration {
+                rest: value,
+                bottom: if is_rising {
+                    value.saturating_add(self.config.default_range)
+                } else {
+                    value.saturating_sub(self.config.default_range)
+                },
+            }
+        });
+        // Depth of the reading past each extreme, positive when outside the calibrated range
+        let (past_rest, past_bottom) = if is_rising {
+            (
+                calibration.rest as i32 - value as i32,
+                value as i32 - calibration.bottom as i32,
+            )
+        } else {
+            (
+                value as i32 - calibration.rest as i32,
+                calibration.bottom as i32 - value as i32,
+            )
+        };
+        if past_rest > CALIBRATION_NOISE as i32 {
+            calibration.rest = value;
+            self.is_calibration_changed = true;
+        } else if past_bottom > CALIBRATION_NOISE as i32 {
+            calibration.bottom = value;
+            self.is_calibration_changed = true;
+        }
+        *calibration
+    }
+
+    fn to_travel(calibration: KeyCalibration, value: u16) -> u16 {
+        let range = calibration.rest.abs_diff(calibration.bottom).max(1) as u32;
+        let distance = value.abs_diff(calibration.rest) as u32;
+        // Readings on the far side of rest are at rest
+        let is_pressed_side =
+            (value >= calibration.rest) == (calibration.bottom >= calibration.rest);
+        if !is_pressed_side {
+            return 0;
+        }
+        (distance.min(range) * FULL_TRAVEL as u32 / range) as u16
+    }
+
+    // Reads every input on the selected channel.
+    fn read_channel(&mut self, channel: usize) {
+        let channel_count = self.reader.channel_count();
+        for input in 0..self.reader.input_count() {
+            self.values[input * channel_count + channel] = Some(self.reader.read(input));
+        }
+    }
+
+    // Turns the readings of a scan into travel and key states.
+    fn update(&mut self, now_ticks: u64) -> Result<ROW_COUNT, COL_COUNT> {
+        let mut result = Result::default();
+        for i in 0..ROW_COUNT {
+            for j in 0..COL_COUNT {
+                let Some(value) = self.sensors[i][j].and_then(|k| self.values[k]) else {
+                    continue;
+                };
+                let calibration = self.calibrate(i, j, value);
+                let travel = Self::to_travel(calibration, value);
+                self.travel[i][j] = travel;
+                self.states[i][j].update(travel, &self.config);
+                let pressed = self.states[i][j].pressed;
+                result.matrix[i][j] = Bit {
+                    edge: Edge::from((self.previous_result.matrix[i][j].pressed, pressed)),
+                    pressed,
+                }
+            }
+        }
+        result.scan_time_ticks = now_ticks;
+        self.previous_result = result;
+        result
+    }
+}
+
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> Scanner<ROW_COUNT, COL_COUNT>
+    for AnalogMatrix<ROW_COUNT, COL_COUNT>
+{
+    async fn scan(&mut self) -> Result<ROW_COUNT, COL_COUNT> {
+        let channel_count = self.reader.channel_count();
+        self.values.fill(None);
+        for channel in 0..channel_count {
+            self.reader.select(channel);
+            if self.config.settle_delay.ticks() > 0 {
+                Mono::delay(self.config.settle_delay).await;
+            }
+            self.read_channel(channel);
+        }
+        self.update(Mono::now().ticks())
+    }
+
+    fn travel(&self) -> Option<Travel<ROW_COUNT, COL_COUNT>> {
+        Some(self.travel)
+    }
+
+    fn take_calibration(&mut self) -> Option<CalibrationRecord> {
+        if !self.is_calibration_changed {
+            return None;
+        }
+        self.is_calibration_changed = false;
+        Some(CalibrationRecord {
+            keys: self.calibration.iter().flatten().copied().collect(),
+        })
+    }
+
+    fn restore_calibration(&mut self, record: CalibrationRecord) {
+        if record.keys.len() != ROW_COUNT * COL_COUNT {
+            defmt::warn!("analog calibration does not match the matrix, ignoring it");
+            return;
+        }
+        for (k, calibration) in record.keys.into_iter().enumerate() {
+            self.calibration[k / COL_COUNT][k % COL_COUNT] = calibration;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REST: u16 = 2000;
+
+    fn new_matrix(rapid_trigger_sensitivity: Option<u16>) -> AnalogMatrix<1, 1> {
+        AnalogMatrix::new(
+            Box::new(MockSensorReader::new(1, 1, REST)),
+            [[Some(0)]],
+            AnalogMatrixConfig {
+                rapid_trigger_sensitivity,
+                ..Default::default()
+            },
+        )
+    }
+
+    // Scans a single reading and returns the key as reported, along with its travel.
+    fn scan(matrix: &mut AnalogMatrix<1, 1>, value: u16) -> (Edge, bool, u16) {
+        matrix.reader = Box::new(MockSensorReader::new(1, 1, value));
+        matrix.reader.select(0);
+        matrix.read_channel(0);
+        let bit = matrix.update(0).matrix[0][0];
+        (bit.edge, bit.pressed, matrix.travel[0][0])
+    }
+
+    #[test]
+    fn calibration_follows_readings_past_the_noise() {
+        let mut matrix = new_matrix(None);
+        assert_eq!(scan(&mut matrix, REST), (Edge::None, false, 0));
+        let calibration = KeyCalibration {
+            rest: REST,
+            bottom: REST + 1000,
+        };
+        assert_eq!(matrix.take_calibration().unwrap().keys, [Some(calibration)]);
+        assert_eq!(matrix.take_calibration(), None);
+
+        // within the noise on either side, nothing moves
+        scan(&mut matrix, REST - CALIBRATION_NOISE);
+        scan(&mut matrix, REST + 1000 + CALIBRATION_NOISE);
+        assert_eq!(matrix.take_calibration(), None);
+
+        // bottoming out deeper widens the range
+        assert_eq!(scan(&mut matrix, REST + 2000).2, FULL_TRAVEL);
+        assert_eq!(scan(&mut matrix, REST + 1000).2, FULL_TRAVEL / 2);
+        assert_eq!(
+            matrix.take_calibration().unwrap().keys,
+            [Some(KeyCalibration {
+                rest: REST,
+                bottom: REST + 2000,
+            })]
+        );
+
+        let mut restored = new_matrix(None);
+        restored.restore_calibration(CalibrationRecord {
+            keys: vec![Some(calibration)],
+        });
+        assert_eq!(scan(&mut restored, REST + 500).2, FULL_TRAVEL / 2);
+    }
+
+    #[test]
+    fn keys_actuate_and_release_at_their_points() {
+        let mut matrix = new_matrix(None);
+        // default range of 1000, actuation at 500 and release at 400
+        assert_eq!(scan(&mut matrix, REST), (Edge::None, false, 0));
+        assert_eq!(scan(&mut matrix, REST + 450), (Edge::None, false, 450));
+        assert_eq!(scan(&mut matrix, REST + 500), (Edge::Rising, true, 500));
+        assert_eq!(scan(&mut matrix, REST + 450), (Edge::None, true, 450));
+        assert_eq!(scan(&mut matrix, REST + 400), (Edge::Falling, false, 400));
+        assert_eq!(scan(&mut matrix, REST + 450), (Edge::None, false, 450));
+    }
+
+    #[test]
+    fn rapid_trigger_follows_direction_changes() {
+        let mut matrix = new_matrix(Some(100));
+        scan(&mut matrix, REST);
+        assert_eq!(scan(&mut matrix, REST + 800), (Edge::Rising, true, 800));
+        // moving up by less than the sensitivity keeps it pressed
+        assert_eq!(scan(&mut matrix, REST + 750), (Edge::None, true, 750));
+        assert_eq!(scan(&mut matrix, REST + 700), (Edge::Falling, false, 700));
+        assert_eq!(scan(&mut matrix, REST + 650), (Edge::None, false, 650));
+        // pressed again well above the actuation point, from the shallowest travel
+        assert_eq!(scan(&mut matrix, REST + 750), (Edge::Rising, true, 750));
+        assert_eq!(scan(&mut matrix, REST + 350), (Edge::Falling, false, 350));
+        // under the release point, it takes the actuation point again
+        assert_eq!(scan(&mut matrix, REST + 450), (Edge::None, false, 450));
+    }
+}
@@ -0,0 +1,93 @@
+use alloc::boxed::Box;
+use embedded_hal::digital::InputPin;
+use rp2040_hal::gpio;
+use rtic_monotonics::rp2040::prelude::*;
+
+use crate::{kb::Mono, key::Edge};
+
+use super::{
+    switch::{ActiveLevel, MatrixPin, Pull},
+    Bit, Result, Scanner,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct DirectPinMatrixConfig {
+    pub pull: Pull,
+    pub active_level: ActiveLevel,
+}
+
+impl Default for DirectPinMatrixConfig {
+    // Switches wired between the pin and ground.
+    fn default() -> Self {
+        DirectPinMatrixConfig {
+            pull: Pull::Up,
+            active_level: ActiveLevel::Low,
+        }
+    }
+}
+
+// One GPIO per key. Pins are placed on a logical grid so that the result lines up with the
+// keymap; positions without a switch are left as `None` and always read as released.
+pub struct DirectPinMatrix<const ROW_COUNT: usize, const COL_COUNT: usize> {
+    pins: [[Option<Box<dyn InputPin<Error = gpio::Error> + Sync + Send>>; COL_COUNT]; ROW_COUNT],
+    config: DirectPinMatrixConfig,
+    previous_result: Result<{ ROW_COUNT }, { COL_COUNT }>,
+}
+
+#[allow(dead_code)]
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> DirectPinMatrix<ROW_COUNT, COL_COUNT> {
+    pub fn new(
+        pins: [[Option<MatrixPin>; COL_COUNT]; ROW_COUNT],
+        config: DirectPinMatrixConfig,
+    ) -> Self {
+        if matches!(
+            (config.pull, config.active_level),
+            (Pull::Up, ActiveLevel::High) | (Pull::Down, ActiveLevel::Low)
+        ) {
+            defmt::warn!(
+                "direct pins are pulled {} while active {}",
+                config.pull,
+                config.active_level
+            );
+        }
+
+        DirectPinMatrix {
+            pins: pins.map(|row| {
+                row.map(|pin| {
+                    pin.map(|p| match config.pull {
+                        Pull::Up => Box::new(p.into_pull_up_input())
+                            as Box<dyn InputPin<Error = gpio::Error> + Sync + Send>,
+                        Pull::Down => Box::new(p.into_pull_down_input()),
+                        Pull::None => Box::new(p.into_floating_input()),
+                    })
+                })
+            }),
+            config,
+            previous_result: Result::default(),
+        }
+    }
+}
+
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> Scanner<ROW_COUNT, COL_COUNT>
+    for DirectPinMatrix<ROW_COUNT, COL_COUNT>
+{
+    async fn scan(&mut self) -> Result<ROW_COUNT, COL_COUNT> {
+        let mut result = Result::default();
+        let is_active_high = self.config.active_level == ActiveLevel::High;
+        for (i, row) in self.pins.iter_mut().enumerate() {
+            for (j, pin) in row.iter_mut().enumerate() {
+                let pressed = match pin {
+                    Some(pin) => pin.is_high().unwrap() == is_active_high,
+                    None => false,
+                };
+                result.matrix[i][j] = Bit {
+                    edge: Edge::from((self.previous_result.matrix[i][j].pressed, pressed)),
+                    pressed,
+                }
+            }
+        }
+        result.scan_time_ticks = Mono::now().ticks();
+        self.previous_result = result;
+        result
+    }
+}
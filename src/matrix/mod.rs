@@ -0,0 +1,462 @@
+pub mod analog;
+pub mod direct;
+pub mod pio;
+pub mod shift;
+pub mod switch;
+
+use alloc::rc::Rc;
+use core::{cell::RefCell, future};
+use defmt::Format;
+use hal::pac;
+use rtic_monotonics::rp2040::prelude::*;
+use rtic_sync::arbiter::Arbiter;
+use serde::{
+    de,
+    ser::{self, SerializeStruct},
+    Deserialize, Serialize,
+};
+
+use self::{
+    analog::AnalogMatrix, direct::DirectPinMatrix, pio::PioMatrix, shift::ShiftRegisterMatrix,
+    switch::SwitchMatrix,
+};
+use crate::{
+    debug,
+    kb::Mono,
+    key::Edge,
+    remote::{
+        self,
+        transport::{Payload, PAYLOAD_CAPACITY},
+        MethodId, RemoteInvoker, Service, ServiceId,
+    },
+    split,
+};
+
+#[derive(Clone, Copy, Debug, Format)]
+pub struct Result<const ROW_COUNT: usize, const COL_COUNT: usize> {
+    pub scan_time_ticks: u64,
+    pub matrix: [[Bit; COL_COUNT]; ROW_COUNT],
+}
+
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> Serialize for Result<ROW_COUNT, COL_COUNT> {
+    fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut state = serializer.serialize_struct("Result", 2)?;
+        state.serialize_field("scan_time_ticks", &self.scan_time_ticks)?;
+
+        let mut packed_matrix = heapless::Vec::<u8, PAYLOAD_CAPACITY>::new();
+        let mut bit_accumulator: u32 = 0; // use u32 to avoid overflow
+        let mut bit_count = 0;
+
+        for row in &self.matrix {
+            for bit in row {
+                bit_accumulator = (bit_accumulator << 3) | bit.pack() as u32;
+                bit_count += 3;
+
+                if bit_count >= 8 {
+                    packed_matrix
+                        .push((bit_accumulator >> (bit_count - 8)) as u8)
+                        .map_err(|_| ser::Error::custom("matrix does not fit in a packet"))?;
+                    bit_count -= 8;
+                }
+            }
+        }
+
+        if bit_count > 0 {
+            packed_matrix
+                .push((bit_accumulator << (8 - bit_count)) as u8)
+                .map_err(|_| ser::Error::custom("matrix does not fit in a packet"))?;
+        }
+
+        state.serialize_field("matrix", &packed_matrix)?;
+        state.end()
+    }
+}
+
+impl<'de, const ROW_COUNT: usize, const COL_COUNT: usize> Deserialize<'de>
+    for Result<ROW_COUNT, COL_COUNT>
+{
+    fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct ResultOwned {
+            scan_time_ticks: u64,
+            matrix: heapless::Vec<u8, PAYLOAD_CAPACITY>,
+        }
+
+        let temp = ResultOwned::deserialize(deserializer)?;
+        let expected_len = (ROW_COUNT * COL_COUNT * 3 + 7) / 8; // add 7 to round up to next byte
+        if temp.matrix.len() != expected_len {
+            return Err(de::Error::custom(
+                "matrix length does not match expected length",
+            ));
+        }
+
+        let mut matrix = [[Bit {
+            edge: Edge::None,
+            pressed: false,
+        }; COL_COUNT]; ROW_COUNT];
+        let mut bit_accumulator: u32 = 0; // use u32 to avoid overflow
+        let mut bit_count = 0;
+        let mut byte_index = 0;
+
+        #[allow(clippy::needless_range_loop)]
+        for i in 0..ROW_COUNT {
+            for j in 0..COL_COUNT {
+                while bit_count < 3 {
+                    bit_accumulator = (bit_accumulator << 8) | temp.matrix[byte_index] as u32;
+                    byte_index += 1;
+                    bit_count += 8;
+                }
+
+                matrix[i][j] = Bit::unpack((bit_accumulator >> (bit_count - 3)) as u8);
+                bit_count -= 3;
+            }
+        }
+
+        Ok(Result {
+            scan_time_ticks: temp.scan_time_ticks,
+            matrix,
+        })
+    }
+}
+
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> Default for Result<ROW_COUNT, COL_COUNT> {
+    fn default() -> Self {
+        Result {
+            scan_time_ticks: 0,
+            matrix: [[Bit {
+                edge: Edge::None,
+                pressed: false,
+            }; COL_COUNT]; ROW_COUNT],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Format)]
+pub struct Bit {
+    pub edge: Edge,
+    pub pressed: bool,
+}
+
+impl Bit {
+    fn pack(&self) -> u8 {
+        (self.edge as u8) << 1 | (self.pressed as u8)
+    }
+
+    fn unpack(packed: u8) -> Self {
+        let edge = match packed >> 1 {
+            0 => Edge::None,
+            1 => Edge::Rising,
+            2 => Edge::Falling,
+            _ => Edge::None, // invalid
+        };
+        let pressed = packed & 1 != 0;
+        Bit { edge, pressed }
+    }
+}
+
+pub trait Scanner<const ROW_COUNT: usize, const COL_COUNT: usize> {
+    async fn scan(&mut self) -> Result<ROW_COUNT, COL_COUNT>;
+
+    // Travel of each key as of the last scan, for scanners that measure it.
+    fn travel(&self) -> Option<analog::Travel<ROW_COUNT, COL_COUNT>> {
+        None
+    }
+
+    // Calibration learned since the last call, to be persisted.
+    fn take_calibration(&mut self) -> Option<analog::CalibrationRecord> {
+        None
+    }
+
+    fn restore_calibration(&mut self, _: analog::CalibrationRecord) {}
+}
+
+// Every scanner a keyboard can pick. Keyboards pick one at runtime, the enum keeps scans statically
+// dispatched so that they do not allocate.
+pub enum AnyScanner<const ROW_COUNT: usize, const COL_COUNT: usize> {
+    Switch(SwitchMatrix<ROW_COUNT, COL_COUNT>),
+    Direct(DirectPinMatrix<ROW_COUNT, COL_COUNT>),
+    Shift(ShiftRegisterMatrix<ROW_COUNT, COL_COUNT>),
+    Analog(AnalogMatrix<ROW_COUNT, COL_COUNT>),
+    // On the state machine left next to the RGB matrix one
+    Pio(PioMatrix<pac::PIO0, hal::pio::SM1, ROW_COUNT, COL_COUNT>),
+}
+
+macro_rules! dispatch {
+    ($scanner:expr, $inner:ident => $call:expr) => {
+        match $scanner {
+            AnyScanner::Switch($inner) => $call,
+            AnyScanner::Direct($inner) => $call,
+            AnyScanner::Shift($inner) => $call,
+            AnyScanner::Analog($inner) => $call,
+            AnyScanner::Pio($inner) => $call,
+        }
+    };
+}
+
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> Scanner<ROW_COUNT, COL_COUNT>
+    for AnyScanner<ROW_COUNT, COL_COUNT>
+{
+    async fn scan(&mut self) -> Result<ROW_COUNT, COL_COUNT> {
+        dispatch!(self, scanner => scanner.scan().await)
+    }
+
+    fn travel(&self) -> Option<analog::Travel<ROW_COUNT, COL_COUNT>> {
+        dispatch!(self, scanner => scanner.travel())
+    }
+
+    fn take_calibration(&mut self) -> Option<analog::CalibrationRecord> {
+        dispatch!(self, scanner => scanner.take_calibration())
+    }
+
+    fn restore_calibration(&mut self, record: analog::CalibrationRecord) {
+        dispatch!(self, scanner => scanner.restore_calibration(record))
+    }
+}
+
+// Size of the matrix of one half of a split keyboard, and where it sits in the merged matrix.
+#[derive(Clone, Copy, Debug, Format)]
+pub struct SplitHalf {
+    pub row_count: usize,
+    pub col_count: usize,
+    pub row_offset: usize,
+    pub col_offset: usize,
+}
+
+impl SplitHalf {
+    pub const fn new(
+        row_count: usize,
+        col_count: usize,
+        row_offset: usize,
+        col_offset: usize,
+    ) -> Self {
+        SplitHalf {
+            row_count,
+            col_count,
+            row_offset,
+            col_offset,
+        }
+    }
+}
+
+// Lets a half with a smaller matrix than the other one be scanned as `ROW_COUNT` x `COL_COUNT`,
+// so that both halves run the same firmware. Extra positions are never pressed.
+pub struct PaddedScanner<
+    S: Scanner<INNER_ROW_COUNT, INNER_COL_COUNT>,
+    const ROW_COUNT: usize,
+    const COL_COUNT: usize,
+    const INNER_ROW_COUNT: usize,
+    const INNER_COL_COUNT: usize,
+> {
+    inner: S,
+}
+
+#[allow(dead_code)]
+impl<
+        S: Scanner<INNER_ROW_COUNT, INNER_COL_COUNT>,
+        const ROW_COUNT: usize,
+        const COL_COUNT: usize,
+        const INNER_ROW_COUNT: usize,
+        const INNER_COL_COUNT: usize,
+    > PaddedScanner<S, ROW_COUNT, COL_COUNT, INNER_ROW_COUNT, INNER_COL_COUNT>
+{
+    pub fn new(inner: S) -> Self {
+        assert!(
+            INNER_ROW_COUNT <= ROW_COUNT && INNER_COL_COUNT <= COL_COUNT,
+            "padded matrix is smaller than the scanned one"
+        );
+        PaddedScanner { inner }
+    }
+}
+
+impl<
+        S: Scanner<INNER_ROW_COUNT, INNER_COL_COUNT>,
+        const ROW_COUNT: usize,
+        const COL_COUNT: usize,
+        const INNER_ROW_COUNT: usize,
+        const INNER_COL_COUNT: usize,
+    > Scanner<ROW_COUNT, COL_COUNT>
+    for PaddedScanner<S, ROW_COUNT, COL_COUNT, INNER_ROW_COUNT, INNER_COL_COUNT>
+{
+    async fn scan(&mut self) -> Result<ROW_COUNT, COL_COUNT> {
+        let inner_result = self.inner.scan().await;
+        let mut result = Result {
+            scan_time_ticks: inner_result.scan_time_ticks,
+            ..Default::default()
+        };
+        for (row, inner_row) in result.matrix.iter_mut().zip(inner_result.matrix) {
+            row[..INNER_COL_COUNT].copy_from_slice(&inner_row);
+        }
+        result
+    }
+}
+
+pub trait SplitScanner<
+    const ROW_COUNT: usize,
+    const COL_COUNT: usize,
+    const HALF_ROW_COUNT: usize,
+    const HALF_COL_COUNT: usize,
+>: Scanner<HALF_ROW_COUNT, HALF_COL_COUNT>
+{
+    async fn scan<I>(&mut self, client: &Arbiter<Rc<RefCell<I>>>) -> Result<ROW_COUNT, COL_COUNT>
+    where
+        I: RemoteInvoker;
+}
+
+// Each half scans its own matrix, padded to `HALF_ROW_COUNT` x `HALF_COL_COUNT` when the halves
+// differ, and the master merges both into the `ROW_COUNT` x `COL_COUNT` logical matrix.
+pub struct SplitSwitchMatrix<
+    const ROW_COUNT: usize,
+    const COL_COUNT: usize,
+    const HALF_ROW_COUNT: usize,
+    const HALF_COL_COUNT: usize,
+> {
+    local_matrix: AnyScanner<HALF_ROW_COUNT, HALF_COL_COUNT>,
+    left: SplitHalf,
+    right: SplitHalf,
+}
+
+#[allow(dead_code)]
+impl<
+        const ROW_COUNT: usize,
+        const COL_COUNT: usize,
+        const HALF_ROW_COUNT: usize,
+        const HALF_COL_COUNT: usize,
+    > SplitSwitchMatrix<ROW_COUNT, COL_COUNT, HALF_ROW_COUNT, HALF_COL_COUNT>
+{
+    pub fn new(
+        local_matrix: AnyScanner<HALF_ROW_COUNT, HALF_COL_COUNT>,
+        left: SplitHalf,
+        right: SplitHalf,
+    ) -> Self {
+        for half in [left, right] {
+            assert!(
+                half.row_count <= HALF_ROW_COUNT && half.col_count <= HALF_COL_COUNT,
+                "split half is larger than the scanned matrix"
+            );
+            assert!(
+                half.row_offset + half.row_count <= ROW_COUNT
+                    && half.col_offset + half.col_count <= COL_COUNT,
+                "split half does not fit in the merged matrix"
+            );
+        }
+        SplitSwitchMatrix {
+            local_matrix,
+            left,
+            right,
+        }
+    }
+}
+
+const SERVICE_ID_KEY_MATRIX: ServiceId = 0x10;
+const METHOD_ID_KEY_MATRIX_SCAN: MethodId = 0x11;
+
+impl<
+        const ROW_COUNT: usize,
+        const COL_COUNT: usize,
+        const HALF_ROW_COUNT: usize,
+        const HALF_COL_COUNT: usize,
+    > Scanner<HALF_ROW_COUNT, HALF_COL_COUNT>
+    for SplitSwitchMatrix<ROW_COUNT, COL_COUNT, HALF_ROW_COUNT, HALF_COL_COUNT>
+{
+    async fn scan(&mut self) -> Result<HALF_ROW_COUNT, HALF_COL_COUNT> {
+        let start_time = Mono::now();
+        let result = self.local_matrix.scan().await;
+        let end_time = Mono::now();
+        debug::log_duration(debug::LogDurationTag::KeyMatrixScan, start_time, end_time);
+        result
+    }
+}
+
+impl<
+        const ROW_COUNT: usize,
+        const COL_COUNT: usize,
+        const HALF_ROW_COUNT: usize,
+        const HALF_COL_COUNT: usize,
+    > SplitScanner<ROW_COUNT, COL_COUNT, HALF_ROW_COUNT, HALF_COL_COUNT>
+    for SplitSwitchMatrix<ROW_COUNT, COL_COUNT, HALF_ROW_COUNT, HALF_COL_COUNT>
+{
+    async fn scan<I>(&mut self, client: &Arbiter<Rc<RefCell<I>>>) -> Result<ROW_COUNT, COL_COUNT>
+    where
+        I: RemoteInvoker,
+    {
+        let (remote_response, local_result) = future::join!(client
+            .access()
+            .await
+            .borrow_mut()
+            .invoke::<SwitchMatrixScanRequest, SwitchMatrixScanResponse<{HALF_ROW_COUNT}, {HALF_COL_COUNT}>>(
+                SERVICE_ID_KEY_MATRIX,
+                METHOD_ID_KEY_MATRIX_SCAN,
+                SwitchMatrixScanRequest {},
+            ), Scanner::scan(self)).await;
+
+        // merge
+        let mut merged_matrix = [[Bit {
+            edge: Edge::None,
+            pressed: false,
+        }; COL_COUNT]; ROW_COUNT];
+
+        let (left_matrix, right_matrix) = match split::get_self_side() {
+            split::Side::Left => (local_result.matrix, remote_response.result.matrix),
+            split::Side::Right => (remote_response.result.matrix, local_result.matrix),
+        };
+        #[allow(clippy::needless_range_loop)]
+        for (half, matrix) in [(self.left, left_matrix), (self.right, right_matrix)] {
+            for i in 0..half.row_count {
+                for j in 0..half.col_count {
+                    merged_matrix[half.row_offset + i][half.col_offset + j] = matrix[i][j];
+                }
+            }
+        }
+
+        Result {
+            scan_time_ticks: local_result.scan_time_ticks,
+            matrix: merged_matrix,
+        }
+    }
+}
+
+impl<
+        const ROW_COUNT: usize,
+        const COL_COUNT: usize,
+        const HALF_ROW_COUNT: usize,
+        const HALF_COL_COUNT: usize,
+    > Service for SplitSwitchMatrix<ROW_COUNT, COL_COUNT, HALF_ROW_COUNT, HALF_COL_COUNT>
+{
+    fn get_service_id(&self) -> ServiceId {
+        SERVICE_ID_KEY_MATRIX
+    }
+
+    async fn dispatch(
+        &mut self,
+        method_id: MethodId,
+        _request_buffer: &[u8],
+    ) -> core::result::Result<Payload, remote::Error> {
+        match method_id {
+            METHOD_ID_KEY_MATRIX_SCAN => {
+                let result = Scanner::scan(self).await;
+                let mut buffer = [0u8; PAYLOAD_CAPACITY];
+                match postcard::to_slice(&SwitchMatrixScanResponse { result }, &mut buffer)
+                    .map(|res| Payload::from_slice(res))
+                {
+                    Ok(Ok(res)) => core::result::Result::Ok(res),
+                    _ => core::result::Result::Err(remote::Error::ResponseSerializationFailed),
+                }
+            }
+            _ => core::result::Result::Err(remote::Error::MethodUnimplemented),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Format, Serialize)]
+pub struct SwitchMatrixScanRequest {}
+
+#[derive(Clone, Copy, Debug, Deserialize, Format, Serialize)]
+pub struct SwitchMatrixScanResponse<const ROW_COUNT: usize, const COL_COUNT: usize> {
+    result: Result<{ ROW_COUNT }, { COL_COUNT }>,
+}
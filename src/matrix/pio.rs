@@ -0,0 +1,241 @@
+use ::pio::{
+    Assembler, InSource, JmpCondition, MovDestination, MovOperation, MovSource, SetDestination,
+    RP2040_MAX_PROGRAM_SIZE,
+};
+use hal::{
+    fugit::HertzU32,
+    gpio,
+    pio::{
+        Buffers, PIOBuilder, PIOExt, PinDir, Running, Rx, ShiftDirection, StateMachine,
+        StateMachineIndex, UninitStateMachine, PIO,
+    },
+};
+use rtic_monotonics::rp2040::prelude::*;
+
+use crate::{kb::Mono, key::Edge};
+
+use super::{
+    switch::{ActiveLevel, MatrixPin},
+    Bit, Result, Scanner,
+};
+
+// State machine clock, so that one cycle is 100ns.
+const PIO_CLOCK_HZ: u64 = 10_000_000;
+// Longest delay that can be encoded on a single instruction without side-set.
+const MAX_SETTLE_CYCLES: u64 = 31;
+// Words the joined RX FIFO holds.
+const FIFO_DEPTH: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PioMatrixConfig {
+    pub active_level: ActiveLevel,
+    // Time given to the row lines to follow a newly selected column before they are sampled.
+    // At most 3.1us.
+    pub settle_delay: <Mono as Monotonic>::Duration,
+}
+
+impl Default for PioMatrixConfig {
+    fn default() -> Self {
+        PioMatrixConfig {
+            active_level: ActiveLevel::High,
+            settle_delay: 1.micros(),
+        }
+    }
+}
+
+// COL2ROW matrix scanned by a PIO state machine. The state machine walks the columns on its own
+// and pushes one word per column into the RX FIFO, holding the one-hot column above the row
+// bits, so the CPU only has to collect a frame and compute edges.
+//
+// The FIFO fills up within a few microseconds of being drained and then drops new words, so a
+// scan returns what was sampled right after the previous one without waiting on the state
+// machine. The words it holds are consecutive columns, so there can be no more columns than it
+// has room for, or some would go stale.
+//
+// Columns and rows each have to be on consecutive GPIOs, in order, and fit in one word
+// together.
+pub struct PioMatrix<
+    P: PIOExt,
+    SM: StateMachineIndex,
+    const ROW_COUNT: usize,
+    const COL_COUNT: usize,
+> {
+    rx: Rx<(P, SM)>,
+    _sm: StateMachine<(P, SM), Running>,
+    active_level: ActiveLevel,
+    rows: [u32; COL_COUNT],
+    previous_result: Result<{ ROW_COUNT }, { COL_COUNT }>,
+}
+
+#[allow(dead_code)]
+impl<P: PIOExt, SM: StateMachineIndex, const ROW_COUNT: usize, const COL_COUNT: usize>
+    PioMatrix<P, SM, ROW_COUNT, COL_COUNT>
+where
+    gpio::DynPinId: gpio::ValidFunction<P::PinFunction>,
+{
+    pub fn new(
+        rows: [MatrixPin; ROW_COUNT],
+        cols: [MatrixPin; COL_COUNT],
+        config: PioMatrixConfig,
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        clock_freq: HertzU32,
+    ) -> Self {
+        assert!(
+            ROW_COUNT > 0 && COL_COUNT > 0 && ROW_COUNT + COL_COUNT <= 32,
+            "PIO matrix rows and columns have to fit in one word"
+        );
+        assert!(
+            COL_COUNT <= FIFO_DEPTH,
+            "PIO matrix columns have to fit in the RX FIFO"
+        );
+        let row_base = Self::pin_base(&rows);
+        let col_base = Self::pin_base(&cols);
+
+        let settle_cycles = config.settle_delay.to_micros() * PIO_CLOCK_HZ / 1_000_000;
+        assert!(
+            settle_cycles <= MAX_SETTLE_CYCLES,
+            "PIO matrix settle delay is too long"
+        );
+
+        // The pins are handed over to the state machine for good
+        for pin in rows {
+            match config.active_level {
+                ActiveLevel::High => {
+                    pin.into_function::<P::PinFunction>();
+                }
+                ActiveLevel::Low => {
+                    pin.into_pull_type::<gpio::PullUp>()
+                        .into_function::<P::PinFunction>();
+                }
+            }
+        }
+        for pin in cols {
+            pin.into_function::<P::PinFunction>();
+        }
+
+        let program = Self::program(config.active_level, settle_cycles as u8);
+        let installed = pio.install(&program).unwrap();
+        let divisor = clock_freq.to_Hz() as u64 * 256 / PIO_CLOCK_HZ;
+        let (mut sm, rx, _) = PIOBuilder::from_installed_program(installed)
+            .out_pins(col_base, COL_COUNT as u8)
+            .in_pin_base(row_base)
+            .in_shift_direction(ShiftDirection::Left)
+            .autopush(false)
+            .buffers(Buffers::OnlyRx)
+            .clock_divisor_fixed_point((divisor >> 8) as u16, divisor as u8)
+            .build(sm);
+        sm.set_pindirs(
+            (col_base..col_base + COL_COUNT as u8)
+                .map(|id| (id, PinDir::Output))
+                .chain((row_base..row_base + ROW_COUNT as u8).map(|id| (id, PinDir::Input))),
+        );
+
+        PioMatrix {
+            rx,
+            _sm: sm.start(),
+            active_level: config.active_level,
+            rows: [0; COL_COUNT],
+            previous_result: Result::default(),
+        }
+    }
+
+    fn pin_base<const N: usize>(pins: &[MatrixPin; N]) -> u8 {
+        let base = pins[0].id().num;
+        assert!(
+            pins.iter()
+                .enumerate()
+                .all(|(k, p)| p.id().num == base + k as u8),
+            "PIO matrix pins have to be consecutive"
+        );
+        base
+    }
+
+    //     set y, 1
+    //     set x, COL_COUNT - 1
+    // column:
+    //     mov pins, y [settle]      ; select the column
+    //     in y, COL_COUNT
+    //     in pins, ROW_COUNT
+    //     mov pins, null            ; release it
+    //     push noblock              ; a full FIFO means the CPU is not listening, so drop
+    //     mov isr, y                ; walk the column bit using the now empty ISR
+    //     in null, 1
+    //     mov y, isr
+    //     mov isr, null
+    //     jmp x-- column
+    //
+    // Active-low matrices drive the inverse of y and null instead.
+    fn program(
+        active_level: ActiveLevel,
+        settle_cycles: u8,
+    ) -> ::pio::Program<RP2040_MAX_PROGRAM_SIZE> {
+        let op = match active_level {
+            ActiveLevel::High => MovOperation::None,
+            ActiveLevel::Low => MovOperation::Invert,
+        };
+        let mut a = Assembler::<RP2040_MAX_PROGRAM_SIZE>::new();
+        let mut wrap_target = a.label();
+        let mut wrap_source = a.label();
+        let mut column = a.label();
+        a.bind(&mut wrap_target);
+        a.set(SetDestination::Y, 1);
+        a.set(SetDestination::X, COL_COUNT as u8 - 1);
+        a.bind(&mut column);
+        a.mov_with_delay(MovDestination::PINS, op, MovSource::Y, settle_cycles);
+        a.in_(InSource::Y, COL_COUNT as u8);
+        a.in_(InSource::PINS, ROW_COUNT as u8);
+        a.mov(MovDestination::PINS, op, MovSource::NULL);
+        a.push(false, false);
+        a.mov(MovDestination::ISR, MovOperation::None, MovSource::Y);
+        a.in_(InSource::NULL, 1);
+        a.mov(MovDestination::Y, MovOperation::None, MovSource::ISR);
+        a.mov(MovDestination::ISR, MovOperation::None, MovSource::NULL);
+        a.jmp(JmpCondition::XDecNonZero, &mut column);
+        a.bind(&mut wrap_source);
+        a.assemble_with_wrap(wrap_source, wrap_target)
+    }
+}
+
+impl<P: PIOExt, SM: StateMachineIndex, const ROW_COUNT: usize, const COL_COUNT: usize>
+    Scanner<ROW_COUNT, COL_COUNT> for PioMatrix<P, SM, ROW_COUNT, COL_COUNT>
+{
+    async fn scan(&mut self) -> Result<ROW_COUNT, COL_COUNT> {
+        let row_mask = (1 << ROW_COUNT) - 1;
+        let idle_rows = match self.active_level {
+            ActiveLevel::High => 0,
+            ActiveLevel::Low => row_mask,
+        };
+        // The FIFO holds a full frame, later words of a column replace earlier ones. Reading no
+        // more than the FIFO held keeps this from chasing the state machine.
+        let mut word_count = 0;
+        while word_count < FIFO_DEPTH {
+            let Some(word) = self.rx.read() else {
+                break;
+            };
+            word_count += 1;
+            let col = word >> ROW_COUNT;
+            if col.count_ones() != 1 || col.trailing_zeros() as usize >= COL_COUNT {
+                continue;
+            }
+            self.rows[col.trailing_zeros() as usize] = (word & row_mask) ^ idle_rows;
+        }
+        if word_count == 0 {
+            defmt::warn!("PIO matrix state machine pushed nothing since the last scan");
+        }
+
+        let mut result = Result::default();
+        for j in 0..COL_COUNT {
+            for i in 0..ROW_COUNT {
+                let pressed = self.rows[j] >> i & 1 == 1;
+                result.matrix[i][j] = Bit {
+                    edge: Edge::from((self.previous_result.matrix[i][j].pressed, pressed)),
+                    pressed,
+                }
+            }
+        }
+        result.scan_time_ticks = Mono::now().ticks();
+        self.previous_result = result;
+        result
+    }
+}
@@ -0,0 +1,280 @@
+use alloc::{boxed::Box, vec::Vec};
+use embedded_hal::{
+    digital::{InputPin, OutputPin, PinState},
+    spi::SpiBus,
+};
+use rp2040_hal::gpio;
+use rtic_monotonics::rp2040::prelude::*;
+
+use crate::{kb::Mono, key::Edge};
+
+use super::{
+    switch::{ActiveLevel, MatrixPin},
+    Bit, Result, Scanner,
+};
+
+type Output = Box<dyn OutputPin<Error = gpio::Error> + Sync + Send>;
+type Input = Box<dyn InputPin<Error = gpio::Error> + Sync + Send>;
+
+// Chained registers are limited to 32 lines so that a whole chain fits in one word.
+const MAX_LINE_COUNT: usize = 32;
+
+// Drives the column lines. Bit `j` of `levels` is the level of column `j`.
+pub trait ColumnDriver: Send {
+    fn write(&mut self, levels: u32);
+}
+
+// Reads the row lines. Bit `i` of the result is the level of row `i`.
+pub trait RowReader: Send {
+    fn read(&mut self) -> u32;
+}
+
+// 74HC595 chain with bit-banged SER/SRCLK/RCLK. Column 0 is QA of the first register in the
+// chain.
+pub struct BitBangHC595 {
+    data: Output,
+    clock: Output,
+    latch: Output,
+    width: usize,
+}
+
+#[allow(dead_code)]
+impl BitBangHC595 {
+    pub fn new(data: MatrixPin, clock: MatrixPin, latch: MatrixPin, width: usize) -> Self {
+        assert!(width <= MAX_LINE_COUNT, "too many shift register outputs");
+        BitBangHC595 {
+            data: Box::new(data.into_push_pull_output()),
+            clock: Box::new(clock.into_push_pull_output()),
+            latch: Box::new(latch.into_push_pull_output()),
+            width,
+        }
+    }
+}
+
+impl ColumnDriver for BitBangHC595 {
+    fn write(&mut self, levels: u32) {
+        // The first bit shifted in ends up on the last output of the chain
+        for k in (0..self.width).rev() {
+            self.data
+                .set_state(PinState::from(levels >> k & 1 == 1))
+                .unwrap();
+            self.clock.set_high().unwrap();
+            self.clock.set_low().unwrap();
+        }
+        self.latch.set_high().unwrap();
+        self.latch.set_low().unwrap();
+    }
+}
+
+// 74HC595 chain on an SPI bus (SER on MOSI, SRCLK on SCK), latched through RCLK.
+pub struct SpiHC595<S> {
+    spi: S,
+    latch: Output,
+    width: usize,
+}
+
+#[allow(dead_code)]
+impl<S: SpiBus<u8> + Send> SpiHC595<S> {
+    pub fn new(spi: S, latch: MatrixPin, width: usize) -> Self {
+        assert!(width <= MAX_LINE_COUNT, "too many shift register outputs");
+        SpiHC595 {
+            spi,
+            latch: Box::new(latch.into_push_pull_output()),
+            width,
+        }
+    }
+}
+
+impl<S: SpiBus<u8> + Send> ColumnDriver for SpiHC595<S> {
+    fn write(&mut self, levels: u32) {
+        let byte_count = self.width.div_ceil(8);
+        let bytes = levels.to_be_bytes();
+        self.spi.write(&bytes[bytes.len() - byte_count..]).unwrap();
+        self.spi.flush().unwrap();
+        self.latch.set_high().unwrap();
+        self.latch.set_low().unwrap();
+    }
+}
+
+// Row lines wired straight to GPIOs.
+pub struct GpioRows {
+    pins: Vec<Input>,
+}
+
+#[allow(dead_code)]
+impl GpioRows {
+    pub fn new<const ROW_COUNT: usize>(pins: [MatrixPin; ROW_COUNT], pull_up: bool) -> Self {
+        assert!(ROW_COUNT <= MAX_LINE_COUNT, "too many row pins");
+        GpioRows {
+            pins: pins
+                .into_iter()
+                .map(|p| {
+                    if pull_up {
+                        Box::new(p.into_pull_up_input()) as Input
+                    } else {
+                        Box::new(p.into_pull_down_input()) as Input
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl RowReader for GpioRows {
+    fn read(&mut self) -> u32 {
+        self.pins
+            .iter_mut()
+            .enumerate()
+            .fold(0, |acc, (i, p)| acc | (p.is_high().unwrap() as u32) << i)
+    }
+}
+
+// 74HC165 chain with bit-banged SH/LD, CLK and QH. Row 0 is input A of the first register in
+// the chain.
+pub struct BitBangHC165 {
+    load: Output,
+    clock: Output,
+    data: Input,
+    width: usize,
+}
+
+#[allow(dead_code)]
+impl BitBangHC165 {
+    pub fn new(load: MatrixPin, clock: MatrixPin, data: MatrixPin, width: usize) -> Self {
+        assert!(width <= MAX_LINE_COUNT, "too many shift register inputs");
+        BitBangHC165 {
+            load: Box::new(load.into_push_pull_output_in_state(PinState::High)),
+            clock: Box::new(clock.into_push_pull_output()),
+            data: Box::new(data.into_floating_input()),
+            width,
+        }
+    }
+}
+
+impl RowReader for BitBangHC165 {
+    fn read(&mut self) -> u32 {
+        // Parallel load while SH/LD is low, then shift out the last input of the chain first
+        self.load.set_low().unwrap();
+        self.load.set_high().unwrap();
+        let mut levels = 0;
+        for k in (0..self.width).rev() {
+            levels |= (self.data.is_high().unwrap() as u32) << k;
+            self.clock.set_high().unwrap();
+            self.clock.set_low().unwrap();
+        }
+        levels
+    }
+}
+
+// 74HC165 chain on an SPI bus (QH on MISO, CLK on SCK), loaded through SH/LD.
+pub struct SpiHC165<S> {
+    spi: S,
+    load: Output,
+    width: usize,
+}
+
+#[allow(dead_code)]
+impl<S: SpiBus<u8> + Send> SpiHC165<S> {
+    pub fn new(spi: S, load: MatrixPin, width: usize) -> Self {
+        assert!(width <= MAX_LINE_COUNT, "too many shift register inputs");
+        SpiHC165 {
+            spi,
+            load: Box::new(load.into_push_pull_output_in_state(PinState::High)),
+            width,
+        }
+    }
+}
+
+impl<S: SpiBus<u8> + Send> RowReader for SpiHC165<S> {
+    fn read(&mut self) -> u32 {
+        self.load.set_low().unwrap();
+        self.load.set_high().unwrap();
+        let byte_count = self.width.div_ceil(8);
+        let mut bytes = [0u8; 4];
+        self.spi.read(&mut bytes[4 - byte_count..]).unwrap();
+        // Whole bytes are clocked, so the chain is followed by whatever was on its serial input
+        u32::from_be_bytes(bytes) >> (byte_count * 8 - self.width)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShiftRegisterMatrixConfig {
+    pub active_level: ActiveLevel,
+    // Time given to the row lines to follow a newly selected column before they are read.
+    pub settle_delay: <Mono as Monotonic>::Duration,
+}
+
+impl Default for ShiftRegisterMatrixConfig {
+    fn default() -> Self {
+        ShiftRegisterMatrixConfig {
+            active_level: ActiveLevel::High,
+            settle_delay: 1.micros(),
+        }
+    }
+}
+
+// COL2ROW matrix whose columns are driven by a shift register and whose rows are read either
+// directly or through another shift register.
+pub struct ShiftRegisterMatrix<const ROW_COUNT: usize, const COL_COUNT: usize> {
+    columns: Box<dyn ColumnDriver>,
+    rows: Box<dyn RowReader>,
+    config: ShiftRegisterMatrixConfig,
+    previous_result: Result<{ ROW_COUNT }, { COL_COUNT }>,
+}
+
+#[allow(dead_code)]
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> ShiftRegisterMatrix<ROW_COUNT, COL_COUNT> {
+    pub fn new(
+        columns: Box<dyn ColumnDriver>,
+        rows: Box<dyn RowReader>,
+        config: ShiftRegisterMatrixConfig,
+    ) -> Self {
+        assert!(
+            ROW_COUNT <= MAX_LINE_COUNT && COL_COUNT <= MAX_LINE_COUNT,
+            "shift register matrix is limited to {} rows and columns",
+            MAX_LINE_COUNT
+        );
+        let mut matrix = ShiftRegisterMatrix {
+            columns,
+            rows,
+            config,
+            previous_result: Result::default(),
+        };
+        matrix.columns.write(matrix.idle_levels());
+        matrix
+    }
+
+    fn idle_levels(&self) -> u32 {
+        match self.config.active_level {
+            ActiveLevel::High => 0,
+            ActiveLevel::Low => u32::MAX,
+        }
+    }
+}
+
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> Scanner<ROW_COUNT, COL_COUNT>
+    for ShiftRegisterMatrix<ROW_COUNT, COL_COUNT>
+{
+    async fn scan(&mut self) -> Result<ROW_COUNT, COL_COUNT> {
+        let mut result = Result::default();
+        let idle_levels = self.idle_levels();
+        for j in 0..COL_COUNT {
+            self.columns.write(idle_levels ^ (1 << j));
+            if self.config.settle_delay.ticks() > 0 {
+                Mono::delay(self.config.settle_delay).await;
+            }
+            let row_levels = self.rows.read() ^ idle_levels;
+            for i in 0..ROW_COUNT {
+                let pressed = row_levels >> i & 1 == 1;
+                result.matrix[i][j] = Bit {
+                    edge: Edge::from((self.previous_result.matrix[i][j].pressed, pressed)),
+                    pressed,
+                }
+            }
+        }
+        self.columns.write(idle_levels);
+        result.scan_time_ticks = Mono::now().ticks();
+        self.previous_result = result;
+        result
+    }
+}
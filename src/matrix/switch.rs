@@ -0,0 +1,151 @@
+use alloc::{boxed::Box, vec::Vec};
+use defmt::Format;
+use embedded_hal::digital::{InputPin, OutputPin};
+use rp2040_hal::gpio;
+use rtic_monotonics::rp2040::prelude::*;
+
+use crate::{kb::Mono, key::Edge};
+
+use super::{Bit, Result, Scanner};
+
+pub type MatrixPin = gpio::Pin<gpio::DynPinId, gpio::FunctionNull, gpio::PullDown>;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum DiodeDirection {
+    Col2Row,
+    Row2Col,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum Pull {
+    Up,
+    Down,
+    None,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum ActiveLevel {
+    High,
+    Low,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SwitchMatrixConfig {
+    pub diode_direction: DiodeDirection,
+    // Pull applied on the sense lines.
+    pub pull: Pull,
+    pub active_level: ActiveLevel,
+    // Time given to the sense lines to return to idle after each strobe line is released.
+    pub settle_delay: <Mono as Monotonic>::Duration,
+}
+
+impl Default for SwitchMatrixConfig {
+    fn default() -> Self {
+        SwitchMatrixConfig {
+            diode_direction: DiodeDirection::Col2Row,
+            pull: Pull::Down,
+            active_level: ActiveLevel::High,
+            settle_delay: 1.micros(),
+        }
+    }
+}
+
+impl SwitchMatrixConfig {
+    // Current only flows from the anode to the cathode, so the anode side has to be driven high
+    // or the cathode side low, with the other side sensing.
+    fn is_col_strobed(&self) -> bool {
+        matches!(
+            (self.diode_direction, self.active_level),
+            (DiodeDirection::Col2Row, ActiveLevel::High)
+                | (DiodeDirection::Row2Col, ActiveLevel::Low)
+        )
+    }
+}
+
+pub struct SwitchMatrix<const ROW_COUNT: usize, const COL_COUNT: usize> {
+    strobes: Vec<Box<dyn OutputPin<Error = gpio::Error> + Sync + Send>>,
+    senses: Vec<Box<dyn InputPin<Error = gpio::Error> + Sync + Send>>,
+    config: SwitchMatrixConfig,
+    previous_result: Result<{ ROW_COUNT }, { COL_COUNT }>,
+}
+
+#[allow(dead_code)]
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> SwitchMatrix<ROW_COUNT, COL_COUNT> {
+    pub fn new(
+        rows: [MatrixPin; ROW_COUNT],
+        cols: [MatrixPin; COL_COUNT],
+        config: SwitchMatrixConfig,
+    ) -> Self {
+        if matches!(
+            (config.pull, config.active_level),
+            (Pull::Up, ActiveLevel::High) | (Pull::Down, ActiveLevel::Low)
+        ) {
+            defmt::warn!(
+                "switch matrix sense lines are pulled {} while active {}",
+                config.pull,
+                config.active_level
+            );
+        }
+
+        let (strobes, senses): (Vec<MatrixPin>, Vec<MatrixPin>) = if config.is_col_strobed() {
+            (cols.into(), rows.into())
+        } else {
+            (rows.into(), cols.into())
+        };
+        let idle_state = match config.active_level {
+            ActiveLevel::High => gpio::PinState::Low,
+            ActiveLevel::Low => gpio::PinState::High,
+        };
+        SwitchMatrix {
+            strobes: strobes
+                .into_iter()
+                .map(|p| {
+                    Box::new(p.into_push_pull_output_in_state(idle_state))
+                        as Box<dyn OutputPin<Error = gpio::Error> + Sync + Send>
+                })
+                .collect(),
+            senses: senses
+                .into_iter()
+                .map(|p| match config.pull {
+                    Pull::Up => Box::new(p.into_pull_up_input())
+                        as Box<dyn InputPin<Error = gpio::Error> + Sync + Send>,
+                    Pull::Down => Box::new(p.into_pull_down_input()),
+                    Pull::None => Box::new(p.into_floating_input()),
+                })
+                .collect(),
+            config,
+            previous_result: Result::default(),
+        }
+    }
+}
+
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> Scanner<ROW_COUNT, COL_COUNT>
+    for SwitchMatrix<ROW_COUNT, COL_COUNT>
+{
+    async fn scan(&mut self) -> Result<ROW_COUNT, COL_COUNT> {
+        let mut result = Result::default();
+        let is_active_high = self.config.active_level == ActiveLevel::High;
+        let is_col_strobed = self.config.is_col_strobed();
+        for (s, strobe) in self.strobes.iter_mut().enumerate() {
+            strobe.set_state(is_active_high.into()).unwrap();
+            for (t, sense) in self.senses.iter_mut().enumerate() {
+                let (i, j) = if is_col_strobed { (t, s) } else { (s, t) };
+                let pressed = sense.is_high().unwrap() == is_active_high;
+                result.matrix[i][j] = Bit {
+                    edge: Edge::from((self.previous_result.matrix[i][j].pressed, pressed)),
+                    pressed,
+                }
+            }
+            strobe.set_state((!is_active_high).into()).unwrap();
+            if self.config.settle_delay.ticks() > 0 {
+                Mono::delay(self.config.settle_delay).await;
+            }
+        }
+        result.scan_time_ticks = Mono::now().ticks();
+        self.previous_result = result;
+        result
+    }
+}
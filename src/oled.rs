@@ -1,3 +1,6 @@
+use alloc::string::String;
+use core::fmt::Write;
+use rtic_sync::channel::Receiver;
 use ssd1306::{prelude::*, Ssd1306};
 
 pub struct OLEDDisplay<DI>
@@ -25,6 +28,13 @@ where
     pub fn clear(&mut self) {
         self.display.clear().unwrap();
     }
+
+    pub async fn render(&mut self, mut text_receiver: Receiver<'static, String, 1>) {
+        while let Ok(text) = text_receiver.recv().await {
+            self.clear();
+            self.write_str(&text).ok();
+        }
+    }
 }
 
 impl<DI> core::fmt::Write for OLEDDisplay<DI>
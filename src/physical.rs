@@ -0,0 +1,18 @@
+use defmt::Format;
+use serde::Serialize;
+
+// A key as seen by the user: the matrix position it is wired to, and the position of its
+// center on the board, in hundredths of a key unit from the top left corner.
+#[derive(Clone, Copy, Debug, Format, PartialEq, Serialize)]
+pub struct PhysicalKey {
+    pub row: u8,
+    pub col: u8,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl PhysicalKey {
+    pub const fn new(row: u8, col: u8, x: u16, y: u16) -> Self {
+        PhysicalKey { row, col, x, y }
+    }
+}
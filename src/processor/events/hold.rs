@@ -0,0 +1,107 @@
+use heapless::Vec;
+use rtic_monotonics::Monotonic;
+
+use crate::{
+    kb::Mono,
+    key::{Action, Edge, LayerIndex},
+    processor::{Error, EventQueue, EventsProcessor, Result, EVENTS_CAPACITY},
+};
+
+struct Hold<L: LayerIndex> {
+    i: usize,
+    j: usize,
+    action: Action<L>,
+    pressed_ticks: u64,
+    is_expired: bool,
+    is_seen: bool,
+}
+
+// Releases non-modifier keys that have been held for longer than the maximum hold time, so that
+// a key stuck down, e.g. by something resting on the keyboard, does not keep repeating on the
+// host. Expired keys stay released until they are pressed again.
+pub struct HoldLimitProcessor<L: LayerIndex> {
+    max_hold_ticks: u64,
+    holds: Vec<Hold<L>, EVENTS_CAPACITY>,
+}
+
+#[allow(dead_code)]
+impl<L: LayerIndex> HoldLimitProcessor<L> {
+    pub fn new(max_hold: <Mono as Monotonic>::Duration) -> Self {
+        HoldLimitProcessor {
+            max_hold_ticks: max_hold.ticks(),
+            holds: Vec::new(),
+        }
+    }
+
+    fn is_limited(action: Action<L>) -> bool {
+        match action {
+            Action::Key(key) => !key.is_modifier(),
+            Action::ModifiedKey(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl<L: LayerIndex> EventsProcessor<L> for HoldLimitProcessor<L> {
+    fn process(&mut self, events: &mut EventQueue<L>) -> Result {
+        let now_ticks = events.now_ticks();
+        let max_hold_ticks = self.max_hold_ticks;
+        let holds = &mut self.holds;
+        let mut is_overflowed = false;
+        holds.iter_mut().for_each(|h| h.is_seen = false);
+        events.retain_mut(|e| {
+            if !Self::is_limited(e.action) {
+                return true;
+            }
+            let index = holds
+                .iter()
+                .position(|h| h.i == e.i && h.j == e.j && h.action == e.action);
+            match (e.edge, index) {
+                (Edge::Rising, _) => {
+                    // There is room for every key an event queue can carry, unless holds leaked
+                    is_overflowed |= holds
+                        .push(Hold {
+                            i: e.i,
+                            j: e.j,
+                            action: e.action,
+                            pressed_ticks: e.time_ticks,
+                            is_expired: false,
+                            is_seen: true,
+                        })
+                        .is_err();
+                }
+                // The release of an expired key was already reported
+                (Edge::Falling, Some(index)) => return !holds.remove(index).is_expired,
+                (Edge::None, Some(index)) => {
+                    let hold = &mut holds[index];
+                    hold.is_seen = true;
+                    if !hold.is_expired
+                        && now_ticks.saturating_sub(hold.pressed_ticks) >= max_hold_ticks
+                    {
+                        defmt::warn!("key at ({}, {}) held for too long, releasing", e.i, e.j);
+                        hold.is_expired = true;
+                        e.edge = Edge::Falling;
+                    }
+                }
+                _ => {}
+            }
+            true
+        });
+        // Keys keep their action and are reported on every pass until released, so unseen ones had
+        // their release taken out by an earlier processor
+        holds.retain(|h| h.is_seen);
+
+        if let Some(ticks) = holds
+            .iter()
+            .filter(|h| !h.is_expired)
+            .map(|h| h.pressed_ticks + max_hold_ticks)
+            .min()
+        {
+            events.schedule(ticks);
+        }
+        if is_overflowed {
+            return Err(Error::Overflow);
+        }
+        Ok(())
+    }
+}
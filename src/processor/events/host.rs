@@ -0,0 +1,226 @@
+use core::mem;
+use heapless::Vec;
+
+use crate::{
+    command::{Handler, Request, Response},
+    key::{Action, Control, Edge, HostMode, Key, LayerIndex},
+    processor::{Error, EventQueue, EventsProcessor, Result, EVENTS_CAPACITY},
+    storage::{Record, RecordKind},
+};
+
+impl HostMode {
+    const COUNT: usize = mem::variant_count::<HostMode>();
+    const ALL: [HostMode; Self::COUNT] = [
+        HostMode::Standard,
+        HostMode::MacOS,
+        HostMode::AltGUISwap,
+        HostMode::GUILock,
+    ];
+
+    fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::COUNT]
+    }
+
+    // None for keys the mode disables.
+    fn map_key(self, key: Key) -> Option<Key> {
+        Some(match (self, key) {
+            (HostMode::MacOS, Key::LeftControl) => Key::LeftGUI,
+            (HostMode::MacOS, Key::LeftGUI) => Key::LeftControl,
+            (HostMode::MacOS, Key::RightControl) => Key::RightGUI,
+            (HostMode::MacOS, Key::RightGUI) => Key::RightControl,
+            (HostMode::AltGUISwap, Key::LeftAlt) => Key::LeftGUI,
+            (HostMode::AltGUISwap, Key::LeftGUI) => Key::LeftAlt,
+            (HostMode::AltGUISwap, Key::RightAlt) => Key::RightGUI,
+            (HostMode::AltGUISwap, Key::RightGUI) => Key::RightAlt,
+            (HostMode::GUILock, Key::LeftGUI | Key::RightGUI) => return None,
+            _ => key,
+        })
+    }
+
+    fn map_action<L: LayerIndex>(self, action: Action<L>) -> Action<L> {
+        match action {
+            Action::Key(key) => self.map_key(key).map_or(Action::None, Action::Key),
+            Action::ModifiedKey(mk) => {
+                let mut is_locked = false;
+                let modifiers = mk
+                    .get_modifiers()
+                    .iter()
+                    .filter_map(|m| m.to_key())
+                    .filter_map(|key| {
+                        let mapped = self.map_key(key);
+                        is_locked |= mapped.is_none();
+                        mapped
+                    })
+                    .fold(0, |modifiers, key| modifiers | key.to_modifier() as u8);
+                // Without its locked modifier, a shortcut would type something else
+                if is_locked {
+                    return Action::None;
+                }
+                // Without modifiers left, it would mask the held ones
+                if modifiers == 0 {
+                    Action::Key(mk.get_key())
+                } else {
+                    Action::ModifiedKey(mk.with_modifiers(modifiers))
+                }
+            }
+            _ => action,
+        }
+    }
+}
+
+impl Record for HostMode {
+    const KIND: RecordKind = RecordKind::HostMode;
+    const VERSION: u8 = 1;
+}
+
+struct Press<L: LayerIndex> {
+    i: usize,
+    j: usize,
+    action: Action<L>,
+    mode: HostMode,
+    is_seen: bool,
+}
+
+// Adapts modifiers to the operating system of the host, so that one layout works on all of them.
+// Keys keep the mode they were pressed in until released, so that switching modes while holding
+// a modifier does not leave it stuck.
+pub struct HostModeProcessor<L: LayerIndex> {
+    mode: HostMode,
+    presses: Vec<Press<L>, EVENTS_CAPACITY>,
+}
+
+#[allow(dead_code)]
+impl<L: LayerIndex> HostModeProcessor<L> {
+    pub fn new() -> Self {
+        HostModeProcessor {
+            mode: HostMode::default(),
+            presses: Vec::new(),
+        }
+    }
+
+    pub fn get_mode(&self) -> HostMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: HostMode) {
+        self.mode = mode;
+    }
+
+    fn is_mapped(action: Action<L>) -> bool {
+        HostMode::ALL
+            .iter()
+            .any(|mode| mode.map_action(action) != action)
+    }
+}
+
+impl<L: LayerIndex> EventsProcessor<L> for HostModeProcessor<L> {
+    fn process(&mut self, events: &mut EventQueue<L>) -> Result {
+        events.iter().for_each(|e| {
+            if e.edge == Edge::Rising {
+                if let Action::Control(c) = e.action {
+                    match c {
+                        Control::HostModeNext => self.mode = self.mode.next(),
+                        Control::HostModeSelect(mode) => self.mode = mode,
+                        _ => {}
+                    }
+                }
+            }
+        });
+
+        let mode = self.mode;
+        let presses = &mut self.presses;
+        let mut is_overflowed = false;
+        presses.iter_mut().for_each(|p| p.is_seen = false);
+        events.iter_mut().for_each(|e| {
+            if !Self::is_mapped(e.action) {
+                return;
+            }
+            let index = presses
+                .iter()
+                .position(|p| p.i == e.i && p.j == e.j && p.action == e.action);
+            let press_mode = match (e.edge, index) {
+                (Edge::Rising, _) => {
+                    // There is room for every key an event queue can carry, unless presses leaked
+                    is_overflowed |= presses
+                        .push(Press {
+                            i: e.i,
+                            j: e.j,
+                            action: e.action,
+                            mode,
+                            is_seen: true,
+                        })
+                        .is_err();
+                    mode
+                }
+                (Edge::Falling, Some(index)) => presses.remove(index).mode,
+                (Edge::None, Some(index)) => {
+                    presses[index].is_seen = true;
+                    presses[index].mode
+                }
+                _ => mode,
+            };
+            e.action = press_mode.map_action(e.action);
+        });
+        // Keys keep their action and are reported on every pass until released, so unseen ones had
+        // their release taken out by an earlier processor
+        presses.retain(|p| p.is_seen);
+
+        if is_overflowed {
+            return Err(Error::Overflow);
+        }
+        Ok(())
+    }
+}
+
+impl<L: LayerIndex> Handler<L> for HostModeProcessor<L> {
+    fn handle(&mut self, request: &Request<L>) -> Option<Response<L>> {
+        match *request {
+            Request::GetHostMode => Some(Response::HostMode(self.mode)),
+            Request::SetHostMode(mode) => {
+                self.mode = mode;
+                Some(Response::Ack)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{key::ModifiedKey, processor::tests::Layer};
+
+    fn modified(key: Key, modifier: Key) -> Action<Layer> {
+        Action::ModifiedKey(ModifiedKey(
+            key as u16 | (modifier.to_modifier() as u16) << 8,
+        ))
+    }
+
+    #[test]
+    fn modifiers_follow_the_host_mode() {
+        let shortcut = modified(Key::C, Key::LeftControl);
+        assert_eq!(HostMode::Standard.map_action(shortcut), shortcut);
+        assert_eq!(
+            HostMode::MacOS.map_action(shortcut),
+            modified(Key::C, Key::LeftGUI)
+        );
+        assert_eq!(
+            HostMode::AltGUISwap.map_action(Action::<Layer>::Key(Key::LeftGUI)),
+            Action::Key(Key::LeftAlt)
+        );
+    }
+
+    #[test]
+    fn gui_lock_drops_gui_shortcuts() {
+        assert_eq!(
+            HostMode::GUILock.map_action(Action::<Layer>::Key(Key::LeftGUI)),
+            Action::None
+        );
+        assert_eq!(
+            HostMode::GUILock.map_action(modified(Key::L, Key::LeftGUI)),
+            Action::None
+        );
+        let shortcut = modified(Key::C, Key::LeftControl);
+        assert_eq!(HostMode::GUILock.map_action(shortcut), shortcut);
+    }
+}
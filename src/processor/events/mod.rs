@@ -1,4 +1,8 @@
+pub mod hold;
+pub mod host;
 pub mod none;
+pub mod profile;
 pub mod replace;
 pub mod rgb;
 pub mod system;
+pub mod tap_hold;
@@ -1,8 +1,6 @@
-use alloc::vec::Vec;
-
 use crate::{
     key::LayerIndex,
-    processor::{Event, EventsProcessor, Result},
+    processor::{EventQueue, EventsProcessor, Result},
 };
 
 pub struct NoneProcessor {}
@@ -15,7 +13,7 @@ impl NoneProcessor {
 }
 
 impl<L: LayerIndex> EventsProcessor<L> for NoneProcessor {
-    fn process(&mut self, _: &mut Vec<Event<L>>) -> Result {
+    fn process(&mut self, _: &mut EventQueue<L>) -> Result {
         Ok(())
     }
 }
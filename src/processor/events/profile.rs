@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{self, Handler, Request, Response},
+    key::{Action, Control, Edge, LayerIndex},
+    processor::{EventQueue, EventsProcessor, Result},
+    storage::{Record, RecordKind},
+};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ActiveProfile(pub u8);
+
+impl Record for ActiveProfile {
+    const KIND: RecordKind = RecordKind::ActiveProfile;
+    const VERSION: u8 = 1;
+}
+
+pub struct ProfileProcessor {
+    profile_count: u8,
+    active_profile: u8,
+}
+
+#[allow(dead_code)]
+impl ProfileProcessor {
+    pub fn new(profile_count: u8) -> Self {
+        assert!(profile_count > 0, "at least one profile is required");
+        ProfileProcessor {
+            profile_count,
+            active_profile: 0,
+        }
+    }
+
+    pub fn get_active(&self) -> u8 {
+        self.active_profile
+    }
+
+    pub fn set_active(&mut self, profile: u8) -> bool {
+        if profile >= self.profile_count {
+            return false;
+        }
+        self.active_profile = profile;
+        true
+    }
+}
+
+impl<L: LayerIndex> EventsProcessor<L> for ProfileProcessor {
+    fn process(&mut self, events: &mut EventQueue<L>) -> Result {
+        events.iter_mut().for_each(|e| {
+            if e.edge == Edge::Rising {
+                if let Action::Control(c) = e.action {
+                    match c {
+                        Control::ProfileNext => {
+                            self.active_profile = (self.active_profile + 1) % self.profile_count
+                        }
+                        Control::ProfileSelect(p) => {
+                            if !self.set_active(p) {
+                                defmt::warn!("profile {} does not exist", p);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl<L: LayerIndex> Handler<L> for ProfileProcessor {
+    fn handle(&mut self, request: &Request<L>) -> Option<Response<L>> {
+        match *request {
+            Request::GetProfile => Some(Response::Profile {
+                active: self.active_profile,
+                count: self.profile_count,
+            }),
+            Request::SetProfile(profile) => Some(if self.set_active(profile) {
+                Response::Ack
+            } else {
+                Response::Error(command::Error::OutOfBounds)
+            }),
+            _ => None,
+        }
+    }
+}
@@ -1,8 +1,6 @@
-use alloc::vec::Vec;
-
 use crate::{
     key::{Action, Key, LayerIndex},
-    processor::{Event, EventsProcessor, Result},
+    processor::{EventQueue, EventsProcessor, Result},
 };
 
 pub struct KeyReplaceProcessor {
@@ -18,13 +16,11 @@ impl KeyReplaceProcessor {
 }
 
 impl<L: LayerIndex> EventsProcessor<L> for KeyReplaceProcessor {
-    fn process(&mut self, events: &mut Vec<Event<L>>) -> Result {
-        events.iter_mut().for_each(|e| {
-            if let Action::Key(k) = &mut e.action {
-                if *k == self.from {
-                    *k = self.to;
-                }
-            }
+    fn process(&mut self, events: &mut EventQueue<L>) -> Result {
+        events.iter_mut().for_each(|e| match &mut e.action {
+            Action::Key(k) if *k == self.from => *k = self.to,
+            Action::ModifiedKey(mk) if mk.get_key() == self.from => *mk = mk.with_key(self.to),
+            _ => {}
         });
         Ok(())
     }
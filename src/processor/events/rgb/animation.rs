@@ -1,5 +1,3 @@
-use alloc::boxed::Box;
-
 use super::{AnimationIterator, AnimationState, RGB8};
 
 pub struct NoneAnimation {}
@@ -12,7 +10,7 @@ impl NoneAnimation {
 
 impl<const LED_COUNT: usize> AnimationIterator<LED_COUNT> for NoneAnimation {
     fn next(&mut self) -> Option<Self::Item> {
-        Some(Box::new([(0, 0, 0).into(); LED_COUNT].into_iter()))
+        Some([(0, 0, 0).into(); LED_COUNT])
     }
 }
 
@@ -36,7 +34,7 @@ impl<const LED_COUNT: usize> AnimationIterator<LED_COUNT> for ScanAnimation<LED_
                 (0, 0, 0).into()
             };
         }
-        Some(Box::new(self.animation_state.frame.into_iter()))
+        Some(self.animation_state.frame)
     }
 }
 
@@ -70,7 +68,7 @@ impl<const LED_COUNT: usize> AnimationIterator<LED_COUNT> for BreatheAnimation<L
                     .wrapping_add((i * 128 / LED_COUNT) as u8),
             );
         }
-        Some(Box::new(self.animation_state.frame.into_iter()))
+        Some(self.animation_state.frame)
     }
 }
 
@@ -106,6 +104,6 @@ impl<const LED_COUNT: usize> AnimationIterator<LED_COUNT> for WheelAnimation<LED
                     .wrapping_add((i * 255 / LED_COUNT) as u8),
             );
         }
-        Some(Box::new(self.animation_state.frame.into_iter()))
+        Some(self.animation_state.frame)
     }
 }
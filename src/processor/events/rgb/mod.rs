@@ -1,18 +1,22 @@
 pub mod animation;
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::boxed::Box;
 use animation::{BreatheAnimation, NoneAnimation, ScanAnimation, WheelAnimation};
 use core::ops::Mul;
+use defmt::Format;
 use hal::timer::Instant;
 use rtic_monotonics::{rp2040::prelude::*, Monotonic};
 use rtic_sync::channel::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
 use smart_leds::{brightness, SmartLedsWrite, RGB8 as SLRGB8};
 
 use crate::{
+    command::{self, Handler, Request, Response},
     kb::Mono,
     key::Edge,
     key::{Action, Control, LayerIndex},
-    processor::{Event, EventsProcessor, Result},
+    processor::{EventQueue, EventsProcessor, Result},
+    storage::{Record, RecordKind},
 };
 
 // More than 64 pulls too much power, it fries the board
@@ -24,9 +28,8 @@ const FRAME_TIME_MAX_MICROS: u64 = 1_000_000;
 
 type RGB8 = SLRGB8;
 
-type Frame<const LED_COUNT: usize> = [RGB8; LED_COUNT];
-
-pub trait FrameIterator = Iterator<Item = RGB8>;
+// Frames are copied whole through the renderer channel, so drawing one never allocates.
+pub type Frame<const LED_COUNT: usize> = [RGB8; LED_COUNT];
 
 #[derive(Clone, Copy)]
 pub struct RGBMatrix<const LED_COUNT: usize, W: SmartLedsWrite>
@@ -45,13 +48,10 @@ where
         RGBMatrix { writer }
     }
 
-    pub async fn render(
-        &mut self,
-        mut frame_receiver: Receiver<'static, Box<dyn FrameIterator>, 1>,
-    ) {
+    pub async fn render(&mut self, mut frame_receiver: Receiver<'static, Frame<LED_COUNT>, 1>) {
         while let Ok(frame) = frame_receiver.recv().await {
             self.writer
-                .write(brightness(frame, LED_MAX_BRIGHTNESS))
+                .write(brightness(frame.into_iter(), LED_MAX_BRIGHTNESS))
                 .ok();
         }
     }
@@ -80,10 +80,39 @@ impl<const LED_COUNT: usize> Default for AnimationState<LED_COUNT> {
     }
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, Format, PartialEq, Serialize)]
+pub struct RGBSettings {
+    pub animation_idx: u8,
+    pub frame_time_micros: u64,
+    pub brightness: u8,
+}
+
+impl RGBSettings {
+    // Speed is mapped onto the frame time on a log2 scale, 255 being the fastest.
+    const SPEED_STEPS: u32 = (FRAME_TIME_MAX_MICROS / FRAME_TIME_MIN_MICROS).ilog2();
+
+    pub fn get_speed(&self) -> u8 {
+        let step = (FRAME_TIME_MAX_MICROS / self.frame_time_micros.max(FRAME_TIME_MIN_MICROS))
+            .ilog2()
+            .min(Self::SPEED_STEPS);
+        (step * u8::MAX as u32 / Self::SPEED_STEPS) as u8
+    }
+
+    pub fn set_speed(&mut self, speed: u8) {
+        let step = speed as u32 * Self::SPEED_STEPS / u8::MAX as u32;
+        self.frame_time_micros = (FRAME_TIME_MAX_MICROS >> step).max(FRAME_TIME_MIN_MICROS);
+    }
+}
+
+impl Record for RGBSettings {
+    const KIND: RecordKind = RecordKind::RGBSettings;
+    const VERSION: u8 = 1;
+}
+
 pub struct RGBProcessor<const LED_COUNT: usize> {
-    animations: [Box<dyn AnimationIterator<{ LED_COUNT }, Item = Box<dyn FrameIterator>>>; 4],
+    animations: [Box<dyn AnimationIterator<{ LED_COUNT }>>; 4],
     animation_idx: usize,
-    frame_sender: Sender<'static, Box<dyn FrameIterator>, 1>,
+    frame_sender: Sender<'static, Frame<LED_COUNT>, 1>,
     last_render: Instant,
     frame_time_micros: u64,
     brightness: u8,
@@ -91,7 +120,7 @@ pub struct RGBProcessor<const LED_COUNT: usize> {
 
 #[allow(dead_code)]
 impl<const LED_COUNT: usize> RGBProcessor<{ LED_COUNT }> {
-    pub fn new(frame_sender: Sender<'static, Box<dyn FrameIterator>, 1>) -> Self {
+    pub fn new(frame_sender: Sender<'static, Frame<LED_COUNT>, 1>) -> Self {
         RGBProcessor {
             animations: [
                 Box::new(BreatheAnimation::new(Default::default())),
@@ -106,10 +135,26 @@ impl<const LED_COUNT: usize> RGBProcessor<{ LED_COUNT }> {
             brightness: 255,
         }
     }
+
+    pub fn get_settings(&self) -> RGBSettings {
+        RGBSettings {
+            animation_idx: self.animation_idx as u8,
+            frame_time_micros: self.frame_time_micros,
+            brightness: self.brightness,
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: RGBSettings) {
+        self.animation_idx = (settings.animation_idx as usize).min(self.animations.len() - 1);
+        self.frame_time_micros = settings
+            .frame_time_micros
+            .clamp(FRAME_TIME_MIN_MICROS, FRAME_TIME_MAX_MICROS);
+        self.brightness = settings.brightness;
+    }
 }
 
 impl<const LED_COUNT: usize, L: LayerIndex> EventsProcessor<L> for RGBProcessor<{ LED_COUNT }> {
-    fn process(&mut self, events: &mut Vec<Event<L>>) -> Result {
+    fn process(&mut self, events: &mut EventQueue<L>) -> Result {
         events.iter_mut().for_each(|e| {
             if e.edge == Edge::Rising {
                 if let Action::Control(k) = e.action {
@@ -156,12 +201,15 @@ impl<const LED_COUNT: usize, L: LayerIndex> EventsProcessor<L> for RGBProcessor<
 
         match Mono::now().checked_duration_since(self.last_render) {
             Some(d) if d > self.frame_time_micros.micros::<1, 1_000_000>() => {
-                self.frame_sender
-                    .try_send(Box::new(brightness(
-                        self.animations[self.animation_idx].next().unwrap(),
-                        self.brightness,
-                    )))
-                    .ok();
+                let frame = self.animations[self.animation_idx].next().unwrap();
+                let mut dimmed_frame = [RGB8::default(); LED_COUNT];
+                for (led, color) in dimmed_frame
+                    .iter_mut()
+                    .zip(brightness(frame.into_iter(), self.brightness))
+                {
+                    *led = color;
+                }
+                self.frame_sender.try_send(dimmed_frame).ok();
                 self.last_render = Mono::now();
             }
             _ => {}
@@ -171,8 +219,24 @@ impl<const LED_COUNT: usize, L: LayerIndex> EventsProcessor<L> for RGBProcessor<
     }
 }
 
+impl<const LED_COUNT: usize, L: LayerIndex> Handler<L> for RGBProcessor<{ LED_COUNT }> {
+    fn handle(&mut self, request: &Request<L>) -> Option<Response<L>> {
+        match *request {
+            Request::GetRGBSettings => Some(Response::RGBSettings(self.get_settings())),
+            Request::SetRGBSettings(settings) => {
+                if settings.animation_idx as usize >= self.animations.len() {
+                    return Some(Response::Error(command::Error::OutOfBounds));
+                }
+                self.set_settings(settings);
+                Some(Response::Ack)
+            }
+            _ => None,
+        }
+    }
+}
+
 trait AnimationIterator<const LED_COUNT: usize> {
-    type Item = Box<dyn FrameIterator>;
+    type Item = Frame<LED_COUNT>;
 
     fn next(&mut self) -> Option<Self::Item>;
 }
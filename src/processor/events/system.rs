@@ -1,9 +1,9 @@
-use alloc::vec::Vec;
 use hal::rom_data;
 
 use crate::{
+    command::{Handler, Request, Response},
     key::{Action, Control, Edge, LayerIndex},
-    processor::{Event, EventsProcessor, Result},
+    processor::{EventQueue, EventsProcessor, Result},
 };
 
 pub struct SystemProcessor {
@@ -18,7 +18,7 @@ impl SystemProcessor {
 }
 
 impl<L: LayerIndex> EventsProcessor<L> for SystemProcessor {
-    fn process(&mut self, events: &mut Vec<Event<L>>) -> Result {
+    fn process(&mut self, events: &mut EventQueue<L>) -> Result {
         events.iter_mut().for_each(|e| {
             if e.edge == Edge::Rising {
                 if let Action::Control(c) = e.action {
@@ -31,3 +31,15 @@ impl<L: LayerIndex> EventsProcessor<L> for SystemProcessor {
         Ok(())
     }
 }
+
+impl<L: LayerIndex> Handler<L> for SystemProcessor {
+    fn handle(&mut self, request: &Request<L>) -> Option<Response<L>> {
+        match *request {
+            Request::BootloaderJump => {
+                rom_data::reset_to_usb_boot(1 << self.u2f_activity_pin, 0);
+                Some(Response::Ack)
+            }
+            _ => None,
+        }
+    }
+}
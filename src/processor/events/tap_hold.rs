@@ -0,0 +1,226 @@
+use heapless::Vec;
+use rtic_monotonics::Monotonic;
+
+use crate::{
+    kb::Mono,
+    key::{Action, Edge, Key, LayerIndex},
+    processor::{Error, EventQueue, EventsProcessor, HeldEvents, Result, EVENTS_CAPACITY},
+};
+
+// Sends one key when tapped and another when held, e.g. Escape on tap and Control on hold. Presses
+// of the tap key are held back until the key is released, which sends the tap key, or until the
+// term runs out or another key is pressed, which send the hold key instead.
+pub struct TapHoldProcessor<L: LayerIndex> {
+    tap: Key,
+    hold: Key,
+    term_ticks: u64,
+    held: HeldEvents<L>,
+    // Positions whose press is held back, and positions that were decided for the hold key
+    pending: Vec<(usize, usize), EVENTS_CAPACITY>,
+    holding: Vec<(usize, usize), EVENTS_CAPACITY>,
+}
+
+#[allow(dead_code)]
+impl<L: LayerIndex> TapHoldProcessor<L> {
+    pub fn new(tap: Key, hold: Key, term: <Mono as Monotonic>::Duration) -> Self {
+        TapHoldProcessor {
+            tap,
+            hold,
+            term_ticks: term.ticks(),
+            held: HeldEvents::new(),
+            pending: Vec::new(),
+            holding: Vec::new(),
+        }
+    }
+}
+
+impl<L: LayerIndex> EventsProcessor<L> for TapHoldProcessor<L> {
+    fn process(&mut self, events: &mut EventQueue<L>) -> Result {
+        let (tap, hold, term_ticks) = (
+            Action::Key(self.tap),
+            Action::Key(self.hold),
+            self.term_ticks,
+        );
+        let (held, pending, holding) = (&mut self.held, &mut self.pending, &mut self.holding);
+        let mut tapped: Vec<(usize, usize), EVENTS_CAPACITY> = Vec::new();
+        let mut is_interrupted = false;
+        let mut is_overflowed = false;
+        events.retain_mut(|e| {
+            let position = (e.i, e.j);
+            if e.action != tap {
+                is_interrupted |= e.edge == Edge::Rising && !pending.is_empty();
+                return true;
+            }
+            if let Some(index) = holding.iter().position(|&p| p == position) {
+                e.action = hold;
+                if e.edge == Edge::Falling {
+                    holding.remove(index);
+                }
+                return true;
+            }
+            let is_pending = pending.contains(&position);
+            match e.edge {
+                Edge::Rising if !is_pending => match held.hold(*e, e.time_ticks + term_ticks) {
+                    Ok(()) => {
+                        pending.push(position).ok();
+                        false
+                    }
+                    Err(_) => {
+                        is_overflowed = true;
+                        true
+                    }
+                },
+                // Still undecided, the key is not reported pressed yet
+                Edge::None if is_pending => false,
+                Edge::Falling if is_pending => {
+                    tapped.push(position).ok();
+                    true
+                }
+                _ => true,
+            }
+        });
+
+        // Released presses go in front of the queue, so taps come out pressed then released
+        held.release_where(events, |e, _| tapped.contains(&(e.i, e.j)));
+        if is_interrupted {
+            held.release_all(events);
+        } else {
+            held.release_due(events);
+        }
+        for e in events.iter_mut() {
+            let position = (e.i, e.j);
+            if e.edge != Edge::Rising || e.action != tap {
+                continue;
+            }
+            let Some(index) = pending.iter().position(|&p| p == position) else {
+                continue;
+            };
+            pending.remove(index);
+            if !tapped.contains(&position) {
+                e.action = hold;
+                is_overflowed |= holding.push(position).is_err();
+            }
+        }
+
+        if is_overflowed {
+            return Err(Error::Overflow);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{tests::Layer, Event, Events};
+
+    const TERM_TICKS: u64 = 100;
+
+    fn processor() -> TapHoldProcessor<Layer> {
+        TapHoldProcessor::new(
+            Key::Escape,
+            Key::LeftControl,
+            <Mono as Monotonic>::Duration::from_ticks(TERM_TICKS),
+        )
+    }
+
+    fn event(j: usize, key: Key, edge: Edge, time_ticks: u64) -> Event<Layer> {
+        Event {
+            time_ticks,
+            i: 0,
+            j,
+            edge,
+            action: Action::Key(key),
+        }
+    }
+
+    fn pass(
+        processor: &mut TapHoldProcessor<Layer>,
+        now_ticks: u64,
+        events: &[Event<Layer>],
+    ) -> EventQueue<Layer> {
+        let mut events = EventQueue::new(now_ticks, Events::from_slice(events).ok().unwrap());
+        processor.process(&mut events).unwrap();
+        events
+    }
+
+    fn keys(events: &EventQueue<Layer>) -> heapless::Vec<(Key, Edge), EVENTS_CAPACITY> {
+        events
+            .iter()
+            .filter_map(|e| match e.action {
+                Action::Key(key) => Some((key, e.edge)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn release_within_term_sends_tap_key() {
+        let mut processor = processor();
+        let events = pass(&mut processor, 0, &[event(0, Key::Escape, Edge::Rising, 0)]);
+        assert!(events.is_empty());
+        assert_eq!(events.deadline_ticks(), Some(TERM_TICKS));
+
+        let events = pass(&mut processor, 10, &[event(0, Key::Escape, Edge::None, 10)]);
+        assert!(events.is_empty());
+
+        let events = pass(
+            &mut processor,
+            20,
+            &[event(0, Key::Escape, Edge::Falling, 20)],
+        );
+        assert_eq!(
+            keys(&events),
+            [(Key::Escape, Edge::Rising), (Key::Escape, Edge::Falling)]
+        );
+        assert_eq!(events.deadline_ticks(), None);
+    }
+
+    #[test]
+    fn deadline_pass_sends_hold_key() {
+        let mut processor = processor();
+        pass(&mut processor, 0, &[event(0, Key::Escape, Edge::Rising, 0)]);
+
+        // no scan came in, the pass repeats the last scan at the deadline
+        let events = pass(
+            &mut processor,
+            TERM_TICKS,
+            &[event(0, Key::Escape, Edge::None, TERM_TICKS)],
+        );
+        assert_eq!(keys(&events), [(Key::LeftControl, Edge::Rising)]);
+        assert_eq!(events.deadline_ticks(), None);
+
+        let events = pass(
+            &mut processor,
+            150,
+            &[event(0, Key::Escape, Edge::None, 150)],
+        );
+        assert_eq!(keys(&events), [(Key::LeftControl, Edge::None)]);
+
+        let events = pass(
+            &mut processor,
+            200,
+            &[event(0, Key::Escape, Edge::Falling, 200)],
+        );
+        assert_eq!(keys(&events), [(Key::LeftControl, Edge::Falling)]);
+    }
+
+    #[test]
+    fn other_key_press_sends_hold_key_first() {
+        let mut processor = processor();
+        pass(&mut processor, 0, &[event(0, Key::Escape, Edge::Rising, 0)]);
+
+        let events = pass(
+            &mut processor,
+            10,
+            &[
+                event(0, Key::Escape, Edge::None, 10),
+                event(1, Key::C, Edge::Rising, 10),
+            ],
+        );
+        assert_eq!(
+            keys(&events),
+            [(Key::LeftControl, Edge::Rising), (Key::C, Edge::Rising)]
+        );
+    }
+}
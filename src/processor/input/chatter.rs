@@ -0,0 +1,183 @@
+use defmt::Format;
+use rtic_monotonics::rp2040::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    command::{self, Handler, Request, Response},
+    kb::Mono,
+    key::{Edge, LayerIndex},
+    matrix::Bit,
+    processor::{mapper::Input, InputProcessor, Result},
+};
+
+use super::debounce::{DebounceAlgorithm, Debouncer};
+
+// Bounces are counted over this many presses of a key at a time.
+const SAMPLE_PRESS_COUNT: u32 = 100;
+// Bounces within one sample for the switch to be flagged. Healthy switches bounce a little on
+// most presses, so it takes a few per press.
+const CHATTER_THRESHOLD: u32 = 3 * SAMPLE_PRESS_COUNT;
+// Extra lockout added each time a flagged switch keeps chattering, up to the maximum.
+const ADAPTIVE_DELAY_STEP_MICROS: u64 = 5_000;
+const ADAPTIVE_DELAY_MAX_MICROS: u64 = 40_000;
+
+#[derive(Clone, Copy, Debug, Default, Format, PartialEq, Serialize)]
+pub struct SwitchHealth {
+    pub press_count: u32,
+    // Contacts made again too soon after a release to be made by a finger
+    pub bounce_count: u32,
+    pub is_chattering: bool,
+    pub extra_delay_micros: u32,
+}
+
+#[derive(Clone, Copy, Default)]
+struct KeyState {
+    released_ticks: Option<u64>,
+    sample_press_count: u32,
+    sample_bounce_count: u32,
+}
+
+// Looks for switches that start to chatter, i.e. bounce far more than they should, and keeps
+// press and bounce counts for every key. A bounce is a release followed by a new contact quicker
+// than anyone can type. When adaptive, keys that keep chattering get an increasingly long
+// lockout after each change.
+//
+// Meant to run ahead of debouncing, so that it sees every bounce of the switches.
+pub struct ChatterProcessor<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize> {
+    window_ticks: u64,
+    is_adaptive: bool,
+    health: [[SwitchHealth; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT],
+    states: [[KeyState; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT],
+    lockout: Debouncer<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>,
+    newly_flagged: Option<(usize, usize)>,
+}
+
+#[allow(dead_code)]
+impl<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize>
+    ChatterProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
+{
+    pub fn new(window: <Mono as Monotonic>::Duration, is_adaptive: bool) -> Self {
+        ChatterProcessor {
+            window_ticks: window.ticks(),
+            is_adaptive,
+            health: [[SwitchHealth::default(); KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT],
+            states: [[KeyState::default(); KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT],
+            lockout: Debouncer::new(DebounceAlgorithm::EagerPerKey, 0),
+            newly_flagged: None,
+        }
+    }
+
+    pub fn get_health(&self, row: usize, col: usize) -> Option<SwitchHealth> {
+        self.health.get(row)?.get(col).copied()
+    }
+
+    // Keys keep their lockout state, so that held ones are not pressed again.
+    pub fn reset_health(&mut self) {
+        self.health = [[SwitchHealth::default(); KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT];
+        self.states = [[KeyState::default(); KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT];
+        for i in 0..KEY_MATRIX_ROW_COUNT {
+            for j in 0..KEY_MATRIX_COL_COUNT {
+                self.lockout.set_delay(i, j, 0);
+            }
+        }
+        self.newly_flagged = None;
+    }
+
+    // Position of the last key found chattering since the previous call.
+    pub fn take_newly_flagged(&mut self) -> Option<(usize, usize)> {
+        self.newly_flagged.take()
+    }
+
+    fn record(&mut self, i: usize, j: usize, edge: Edge, now_ticks: u64) {
+        let health = &mut self.health[i][j];
+        let state = &mut self.states[i][j];
+        match edge {
+            Edge::Rising => {
+                let is_bounce = state
+                    .released_ticks
+                    .is_some_and(|t| now_ticks.saturating_sub(t) <= self.window_ticks);
+                if is_bounce {
+                    health.bounce_count = health.bounce_count.saturating_add(1);
+                    state.sample_bounce_count += 1;
+                } else {
+                    health.press_count = health.press_count.saturating_add(1);
+                    state.sample_press_count += 1;
+                }
+            }
+            Edge::Falling => state.released_ticks = Some(now_ticks),
+            Edge::None => return,
+        }
+
+        if state.sample_bounce_count >= CHATTER_THRESHOLD {
+            defmt::warn!("switch at ({}, {}) is chattering", i, j);
+            self.newly_flagged = Some((i, j));
+            health.is_chattering = true;
+            if self.is_adaptive {
+                let delay_micros = (health.extra_delay_micros as u64 + ADAPTIVE_DELAY_STEP_MICROS)
+                    .min(ADAPTIVE_DELAY_MAX_MICROS);
+                health.extra_delay_micros = delay_micros as u32;
+                let delay: <Mono as Monotonic>::Duration = delay_micros.micros();
+                self.lockout.set_delay(i, j, delay.ticks());
+            }
+            *state = KeyState {
+                released_ticks: state.released_ticks,
+                ..Default::default()
+            };
+        } else if state.sample_press_count >= SAMPLE_PRESS_COUNT {
+            *state = KeyState {
+                released_ticks: state.released_ticks,
+                ..Default::default()
+            };
+        }
+    }
+}
+
+impl<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize>
+    InputProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
+    for ChatterProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
+{
+    fn process(&mut self, input: &mut Input<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>) -> Result {
+        let result = &mut input.key_matrix_result;
+        for (i, row) in result.matrix.iter().enumerate() {
+            for (j, bit) in row.iter().enumerate() {
+                self.record(i, j, bit.edge, result.scan_time_ticks);
+            }
+        }
+        if !self.is_adaptive {
+            return Ok(());
+        }
+
+        let pressed = result.matrix.map(|row| row.map(|bit| bit.pressed));
+        let previous = *self.lockout.debounced();
+        let filtered = self.lockout.update(result.scan_time_ticks, &pressed);
+        for (i, row) in result.matrix.iter_mut().enumerate() {
+            for (j, bit) in row.iter_mut().enumerate() {
+                *bit = Bit {
+                    edge: Edge::from((previous[i][j], filtered[i][j])),
+                    pressed: filtered[i][j],
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<L: LayerIndex, const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize> Handler<L>
+    for ChatterProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
+{
+    fn handle(&mut self, request: &Request<L>) -> Option<Response<L>> {
+        match *request {
+            Request::GetSwitchHealth { row, col } => {
+                Some(match self.get_health(row as usize, col as usize) {
+                    Some(health) => Response::SwitchHealth { row, col, health },
+                    None => Response::Error(command::Error::OutOfBounds),
+                })
+            }
+            Request::ResetSwitchHealth => {
+                self.reset_health();
+                Some(Response::Ack)
+            }
+            _ => None,
+        }
+    }
+}
@@ -4,63 +4,327 @@ use rtic_monotonics::Monotonic;
 use crate::{
     kb::Mono,
     key::Edge,
+    matrix::Bit,
     processor::{mapper::Input, InputProcessor, Result},
 };
 
-pub struct KeyMatrixRisingFallingDebounceProcessor<
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum DebounceAlgorithm {
+    // Reports the whole matrix once none of it changed for the longest delay.
+    SymmetricDeferredGlobal,
+    // Reports a key once it has not changed for its delay.
+    SymmetricDeferredPerKey,
+    // Reports a key change right away, then ignores the key for its delay.
+    EagerPerKey,
+    // Reports presses right away, and releases once the key stayed released for its delay.
+    EagerPressDeferredRelease,
+    // Reports a row once none of its keys changed for the longest delay of the row.
+    DeferredPerRow,
+}
+
+// Debouncing state of the whole matrix, driven by raw samples and their scan time so that it
+// does not depend on the monotonic timer.
+pub struct Debouncer<const ROW_COUNT: usize, const COL_COUNT: usize> {
+    algorithm: DebounceAlgorithm,
+    delay_ticks: [[u64; COL_COUNT]; ROW_COUNT],
+    raw: [[bool; COL_COUNT]; ROW_COUNT],
+    debounced: [[bool; COL_COUNT]; ROW_COUNT],
+    // Last change of the raw level, per key, per row and for the whole matrix
+    changed_ticks: [[u64; COL_COUNT]; ROW_COUNT],
+    row_changed_ticks: [u64; ROW_COUNT],
+    matrix_changed_ticks: u64,
+    // Last change of the debounced level, for the eager lockout
+    reported_ticks: [[u64; COL_COUNT]; ROW_COUNT],
+}
+
+#[allow(dead_code)]
+impl<const ROW_COUNT: usize, const COL_COUNT: usize> Debouncer<ROW_COUNT, COL_COUNT> {
+    pub fn new(algorithm: DebounceAlgorithm, delay_ticks: u64) -> Self {
+        Debouncer {
+            algorithm,
+            delay_ticks: [[delay_ticks; COL_COUNT]; ROW_COUNT],
+            raw: [[false; COL_COUNT]; ROW_COUNT],
+            debounced: [[false; COL_COUNT]; ROW_COUNT],
+            changed_ticks: [[0; COL_COUNT]; ROW_COUNT],
+            row_changed_ticks: [0; ROW_COUNT],
+            matrix_changed_ticks: 0,
+            reported_ticks: [[0; COL_COUNT]; ROW_COUNT],
+        }
+    }
+
+    pub fn set_delay(&mut self, row: usize, col: usize, delay_ticks: u64) {
+        assert!(
+            row < ROW_COUNT && col < COL_COUNT,
+            "debounce delay set on a key outside the matrix"
+        );
+        self.delay_ticks[row][col] = delay_ticks;
+    }
+
+    pub fn debounced(&self) -> &[[bool; COL_COUNT]; ROW_COUNT] {
+        &self.debounced
+    }
+
+    // Feeds one raw sample and returns the debounced levels.
+    #[allow(clippy::needless_range_loop)]
+    pub fn update(
+        &mut self,
+        now_ticks: u64,
+        raw: &[[bool; COL_COUNT]; ROW_COUNT],
+    ) -> &[[bool; COL_COUNT]; ROW_COUNT] {
+        for i in 0..ROW_COUNT {
+            for j in 0..COL_COUNT {
+                if raw[i][j] != self.raw[i][j] {
+                    self.raw[i][j] = raw[i][j];
+                    self.changed_ticks[i][j] = now_ticks;
+                    self.row_changed_ticks[i] = now_ticks;
+                    self.matrix_changed_ticks = now_ticks;
+                }
+            }
+        }
+
+        let elapsed = |since: u64| now_ticks.saturating_sub(since);
+        match self.algorithm {
+            DebounceAlgorithm::SymmetricDeferredGlobal => {
+                let delay = self.delay_ticks.iter().flatten().copied().max();
+                if elapsed(self.matrix_changed_ticks) >= delay.unwrap_or(0) {
+                    self.debounced = self.raw;
+                }
+            }
+            DebounceAlgorithm::DeferredPerRow => {
+                for i in 0..ROW_COUNT {
+                    let delay = self.delay_ticks[i].iter().copied().max();
+                    if elapsed(self.row_changed_ticks[i]) >= delay.unwrap_or(0) {
+                        self.debounced[i] = self.raw[i];
+                    }
+                }
+            }
+            algorithm => {
+                for i in 0..ROW_COUNT {
+                    for j in 0..COL_COUNT {
+                        let raw = self.raw[i][j];
+                        if raw == self.debounced[i][j] {
+                            continue;
+                        }
+                        let delay = self.delay_ticks[i][j];
+                        let is_stable = elapsed(self.changed_ticks[i][j]) >= delay;
+                        let is_unlocked = elapsed(self.reported_ticks[i][j]) >= delay;
+                        let report = match algorithm {
+                            DebounceAlgorithm::SymmetricDeferredPerKey => is_stable,
+                            DebounceAlgorithm::EagerPerKey => is_unlocked,
+                            DebounceAlgorithm::EagerPressDeferredRelease => raw || is_stable,
+                            DebounceAlgorithm::SymmetricDeferredGlobal
+                            | DebounceAlgorithm::DeferredPerRow => unreachable!(),
+                        };
+                        if report {
+                            self.debounced[i][j] = raw;
+                            self.reported_ticks[i][j] = now_ticks;
+                        }
+                    }
+                }
+            }
+        }
+        &self.debounced
+    }
+}
+
+pub struct KeyMatrixDebounceProcessor<
     const KEY_MATRIX_ROW_COUNT: usize,
     const KEY_MATRIX_COL_COUNT: usize,
 > {
-    delay: <Mono as Monotonic>::Duration,
-    previous_states: [[State; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT],
-}
-
-#[derive(Clone, Copy, Debug, Format)]
-struct State {
-    pressed_ticks: u64,
-    pressed: bool,
+    debouncer: Debouncer<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>,
 }
 
 #[allow(dead_code)]
 impl<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize>
-    KeyMatrixRisingFallingDebounceProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
+    KeyMatrixDebounceProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
 {
-    pub fn new(delay: <Mono as Monotonic>::Duration) -> Self {
-        KeyMatrixRisingFallingDebounceProcessor {
-            delay,
-            previous_states: [[State {
-                pressed_ticks: 0,
-                pressed: false,
-            }; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT],
+    pub fn new(algorithm: DebounceAlgorithm, delay: <Mono as Monotonic>::Duration) -> Self {
+        KeyMatrixDebounceProcessor {
+            debouncer: Debouncer::new(algorithm, delay.ticks()),
         }
     }
+
+    // Overrides the delay of a single key, e.g. one with a noisier switch.
+    pub fn set_delay(&mut self, row: usize, col: usize, delay: <Mono as Monotonic>::Duration) {
+        self.debouncer.set_delay(row, col, delay.ticks());
+    }
 }
 
 impl<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize>
     InputProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
-    for KeyMatrixRisingFallingDebounceProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
+    for KeyMatrixDebounceProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
 {
     fn process(&mut self, input: &mut Input<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>) -> Result {
-        for (i, row) in input.key_matrix_result.matrix.iter_mut().enumerate() {
+        let result = &mut input.key_matrix_result;
+        let raw = result.matrix.map(|row| row.map(|bit| bit.pressed));
+        let previous = *self.debouncer.debounced();
+        let debounced = self.debouncer.update(result.scan_time_ticks, &raw);
+
+        // Edges are recomputed so that they always agree with the debounced levels
+        for (i, row) in result.matrix.iter_mut().enumerate() {
             for (j, bit) in row.iter_mut().enumerate() {
-                let previous_state = &mut self.previous_states[i][j];
-                if input.key_matrix_result.scan_time_ticks - previous_state.pressed_ticks
-                    <= self.delay.ticks()
-                {
-                    // ignore change
-                    if bit.pressed != previous_state.pressed {
-                        bit.edge = Edge::None;
-                        bit.pressed = previous_state.pressed;
-                    }
-                } else if bit.edge == Edge::Rising || bit.edge == Edge::Falling {
-                    // update previous_state
-                    *previous_state = State {
-                        pressed_ticks: input.key_matrix_result.scan_time_ticks,
-                        pressed: bit.pressed,
-                    };
-                }
+                *bit = Bit {
+                    edge: Edge::from((previous[i][j], debounced[i][j])),
+                    pressed: debounced[i][j],
+                };
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY_TICKS: u64 = 5;
+
+    fn check<const ROW_COUNT: usize, const COL_COUNT: usize>(
+        debouncer: &mut Debouncer<ROW_COUNT, COL_COUNT>,
+        script: &[(
+            u64,
+            [[bool; COL_COUNT]; ROW_COUNT],
+            [[bool; COL_COUNT]; ROW_COUNT],
+        )],
+    ) {
+        for (now_ticks, raw, expected) in script {
+            assert_eq!(
+                debouncer.update(*now_ticks, raw),
+                expected,
+                "at {} ticks",
+                now_ticks
+            );
+        }
+    }
+
+    #[test]
+    fn symmetric_deferred_per_key_waits_for_stable_levels() {
+        let mut debouncer =
+            Debouncer::<1, 1>::new(DebounceAlgorithm::SymmetricDeferredPerKey, DELAY_TICKS);
+        check(
+            &mut debouncer,
+            &[
+                (0, [[false]], [[false]]),
+                (10, [[true]], [[false]]),
+                (11, [[false]], [[false]]),
+                (12, [[true]], [[false]]),
+                (16, [[true]], [[false]]),
+                (17, [[true]], [[true]]),
+                (30, [[false]], [[true]]),
+                (32, [[true]], [[true]]),
+                (33, [[false]], [[true]]),
+                (37, [[false]], [[true]]),
+                (38, [[false]], [[false]]),
+            ],
+        );
+    }
+
+    #[test]
+    fn eager_per_key_reports_right_away_then_locks_out() {
+        let mut debouncer = Debouncer::<1, 1>::new(DebounceAlgorithm::EagerPerKey, DELAY_TICKS);
+        check(
+            &mut debouncer,
+            &[
+                (0, [[false]], [[false]]),
+                (10, [[true]], [[true]]),
+                (11, [[false]], [[true]]),
+                (12, [[true]], [[true]]),
+                (16, [[true]], [[true]]),
+                (30, [[false]], [[false]]),
+                (31, [[true]], [[false]]),
+                (32, [[false]], [[false]]),
+                (36, [[false]], [[false]]),
+            ],
+        );
+    }
+
+    #[test]
+    fn eager_press_deferred_release_only_defers_releases() {
+        let mut debouncer =
+            Debouncer::<1, 1>::new(DebounceAlgorithm::EagerPressDeferredRelease, DELAY_TICKS);
+        check(
+            &mut debouncer,
+            &[
+                (0, [[false]], [[false]]),
+                (10, [[true]], [[true]]),
+                (11, [[false]], [[true]]),
+                (12, [[true]], [[true]]),
+                (30, [[false]], [[true]]),
+                (32, [[true]], [[true]]),
+                (33, [[false]], [[true]]),
+                (37, [[false]], [[true]]),
+                (38, [[false]], [[false]]),
+                (40, [[true]], [[true]]),
+            ],
+        );
+    }
+
+    #[test]
+    fn symmetric_deferred_global_waits_for_the_whole_matrix() {
+        let mut debouncer =
+            Debouncer::<1, 2>::new(DebounceAlgorithm::SymmetricDeferredGlobal, DELAY_TICKS);
+        check(
+            &mut debouncer,
+            &[
+                (0, [[false, false]], [[false, false]]),
+                (10, [[true, false]], [[false, false]]),
+                // Another key bouncing holds the first one back
+                (13, [[true, true]], [[false, false]]),
+                (14, [[true, false]], [[false, false]]),
+                (18, [[true, false]], [[false, false]]),
+                (19, [[true, false]], [[true, false]]),
+            ],
+        );
+    }
+
+    #[test]
+    fn deferred_per_row_keeps_rows_apart() {
+        let mut debouncer = Debouncer::<2, 1>::new(DebounceAlgorithm::DeferredPerRow, DELAY_TICKS);
+        check(
+            &mut debouncer,
+            &[
+                (0, [[false], [false]], [[false], [false]]),
+                (10, [[true], [false]], [[false], [false]]),
+                // A bounce on another row does not hold this one back
+                (12, [[true], [true]], [[false], [false]]),
+                (13, [[true], [false]], [[false], [false]]),
+                (15, [[true], [false]], [[true], [false]]),
+                (18, [[true], [false]], [[true], [false]]),
+            ],
+        );
+    }
+
+    #[test]
+    fn overridden_delay_applies_to_its_key_only() {
+        let mut debouncer =
+            Debouncer::<1, 2>::new(DebounceAlgorithm::SymmetricDeferredPerKey, DELAY_TICKS);
+        debouncer.set_delay(0, 1, 20);
+        check(
+            &mut debouncer,
+            &[
+                (0, [[false, false]], [[false, false]]),
+                (10, [[true, true]], [[false, false]]),
+                (15, [[true, true]], [[true, false]]),
+                (29, [[true, true]], [[true, false]]),
+                (30, [[true, true]], [[true, true]]),
+            ],
+        );
+    }
+
+    #[test]
+    fn overridden_delay_holds_the_whole_matrix_back() {
+        let mut debouncer =
+            Debouncer::<1, 2>::new(DebounceAlgorithm::SymmetricDeferredGlobal, DELAY_TICKS);
+        debouncer.set_delay(0, 1, 20);
+        check(
+            &mut debouncer,
+            &[
+                (0, [[false, false]], [[false, false]]),
+                (10, [[true, false]], [[false, false]]),
+                (15, [[true, false]], [[false, false]]),
+                (30, [[true, false]], [[true, false]]),
+            ],
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use crate::{
+    key::Edge,
+    matrix::Bit,
+    processor::{mapper::Input, InputProcessor, Result},
+};
+
+// Without diodes, three keys pressed at the corners of a rectangle close the circuit for the
+// fourth corner as well, so the matrix cannot tell which of the four keys are really pressed.
+// Keys that become pressed while they are part of such a rectangle are held back until the
+// rectangle is gone; keys that were already reported stay pressed.
+pub struct AntiGhostProcessor<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize>
+{
+    reported: [[bool; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT],
+}
+
+#[allow(dead_code)]
+impl<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize>
+    AntiGhostProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
+{
+    pub fn new() -> Self {
+        AntiGhostProcessor {
+            reported: [[false; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT],
+        }
+    }
+}
+
+impl<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize>
+    InputProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
+    for AntiGhostProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
+{
+    fn process(&mut self, input: &mut Input<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>) -> Result {
+        let matrix = &mut input.key_matrix_result.matrix;
+
+        // Any two rows sharing two or more pressed columns form at least one rectangle
+        let mut ambiguous = [[false; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT];
+        for i1 in 0..KEY_MATRIX_ROW_COUNT {
+            for i2 in i1 + 1..KEY_MATRIX_ROW_COUNT {
+                let is_shared = |j: usize| matrix[i1][j].pressed && matrix[i2][j].pressed;
+                if (0..KEY_MATRIX_COL_COUNT).filter(|&j| is_shared(j)).count() < 2 {
+                    continue;
+                }
+                for j in (0..KEY_MATRIX_COL_COUNT).filter(|&j| is_shared(j)) {
+                    ambiguous[i1][j] = true;
+                    ambiguous[i2][j] = true;
+                }
+            }
+        }
+
+        for (i, row) in matrix.iter_mut().enumerate() {
+            for (j, bit) in row.iter_mut().enumerate() {
+                let reported = &mut self.reported[i][j];
+                let pressed = bit.pressed && (*reported || !ambiguous[i][j]);
+                if bit.edge == Edge::Rising && !pressed {
+                    defmt::debug!("suppressed ambiguous key at ({}, {})", i, j);
+                }
+                *bit = Bit {
+                    edge: Edge::from((*reported, pressed)),
+                    pressed,
+                };
+                *reported = pressed;
+            }
+        }
+        Ok(())
+    }
+}
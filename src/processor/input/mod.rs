@@ -1,3 +1,5 @@
+pub mod chatter;
 pub mod debounce;
-pub mod flip;
+pub mod ghost;
 pub mod none;
+pub mod socd;
@@ -0,0 +1,211 @@
+use alloc::vec::Vec;
+use defmt::Format;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    key::{Action, Control, Edge, LayerIndex},
+    matrix::Bit,
+    processor::{mapper::Input, EventQueue, EventsProcessor, InputProcessor, Result},
+    storage::{Record, RecordKind},
+};
+
+// What is reported while both keys of a pair are held.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum SocdMode {
+    // The key pressed last wins, the other one comes back when it is released.
+    LastInputPriority,
+    // The key pressed first wins, the other one comes in once the first one is released.
+    FirstInputPriority,
+    // Neither key is reported.
+    Neutral,
+    // The first key of the pair always wins.
+    AbsolutePriority,
+}
+
+// Two opposing keys, as (row, col) key matrix positions.
+#[derive(Clone, Copy, Debug, Format)]
+pub struct SocdPair {
+    pub keys: [(usize, usize); 2],
+    pub mode: SocdMode,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Format, PartialEq, Serialize)]
+pub struct SocdSettings {
+    pub is_enabled: bool,
+}
+
+impl Record for SocdSettings {
+    const KIND: RecordKind = RecordKind::SocdSettings;
+    const VERSION: u8 = 1;
+}
+
+#[derive(Clone, Copy, Default)]
+struct PairState {
+    raw: [bool; 2],
+    reported: [bool; 2],
+    // Index of the key pressed most recently
+    last_pressed: usize,
+}
+
+// Simultaneous opposing cardinal directions cleaning for any number of key pairs. It can be
+// switched on and off with `Control::SocdToggle`, separately for each profile; keys that are held
+// while it is toggled move to their new state on the next scan.
+pub struct SocdProcessor {
+    pairs: &'static [SocdPair],
+    states: Vec<PairState>,
+    is_enabled: bool,
+}
+
+#[allow(dead_code)]
+impl SocdProcessor {
+    pub fn new(pairs: &'static [SocdPair], is_enabled: bool) -> Self {
+        SocdProcessor {
+            pairs,
+            states: pairs.iter().map(|_| PairState::default()).collect(),
+            is_enabled,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.is_enabled
+    }
+
+    pub fn set_enabled(&mut self, is_enabled: bool) {
+        self.is_enabled = is_enabled;
+    }
+
+    pub fn get_settings(&self) -> SocdSettings {
+        SocdSettings {
+            is_enabled: self.is_enabled,
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: SocdSettings) {
+        self.is_enabled = settings.is_enabled;
+    }
+
+    fn resolve(mode: SocdMode, state: &PairState) -> [bool; 2] {
+        if !(state.raw[0] && state.raw[1]) {
+            return state.raw;
+        }
+        let last = state.last_pressed;
+        let winner = match mode {
+            SocdMode::LastInputPriority => last,
+            SocdMode::FirstInputPriority => 1 - last,
+            SocdMode::Neutral => return [false, false],
+            SocdMode::AbsolutePriority => 0,
+        };
+        [winner == 0, winner == 1]
+    }
+}
+
+impl<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize>
+    InputProcessor<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT> for SocdProcessor
+{
+    fn process(&mut self, input: &mut Input<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>) -> Result {
+        let matrix = &mut input.key_matrix_result.matrix;
+        for (pair, state) in self.pairs.iter().zip(self.states.iter_mut()) {
+            let raw = pair.keys.map(|(i, j)| matrix[i][j].pressed);
+            // When both go down in the same scan, the second key counts as the last one
+            for (k, &pressed) in raw.iter().enumerate() {
+                if pressed && !state.raw[k] {
+                    state.last_pressed = k;
+                }
+            }
+            state.raw = raw;
+
+            let reported = if self.is_enabled {
+                Self::resolve(pair.mode, state)
+            } else {
+                raw
+            };
+            for (k, &(i, j)) in pair.keys.iter().enumerate() {
+                matrix[i][j] = Bit {
+                    edge: Edge::from((state.reported[k], reported[k])),
+                    pressed: reported[k],
+                };
+            }
+            state.reported = reported;
+        }
+        Ok(())
+    }
+}
+
+impl<L: LayerIndex> EventsProcessor<L> for SocdProcessor {
+    fn process(&mut self, events: &mut EventQueue<L>) -> Result {
+        events.iter().for_each(|e| {
+            if e.edge == Edge::Rising && e.action == Action::Control(Control::SocdToggle) {
+                self.is_enabled = !self.is_enabled;
+                defmt::info!("socd: {}", self.is_enabled);
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::Result as MatrixResult;
+
+    static FIRST_INPUT_PRIORITY: [SocdPair; 1] = [SocdPair {
+        keys: [(0, 0), (0, 1)],
+        mode: SocdMode::FirstInputPriority,
+    }];
+    static LAST_INPUT_PRIORITY: [SocdPair; 1] = [SocdPair {
+        keys: [(0, 0), (0, 1)],
+        mode: SocdMode::LastInputPriority,
+    }];
+
+    // Feeds which keys are held and returns the reported (edge, pressed) of both.
+    fn process(processor: &mut SocdProcessor, held: [bool; 2]) -> [(Edge, bool); 2] {
+        let mut input = Input::<1, 2>::repeat(&MatrixResult::default(), 0);
+        for (bit, pressed) in input.key_matrix_result.matrix[0].iter_mut().zip(held) {
+            bit.pressed = pressed;
+        }
+        InputProcessor::process(processor, &mut input).unwrap();
+        input.key_matrix_result.matrix[0].map(|bit| (bit.edge, bit.pressed))
+    }
+
+    #[test]
+    fn first_input_priority_hands_over_on_release() {
+        let mut processor = SocdProcessor::new(&FIRST_INPUT_PRIORITY, true);
+        let none = (Edge::None, false);
+        assert_eq!(
+            process(&mut processor, [true, false]),
+            [(Edge::Rising, true), none]
+        );
+        assert_eq!(
+            process(&mut processor, [true, true]),
+            [(Edge::None, true), none]
+        );
+        assert_eq!(
+            process(&mut processor, [false, true]),
+            [(Edge::Falling, false), (Edge::Rising, true)]
+        );
+        assert_eq!(
+            process(&mut processor, [false, false]),
+            [none, (Edge::Falling, false)]
+        );
+    }
+
+    #[test]
+    fn last_input_priority_comes_back_on_release() {
+        let mut processor = SocdProcessor::new(&LAST_INPUT_PRIORITY, true);
+        let none = (Edge::None, false);
+        process(&mut processor, [true, false]);
+        assert_eq!(
+            process(&mut processor, [true, true]),
+            [(Edge::Falling, false), (Edge::Rising, true)]
+        );
+        assert_eq!(
+            process(&mut processor, [true, false]),
+            [(Edge::Rising, true), (Edge::Falling, false)]
+        );
+        assert_eq!(
+            process(&mut processor, [false, false]),
+            [(Edge::Falling, false), none]
+        );
+    }
+}
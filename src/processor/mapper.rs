@@ -1,19 +1,63 @@
 use alloc::vec::Vec;
-use enum_map::{EnumArray, EnumMap};
+use enum_map::{Enum, EnumArray, EnumMap};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    key::{Action, Edge, LayerIndex, Modifier},
-    matrix::Result as MatrixResult,
+    command::{self, Handler, Request, Response},
+    key::{Action, Edge, LayerIndex},
+    matrix::{
+        analog::{CalibrationRecord, Travel},
+        Result as MatrixResult,
+    },
+    physical::PhysicalKey,
     rotary::{Direction, Result as RotaryResult},
+    storage::{Record, RecordKind},
 };
 
-use super::Event;
+use super::{Event, Events};
 
 pub struct Input<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize> {
     pub key_matrix_result: MatrixResult<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>,
+    // Only set by analog scanners; calibration only when it changed.
+    pub key_matrix_travel: Option<Travel<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>>,
+    pub key_matrix_calibration: Option<CalibrationRecord>,
     pub rotary_encoder_result: RotaryResult,
 }
 
+impl<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize>
+    Input<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>
+{
+    // Input for a pass that was not triggered by a scan: keys stay as they were last scanned.
+    pub fn repeat(
+        previous: &MatrixResult<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>,
+        now_ticks: u64,
+    ) -> Self {
+        let mut key_matrix_result = *previous;
+        key_matrix_result.scan_time_ticks = now_ticks;
+        key_matrix_result
+            .matrix
+            .iter_mut()
+            .flatten()
+            .for_each(|bit| bit.edge = Edge::None);
+        Input {
+            key_matrix_result,
+            key_matrix_travel: None,
+            key_matrix_calibration: None,
+            rotary_encoder_result: Default::default(),
+        }
+    }
+
+    pub fn has_releases(&self) -> bool {
+        self.key_matrix_result
+            .matrix
+            .iter()
+            .flatten()
+            .any(|bit| bit.edge == Edge::Falling)
+            || self.rotary_encoder_result.edge == Edge::Falling
+    }
+}
+
+#[derive(Clone)]
 pub struct InputMap<
     const LAYER_COUNT: usize,
     const KEY_MATRIX_ROW_COUNT: usize,
@@ -35,15 +79,121 @@ impl<
             + EnumArray<EnumMap<Direction, Action<L>>>,
     > InputMap<LAYER_COUNT, KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT, L>
 {
-    pub const fn new(
-        key_matrix: EnumMap<L, [[Action<L>; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT]>,
+    // Builds the map from one action per physical key, listed in the same order as `layout`.
+    // Matrix positions without a key are left as `Action::None`.
+    pub fn from_layout<const KEY_COUNT: usize>(
+        layout: &[PhysicalKey],
+        keys: EnumMap<L, [Action<L>; KEY_COUNT]>,
         rotary_encoder: EnumMap<L, EnumMap<Direction, Action<L>>>,
-    ) -> Self {
+    ) -> Self
+    where
+        L: EnumArray<[Action<L>; KEY_COUNT]>,
+    {
+        assert!(
+            layout.len() == KEY_COUNT,
+            "keymap does not match the physical layout"
+        );
+        let mut key_matrix =
+            EnumMap::from_fn(|_| [[Action::None; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT]);
+        for (l, actions) in keys {
+            for (key, action) in layout.iter().zip(actions) {
+                key_matrix[l][key.row as usize][key.col as usize] = action;
+            }
+        }
         InputMap {
             key_matrix,
             rotary_encoder,
         }
     }
+
+    pub fn get_key_action_mut(
+        &mut self,
+        layer: usize,
+        row: usize,
+        col: usize,
+    ) -> Option<&mut Action<L>> {
+        if layer >= L::LENGTH {
+            return None;
+        }
+        self.key_matrix[L::from_usize(layer)]
+            .get_mut(row)?
+            .get_mut(col)
+    }
+
+    pub fn get_rotary_action_mut(
+        &mut self,
+        layer: usize,
+        direction: Direction,
+    ) -> Option<&mut Action<L>> {
+        if layer >= L::LENGTH || direction == Direction::None {
+            return None;
+        }
+        Some(&mut self.rotary_encoder[L::from_usize(layer)][direction])
+    }
+
+    pub fn to_record(&self) -> InputMapRecord<L> {
+        InputMapRecord {
+            layer_count: L::LENGTH as u8,
+            row_count: KEY_MATRIX_ROW_COUNT as u8,
+            col_count: KEY_MATRIX_COL_COUNT as u8,
+            key_matrix: self
+                .key_matrix
+                .values()
+                .flat_map(|layer| layer.iter().flatten())
+                .copied()
+                .collect(),
+            rotary_encoder: self
+                .rotary_encoder
+                .values()
+                .flat_map(|layer| layer.values())
+                .copied()
+                .collect(),
+        }
+    }
+
+    // Returns None if the record was made for a different layout.
+    pub fn from_record(record: &InputMapRecord<L>) -> Option<Self> {
+        let layer_size = KEY_MATRIX_ROW_COUNT * KEY_MATRIX_COL_COUNT;
+        if record.layer_count as usize != L::LENGTH
+            || record.row_count as usize != KEY_MATRIX_ROW_COUNT
+            || record.col_count as usize != KEY_MATRIX_COL_COUNT
+            || record.key_matrix.len() != L::LENGTH * layer_size
+            || record.rotary_encoder.len() != L::LENGTH * Direction::LENGTH
+        {
+            return None;
+        }
+        Some(InputMap {
+            key_matrix: EnumMap::from_fn(|l: L| {
+                core::array::from_fn(|i| {
+                    core::array::from_fn(|j| {
+                        record.key_matrix
+                            [l.into_usize() * layer_size + i * KEY_MATRIX_COL_COUNT + j]
+                    })
+                })
+            }),
+            rotary_encoder: EnumMap::from_fn(|l: L| {
+                EnumMap::from_fn(|d: Direction| {
+                    record.rotary_encoder[l.into_usize() * Direction::LENGTH + d.into_usize()]
+                })
+            }),
+        })
+    }
+}
+
+// Flattened InputMap, as const generic arrays cannot be serialized directly.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct InputMapRecord<L: LayerIndex> {
+    layer_count: u8,
+    row_count: u8,
+    col_count: u8,
+    key_matrix: Vec<Action<L>>,
+    rotary_encoder: Vec<Action<L>>,
+}
+
+impl<L: LayerIndex> Record for InputMapRecord<L> {
+    const KIND: RecordKind = RecordKind::InputMap;
+    // 2: split halves are no longer mirrored in the matrix
+    const VERSION: u8 = 2;
 }
 
 pub struct Mapper<
@@ -55,7 +205,13 @@ pub struct Mapper<
         + EnumArray<EnumMap<Direction, Action<L>>>,
 > {
     previous_key_matrix_result: MatrixResult<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>,
+    // Actions keys were pressed with, kept until they are released, so that a layer change, a
+    // keymap edit or a profile switch in between cannot release another action than was pressed
+    pressed_actions: [[Action<L>; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT],
+    pressed_rotary_action: Action<L>,
     mapping: InputMap<LAYER_COUNT, KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT, L>,
+    default_mapping: InputMap<LAYER_COUNT, KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT, L>,
+    revision: u32,
 }
 
 impl<
@@ -68,13 +224,42 @@ impl<
     > Mapper<LAYER_COUNT, KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT, L>
 {
     pub fn new(
-        mapping: InputMap<LAYER_COUNT, KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT, L>,
+        default_mapping: InputMap<LAYER_COUNT, KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT, L>,
     ) -> Self {
         Mapper {
             previous_key_matrix_result: MatrixResult::default(),
-            mapping,
+            pressed_actions: [[Action::Pass; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT],
+            pressed_rotary_action: Action::Pass,
+            mapping: default_mapping.clone(),
+            default_mapping,
+            revision: 0,
         }
     }
+
+    pub fn get_mapping(
+        &self,
+    ) -> &InputMap<LAYER_COUNT, KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT, L> {
+        &self.mapping
+    }
+
+    // Keys held across the change, e.g. on a profile switch, keep the action they were pressed
+    // with until released.
+    pub fn set_mapping(
+        &mut self,
+        mapping: InputMap<LAYER_COUNT, KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT, L>,
+    ) {
+        self.mapping = mapping;
+    }
+
+    pub fn reset_mapping(&mut self) {
+        self.mapping = self.default_mapping.clone();
+        self.revision = self.revision.wrapping_add(1);
+    }
+
+    // Bumped on every runtime edit of the mapping.
+    pub fn get_revision(&self) -> u32 {
+        self.revision
+    }
 }
 
 impl<
@@ -89,19 +274,21 @@ impl<
     pub fn map(
         &mut self,
         input: &Input<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>,
-        events: &mut Vec<Event<L>>,
+        events: &mut Events<L>,
     ) {
         // map key matrix
         let result = input.key_matrix_result;
-        let mut provisional_events = Vec::<Event<L>>::with_capacity(10);
         let mut new_layer = true;
         let mut layer = L::default();
         while new_layer {
-            provisional_events.clear();
+            events.clear();
             new_layer = false;
             for (i, row) in result.matrix.iter().enumerate() {
                 for (j, bit) in row.iter().enumerate() {
-                    let action = self.mapping.key_matrix[layer][i][j];
+                    let action = match bit.edge {
+                        Edge::Rising => self.mapping.key_matrix[layer][i][j],
+                        _ => self.pressed_actions[i][j],
+                    };
                     if bit.pressed {
                         if let Action::LayerModifier(l) = action {
                             if layer < l {
@@ -113,56 +300,191 @@ impl<
                     }
                     // push non-idling event
                     #[allow(clippy::nonminimal_bool)]
-                    if !(bit.edge == Edge::None && !bit.pressed) {
-                        // resolve modified key modifiers
-                        if let Action::ModifiedKey(mk) = action {
-                            mk.get_modifiers()
-                                .iter()
-                                .filter(|&&m: &&Modifier| m != Default::default())
-                                .for_each(|&m| {
-                                    provisional_events.push(Event {
-                                        time_ticks: result.scan_time_ticks,
-                                        i,
-                                        j,
-                                        edge: bit.edge,
-                                        action: m.into(),
-                                    })
-                                });
-                            provisional_events.push(Event {
+                    if !(bit.edge == Edge::None && !bit.pressed)
+                        && events
+                            .push(Event {
                                 time_ticks: result.scan_time_ticks,
                                 i,
                                 j,
                                 edge: bit.edge,
-                                action: mk.get_key().into(),
-                            });
-                            continue;
-                        }
-
-                        provisional_events.push(Event {
-                            time_ticks: result.scan_time_ticks,
-                            i,
-                            j,
-                            edge: bit.edge,
-                            action,
-                        })
+                                action,
+                            })
+                            .is_err()
+                    {
+                        defmt::warn!("event queue is full, dropping key ({}, {})", i, j);
                     }
                 }
             }
         }
 
+        for e in events.iter() {
+            match e.edge {
+                Edge::Rising => self.pressed_actions[e.i][e.j] = e.action,
+                Edge::Falling => self.pressed_actions[e.i][e.j] = Action::Pass,
+                Edge::None => {}
+            }
+        }
+
         // map rotary encoder
         let result = input.rotary_encoder_result;
-        if !(result.edge == Edge::None && result.direction == Direction::None) {
-            provisional_events.push(Event {
-                time_ticks: result.scan_time_ticks,
-                i: 0,
-                j: 0,
-                edge: result.edge,
-                action: self.mapping.rotary_encoder[layer][result.direction],
-            });
+        let action = match result.edge {
+            Edge::Rising => self.mapping.rotary_encoder[layer][result.direction],
+            _ => self.pressed_rotary_action,
+        };
+        match result.edge {
+            Edge::Rising => self.pressed_rotary_action = action,
+            Edge::Falling => self.pressed_rotary_action = Action::Pass,
+            Edge::None => {}
+        }
+        if !(result.edge == Edge::None && result.direction == Direction::None)
+            && events
+                .push(Event {
+                    time_ticks: result.scan_time_ticks,
+                    i: 0,
+                    j: 0,
+                    edge: result.edge,
+                    action,
+                })
+                .is_err()
+        {
+            defmt::warn!("event queue is full, dropping rotary encoder");
         }
 
-        *events = provisional_events;
         self.previous_key_matrix_result = input.key_matrix_result;
     }
 }
+
+impl<
+        const LAYER_COUNT: usize,
+        const KEY_MATRIX_ROW_COUNT: usize,
+        const KEY_MATRIX_COL_COUNT: usize,
+        L: LayerIndex
+            + EnumArray<[[Action<L>; KEY_MATRIX_COL_COUNT]; KEY_MATRIX_ROW_COUNT]>
+            + EnumArray<EnumMap<Direction, Action<L>>>,
+    > Handler<L> for Mapper<LAYER_COUNT, KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT, L>
+{
+    fn handle(&mut self, request: &Request<L>) -> Option<Response<L>> {
+        match *request {
+            Request::GetKeymapEntry { layer, row, col } => Some(
+                match self
+                    .mapping
+                    .get_key_action_mut(layer as usize, row as usize, col as usize)
+                {
+                    Some(action) => Response::KeymapEntry {
+                        layer,
+                        row,
+                        col,
+                        action: *action,
+                    },
+                    None => Response::Error(command::Error::OutOfBounds),
+                },
+            ),
+            Request::SetKeymapEntry {
+                layer,
+                row,
+                col,
+                action,
+            } => Some(
+                match self
+                    .mapping
+                    .get_key_action_mut(layer as usize, row as usize, col as usize)
+                {
+                    Some(entry) => {
+                        *entry = action;
+                        self.revision = self.revision.wrapping_add(1);
+                        Response::Ack
+                    }
+                    None => Response::Error(command::Error::OutOfBounds),
+                },
+            ),
+            Request::GetEncoderEntry { layer, direction } => Some(
+                match self
+                    .mapping
+                    .get_rotary_action_mut(layer as usize, direction)
+                {
+                    Some(action) => Response::EncoderEntry {
+                        layer,
+                        direction,
+                        action: *action,
+                    },
+                    None => Response::Error(command::Error::OutOfBounds),
+                },
+            ),
+            Request::SetEncoderEntry {
+                layer,
+                direction,
+                action,
+            } => Some(
+                match self
+                    .mapping
+                    .get_rotary_action_mut(layer as usize, direction)
+                {
+                    Some(entry) => {
+                        *entry = action;
+                        self.revision = self.revision.wrapping_add(1);
+                        Response::Ack
+                    }
+                    None => Response::Error(command::Error::OutOfBounds),
+                },
+            ),
+            Request::ResetKeymap => {
+                self.reset_mapping();
+                Some(Response::Ack)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{key::Key, matrix::Bit, processor::tests::Layer};
+
+    fn mapping(key: Key) -> InputMap<1, 1, 1, Layer> {
+        InputMap {
+            key_matrix: EnumMap::from_fn(|_| [[Action::Key(key)]]),
+            rotary_encoder: EnumMap::from_fn(|_| EnumMap::from_fn(|_| Action::None)),
+        }
+    }
+
+    fn map(mapper: &mut Mapper<1, 1, 1, Layer>, edge: Edge, pressed: bool) -> Vec<Action<Layer>> {
+        let mut input = Input::repeat(&MatrixResult::default(), 0);
+        input.key_matrix_result.matrix[0][0] = Bit { edge, pressed };
+        let mut events = Events::new();
+        mapper.map(&input, &mut events);
+        events.iter().map(|e| e.action).collect()
+    }
+
+    #[test]
+    fn held_key_keeps_action_across_mapping_change() {
+        let mut mapper = Mapper::new(mapping(Key::A));
+        assert_eq!(map(&mut mapper, Edge::Rising, true), [Action::Key(Key::A)]);
+        mapper.set_mapping(mapping(Key::B));
+        assert_eq!(map(&mut mapper, Edge::None, true), [Action::Key(Key::A)]);
+        assert_eq!(
+            map(&mut mapper, Edge::Falling, false),
+            [Action::Key(Key::A)]
+        );
+        assert_eq!(map(&mut mapper, Edge::Rising, true), [Action::Key(Key::B)]);
+    }
+
+    #[test]
+    fn repeat_keeps_keys_as_last_scanned_without_edges() {
+        let mut previous = MatrixResult::<1, 2>::default();
+        previous.scan_time_ticks = 5;
+        previous.matrix[0][0] = Bit {
+            edge: Edge::Rising,
+            pressed: true,
+        };
+        previous.matrix[0][1] = Bit {
+            edge: Edge::Falling,
+            pressed: false,
+        };
+        let input = Input::repeat(&previous, 9);
+        assert_eq!(input.key_matrix_result.scan_time_ticks, 9);
+        let bits = input.key_matrix_result.matrix[0].map(|bit| (bit.edge, bit.pressed));
+        assert_eq!(bits, [(Edge::None, true), (Edge::None, false)]);
+        assert!(!input.has_releases());
+    }
+}
@@ -1,13 +1,15 @@
 pub mod events;
 pub mod input;
 pub mod mapper;
+pub mod pipeline;
 
-use alloc::{
-    string::{String, ToString},
-    vec::Vec,
+use core::{
+    ops::{Deref, DerefMut},
+    result,
 };
-use core::{error, fmt, result};
+use defmt::Format;
 use mapper::Input;
+use serde::Serialize;
 
 use crate::{
     key::Edge,
@@ -15,6 +17,7 @@ use crate::{
 };
 
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub struct Event<L: LayerIndex> {
     pub time_ticks: u64,
     pub i: usize,
@@ -23,38 +26,222 @@ pub struct Event<L: LayerIndex> {
     pub action: Action<L>,
 }
 
+// Most events a single pass can carry. Every pressed key is an event on every pass, so this is
+// also the most keys that can be held at once.
+pub const EVENTS_CAPACITY: usize = 64;
+
+pub type Events<L> = heapless::Vec<Event<L>, EVENTS_CAPACITY>;
+
 pub trait InputProcessor<const KEY_MATRIX_ROW_COUNT: usize, const KEY_MATRIX_COL_COUNT: usize> {
     fn process(&mut self, input: &mut Input<KEY_MATRIX_ROW_COUNT, KEY_MATRIX_COL_COUNT>) -> Result;
 }
 
 pub trait EventsProcessor<L: LayerIndex> {
-    fn process(&mut self, events: &mut Vec<Event<L>>) -> Result;
+    fn process(&mut self, events: &mut EventQueue<L>) -> Result;
 }
 
-pub type Result = result::Result<(), Error>;
+// Events of one pass through the events processors. A pass runs for every scan, and also when
+// a deadline requested by a processor expires, in which case keys are reported as they were last
+// scanned. Processors may take events out to hold them, and put held or new events back in the
+// order the processors after them should see them.
+pub struct EventQueue<L: LayerIndex> {
+    now_ticks: u64,
+    events: Events<L>,
+    deadline_ticks: Option<u64>,
+}
+
+impl<L: LayerIndex> EventQueue<L> {
+    pub fn new(now_ticks: u64, events: Events<L>) -> Self {
+        EventQueue {
+            now_ticks,
+            events,
+            deadline_ticks: None,
+        }
+    }
 
-#[derive(Debug)]
-pub struct Error {
-    msg: String,
+    pub fn now_ticks(&self) -> u64 {
+        self.now_ticks
+    }
+
+    // Asks for another pass no later than `deadline_ticks`. Deadlines only last for one pass, so
+    // processors that still hold events have to ask again every time.
+    pub fn schedule(&mut self, deadline_ticks: u64) {
+        self.deadline_ticks = Some(match self.deadline_ticks {
+            Some(t) => t.min(deadline_ticks),
+            None => deadline_ticks,
+        });
+    }
+
+    pub fn deadline_ticks(&self) -> Option<u64> {
+        self.deadline_ticks
+    }
+}
+
+impl<L: LayerIndex> Deref for EventQueue<L> {
+    type Target = Events<L>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.events
+    }
+}
+
+impl<L: LayerIndex> DerefMut for EventQueue<L> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.events
+    }
+}
+
+// Events held back by a processor, each until its own deadline unless released earlier.
+pub struct HeldEvents<L: LayerIndex> {
+    held: heapless::Vec<(u64, Event<L>), EVENTS_CAPACITY>,
 }
 
 #[allow(dead_code)]
-impl Error {
-    pub fn new(msg: &str) -> Self {
-        Error {
-            msg: msg.to_string(),
+impl<L: LayerIndex> HeldEvents<L> {
+    pub fn new() -> Self {
+        HeldEvents {
+            held: heapless::Vec::new(),
         }
     }
-}
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.msg)
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    // Gives the event back if too many are held already.
+    pub fn hold(&mut self, event: Event<L>, deadline_ticks: u64) -> result::Result<(), Event<L>> {
+        self.held
+            .push((deadline_ticks, event))
+            .map_err(|(_, event)| event)
+    }
+
+    // Puts the events whose deadline passed back in front of the queue, as they happened before
+    // anything in it, and asks for a pass at the next deadline of the ones still held. Events
+    // that do not fit in the queue stay held until the next pass.
+    pub fn release_due(&mut self, events: &mut EventQueue<L>) {
+        let now_ticks = events.now_ticks();
+        self.release_where(events, |_, deadline_ticks| deadline_ticks <= now_ticks);
+        if let Some(deadline_ticks) = self.held.iter().map(|&(t, _)| t).min() {
+            events.schedule(deadline_ticks.max(now_ticks));
+        }
+    }
+
+    pub fn release_all(&mut self, events: &mut EventQueue<L>) {
+        self.release_where(events, |_, _| true);
+    }
+
+    // Releases the events picked by `is_due`, given each event and its deadline.
+    pub fn release_where(
+        &mut self,
+        events: &mut EventQueue<L>,
+        is_due: impl Fn(&Event<L>, u64) -> bool,
+    ) {
+        let mut index = 0;
+        self.held.retain(|&(deadline_ticks, event)| {
+            if !is_due(&event, deadline_ticks) || events.insert(index, event).is_err() {
+                return true;
+            }
+            index += 1;
+            false
+        });
     }
 }
 
-impl error::Error for Error {
-    fn description(&self) -> &str {
-        &self.msg
+pub type Result = result::Result<(), Error>;
+
+// How a processor failed. What happens to the pass then depends on the `ErrorPolicy` of its stage
+// in the pipeline.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Format, PartialEq, Serialize)]
+pub enum Error {
+    // Events or state did not fit in a fixed-capacity buffer
+    Overflow,
+    // The input is not what the processor was set up for
+    InvalidInput,
+    // The processor lost track of its own state
+    InvalidState,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use enum_map::Enum;
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::key::Key;
+
+    // Single layer for tests that need a `LayerIndex`.
+    #[derive(
+        Clone, Copy, Debug, Default, Deserialize, Enum, Format, PartialEq, PartialOrd, Serialize,
+    )]
+    pub(crate) enum Layer {
+        #[default]
+        Base,
+    }
+
+    impl LayerIndex for Layer {}
+
+    fn event(key: Key, time_ticks: u64) -> Event<Layer> {
+        Event {
+            time_ticks,
+            i: 0,
+            j: key as usize,
+            edge: Edge::Rising,
+            action: Action::Key(key),
+        }
+    }
+
+    fn keys(events: &EventQueue<Layer>) -> heapless::Vec<Key, EVENTS_CAPACITY> {
+        events
+            .iter()
+            .filter_map(|e| match e.action {
+                Action::Key(key) => Some(key),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn held_events_come_back_in_front_at_their_deadline() {
+        let mut held = HeldEvents::new();
+        held.hold(event(Key::A, 0), 20).ok().unwrap();
+        held.hold(event(Key::B, 0), 10).ok().unwrap();
+
+        // nothing due yet, a pass is asked for at the first deadline
+        let mut events = EventQueue::new(5, Events::new());
+        held.release_due(&mut events);
+        assert!(events.is_empty());
+        assert_eq!(events.deadline_ticks(), Some(10));
+
+        // the due one goes in front of what was scanned since, the other one is waited for
+        let mut events =
+            EventQueue::new(10, Events::from_slice(&[event(Key::C, 10)]).ok().unwrap());
+        held.release_due(&mut events);
+        assert_eq!(keys(&events), [Key::B, Key::C]);
+        assert_eq!(events.deadline_ticks(), Some(20));
+
+        let mut events = EventQueue::new(12, Events::new());
+        held.release_all(&mut events);
+        assert_eq!(keys(&events), [Key::A]);
+        assert!(held.is_empty());
+    }
+
+    #[test]
+    fn held_events_past_capacity_are_given_back() {
+        let mut held = HeldEvents::new();
+        for _ in 0..EVENTS_CAPACITY {
+            held.hold(event(Key::A, 0), 10).ok().unwrap();
+        }
+        assert!(held.hold(event(Key::B, 0), 10).is_err());
+    }
+
+    #[test]
+    fn schedule_keeps_the_earliest_deadline() {
+        let mut events = EventQueue::<Layer>::new(0, Events::new());
+        assert_eq!(events.deadline_ticks(), None);
+        events.schedule(30);
+        events.schedule(10);
+        events.schedule(20);
+        assert_eq!(events.deadline_ticks(), Some(10));
     }
 }
@@ -0,0 +1,226 @@
+use defmt::Format;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{self, Handler, Request, Response},
+    key::{Action, Control, Edge, Key, LayerIndex},
+    processor::{Error, EventQueue, EventsProcessor, Result},
+    storage::{Record, RecordKind},
+};
+
+// Input processors a keyboard can put in its chain. Their settings come from the matching
+// `Configurator` consts.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Format, PartialEq, Serialize)]
+pub enum InputStage {
+    Debounce,
+    AntiGhost,
+    Chatter,
+    Socd,
+}
+
+// Events processors a keyboard can put in its chain, the same one may appear several times.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Format, PartialEq, Serialize)]
+pub enum EventsStage {
+    RGB,
+    Profile,
+    System,
+    Socd,
+    HostMode,
+    // Releases non-modifier keys held for longer than this
+    HoldLimit {
+        max_hold_micros: u64,
+    },
+    KeyReplace {
+        from: Key,
+        to: Key,
+    },
+    // Sends `tap` when tapped, `hold` when held for longer than the term or along with another key
+    TapHold {
+        tap: Key,
+        hold: Key,
+        term_micros: u64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq, Serialize)]
+pub enum Stage {
+    Input(InputStage),
+    Events(EventsStage),
+}
+
+// What becomes of a pass when a processor fails. Keys released in a dropped pass would never be
+// reported released, so such a pass releases all keys instead.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Format, PartialEq, Serialize)]
+pub enum ErrorPolicy {
+    // Runs the rest of the chain as if the processor had succeeded
+    Skip,
+    DropScan,
+    // Drops the pass and releases all keys, held keys come back once pressed again
+    ReleaseAll,
+}
+
+#[derive(Clone, Copy, Debug, Default, Format, PartialEq, Serialize)]
+pub struct StageErrors {
+    pub count: u32,
+    pub last: Option<Error>,
+}
+
+// Most stages both chains can have together, one bit of `PipelineSettings::disabled` each.
+pub const STAGE_CAPACITY: usize = 32;
+
+// Stored along with the number of stages, so that settings saved for another chain are ignored.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PipelineSettings {
+    stage_count: u8,
+    disabled: u32,
+}
+
+impl Record for PipelineSettings {
+    const KIND: RecordKind = RecordKind::PipelineSettings;
+    const VERSION: u8 = 1;
+}
+
+// Keeps track of which stages of the input and events chains run, and how they failed. Stages are
+// numbered through the input chain first, then the events chain, and can be switched on and off
+// with `Control::ProcessorToggle` or the command interface. Each profile keeps its own toggles.
+pub struct Pipeline {
+    input_stages: &'static [InputStage],
+    events_stages: &'static [EventsStage],
+    error_policies: &'static [(usize, ErrorPolicy)],
+    disabled: u32,
+    errors: [StageErrors; STAGE_CAPACITY],
+}
+
+#[allow(dead_code)]
+impl Pipeline {
+    pub fn new(
+        input_stages: &'static [InputStage],
+        events_stages: &'static [EventsStage],
+        error_policies: &'static [(usize, ErrorPolicy)],
+    ) -> Self {
+        assert!(
+            input_stages.len() + events_stages.len() <= STAGE_CAPACITY,
+            "too many processors"
+        );
+        Pipeline {
+            input_stages,
+            events_stages,
+            error_policies,
+            disabled: 0,
+            errors: [StageErrors::default(); STAGE_CAPACITY],
+        }
+    }
+
+    pub fn get_stage_count(&self) -> usize {
+        self.input_stages.len() + self.events_stages.len()
+    }
+
+    pub fn get_stage(&self, index: usize) -> Option<Stage> {
+        match index.checked_sub(self.input_stages.len()) {
+            None => Some(Stage::Input(self.input_stages[index])),
+            Some(i) => self.events_stages.get(i).copied().map(Stage::Events),
+        }
+    }
+
+    pub fn is_enabled(&self, index: usize) -> bool {
+        self.disabled & 1 << index == 0
+    }
+
+    pub fn set_enabled(&mut self, index: usize, is_enabled: bool) -> bool {
+        if index >= self.get_stage_count() {
+            return false;
+        }
+        if is_enabled {
+            self.disabled &= !(1 << index);
+        } else {
+            self.disabled |= 1 << index;
+        }
+        true
+    }
+
+    pub fn get_error_policy(&self, index: usize) -> ErrorPolicy {
+        self.error_policies
+            .iter()
+            .find(|&&(i, _)| i == index)
+            .map_or(ErrorPolicy::DropScan, |&(_, policy)| policy)
+    }
+
+    // Counts the failure of a stage, and tells how to recover from it.
+    pub fn fail(&mut self, index: usize, error: Error) -> ErrorPolicy {
+        let policy = self.get_error_policy(index);
+        defmt::warn!("processor {} failed: {}, {}", index, error, policy);
+        let errors = &mut self.errors[index];
+        errors.count = errors.count.wrapping_add(1);
+        errors.last = Some(error);
+        policy
+    }
+
+    pub fn get_settings(&self) -> PipelineSettings {
+        PipelineSettings {
+            stage_count: self.get_stage_count() as u8,
+            disabled: self.disabled,
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: PipelineSettings) {
+        if settings.stage_count as usize != self.get_stage_count() {
+            defmt::warn!("processor chain changed, ignoring stored processor settings");
+            return;
+        }
+        self.disabled = settings.disabled;
+    }
+
+    pub fn reset_settings(&mut self) {
+        self.disabled = 0;
+    }
+}
+
+impl<L: LayerIndex> EventsProcessor<L> for Pipeline {
+    fn process(&mut self, events: &mut EventQueue<L>) -> Result {
+        events.iter().for_each(|e| {
+            if let (Edge::Rising, Action::Control(Control::ProcessorToggle(index))) =
+                (e.edge, e.action)
+            {
+                let index = index as usize;
+                if index < self.get_stage_count() {
+                    self.set_enabled(index, !self.is_enabled(index));
+                } else {
+                    defmt::warn!("processor {} does not exist", index);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl<L: LayerIndex> Handler<L> for Pipeline {
+    fn handle(&mut self, request: &Request<L>) -> Option<Response<L>> {
+        match *request {
+            Request::GetProcessor(index) => Some(match self.get_stage(index as usize) {
+                Some(stage) => Response::Processor {
+                    index,
+                    count: self.get_stage_count() as u8,
+                    stage,
+                    is_enabled: self.is_enabled(index as usize),
+                    errors: self.errors[index as usize],
+                },
+                None => Response::Error(command::Error::OutOfBounds),
+            }),
+            Request::SetProcessorEnabled { index, is_enabled } => {
+                Some(if self.set_enabled(index as usize, is_enabled) {
+                    Response::Ack
+                } else {
+                    Response::Error(command::Error::OutOfBounds)
+                })
+            }
+            Request::ResetProcessorErrors => {
+                self.errors = [StageErrors::default(); STAGE_CAPACITY];
+                Some(Response::Ack)
+            }
+            _ => None,
+        }
+    }
+}
@@ -1,31 +1,26 @@
 pub mod transport;
 
-use alloc::{boxed::Box, rc::Rc, vec::Vec};
-use async_trait::async_trait;
-use core::cell::{LazyCell, RefCell};
+use alloc::rc::Rc;
+use core::cell::RefCell;
 use defmt::Format;
 use rtic_monotonics::Monotonic;
 use rtic_sync::{arbiter::Arbiter, channel::Receiver};
-use serde::{Deserialize, Serialize};
-use transport::{Sequence, TransportSender};
+use serde::{de::DeserializeOwned, Serialize};
+use transport::{Payload, Sequence, TransportSender};
 
 use crate::{debug, kb::Mono};
 
 pub const REQUEST_SEQUENCE_QUEUE_SIZE: usize = 1;
 
-static SERVICE_REGISTRY: Arbiter<
-    LazyCell<Rc<RefCell<[Option<Box<dyn Service>>; ServiceId::MAX as usize + 1]>>>,
-> = Arbiter::new(LazyCell::new(|| {
-    Rc::new(RefCell::new([const { None }; ServiceId::MAX as usize + 1]))
-}));
+// Most services a server can hold.
+const SERVICES_CAPACITY: usize = 4;
 
 pub type ServiceId = u8;
 pub type MethodId = u8;
 
-#[async_trait(?Send)]
 pub trait Service {
     fn get_service_id(&self) -> ServiceId;
-    async fn dispatch(&mut self, method_id: MethodId, request: &[u8]) -> Result<Vec<u8>, Error>;
+    async fn dispatch(&mut self, method_id: MethodId, request: &[u8]) -> Result<Payload, Error>;
 }
 
 #[derive(Clone, Copy, Debug, Format, PartialEq)]
@@ -34,23 +29,35 @@ pub enum Error {
     MethodUnimplemented,
 }
 
-pub struct Server {
+// Serves requests with services of a single type, an enum of them if there are several kinds, so
+// that dispatching a request does not allocate.
+pub struct Server<S: Service> {
     seq_receiver: Receiver<'static, Sequence, REQUEST_SEQUENCE_QUEUE_SIZE>,
+    services: heapless::Vec<S, SERVICES_CAPACITY>,
 }
 
-impl Server {
+impl<S: Service> Server<S> {
     pub fn new(seq_receiver: Receiver<'static, Sequence, REQUEST_SEQUENCE_QUEUE_SIZE>) -> Self {
-        Server { seq_receiver }
+        Server {
+            seq_receiver,
+            services: heapless::Vec::new(),
+        }
     }
 
-    pub async fn register_service(&mut self, service: Box<dyn Service>) {
+    pub fn register_service(&mut self, service: S) {
         let service_id = service.get_service_id();
-        SERVICE_REGISTRY.access().await.as_ref().borrow_mut()[service_id as usize] = Some(service);
+        self.services.retain(|s| s.get_service_id() != service_id);
+        if self.services.push(service).is_err() {
+            defmt::error!(
+                "too many services, not registered: service_id={}",
+                service_id
+            );
+        }
     }
 
-    pub async fn listen<S>(&mut self, sender: &Arbiter<Rc<RefCell<S>>>)
+    pub async fn listen<T>(&mut self, sender: &Arbiter<Rc<RefCell<T>>>)
     where
-        S: TransportSender,
+        T: TransportSender,
     {
         while let Ok(sequence) = self.seq_receiver.recv().await {
             let start_time_listen = Mono::now();
@@ -59,7 +66,7 @@ impl Server {
 
             // retrieve request
             let start_time_retrieve = Mono::now();
-            let (service_id, method_id, req, mut respond) = match client.get_payload(sequence) {
+            let (service_id, method_id, req) = match client.take_request(sequence) {
                 Ok(r) => r,
                 Err(err) => {
                     defmt::error!("failed to retrieve request payload: {}", err);
@@ -75,10 +82,12 @@ impl Server {
 
             // dispatch
             let start_time_dispatch = Mono::now();
-            let res = match match SERVICE_REGISTRY.access().await.as_ref().borrow_mut()
-                [service_id as usize]
+            let res = match match self
+                .services
+                .iter_mut()
+                .find(|s| s.get_service_id() == service_id)
             {
-                Some(ref mut service) => service.dispatch(method_id, req).await,
+                Some(service) => service.dispatch(method_id, &req).await,
                 None => {
                     defmt::error!("service not implemented: service_id={}", service_id);
                     return;
@@ -99,7 +108,7 @@ impl Server {
 
             // return response
             let start_time_respond = Mono::now();
-            respond(&res);
+            client.send_response(sequence, service_id, method_id, &res);
             let end_time_respond = Mono::now();
             debug::log_duration(
                 debug::LogDurationTag::ServerListenRespond,
@@ -119,7 +128,7 @@ impl Server {
 }
 
 pub trait RemoteInvoker {
-    fn invoke<'b, Q, R>(
+    fn invoke<Q, R>(
         &mut self,
         service_id: ServiceId,
         method_id: MethodId,
@@ -127,5 +136,5 @@ pub trait RemoteInvoker {
     ) -> impl core::future::Future<Output = R>
     where
         Q: Serialize,
-        R: Deserialize<'b>;
+        R: DeserializeOwned;
 }
@@ -1,6 +1,5 @@
 pub mod uart;
 
-use alloc::vec::Vec;
 use core::future::Future;
 use defmt::Format;
 use rtic_sync::channel::Receiver;
@@ -17,6 +16,12 @@ enum Kind {
 
 pub type Sequence = u8;
 
+// Largest payload a packet can carry, leaving room for the header and COBS framing in a frame
+// whose length has to fit in its first byte.
+pub const PAYLOAD_CAPACITY: usize = 240;
+
+pub type Payload = heapless::Vec<u8, PAYLOAD_CAPACITY>;
+
 #[derive(Clone, Copy, Debug, Deserialize, Format, Serialize)]
 pub struct Packet<'a> {
     kind: Kind,
@@ -62,12 +67,17 @@ pub trait TransportSender {
         method_id: super::MethodId,
         payload: &[u8],
     ) -> Sequence;
-    fn get_payload(
+    fn take_request(&mut self, sequence: Sequence)
+        -> Result<(ServiceId, MethodId, Payload), Error>;
+    fn send_response(
         &mut self,
         sequence: Sequence,
-    ) -> Result<(ServiceId, MethodId, &[u8], impl FnMut(&[u8])), Error>;
+        service_id: ServiceId,
+        method_id: MethodId,
+        payload: &[u8],
+    );
     fn receive_response_poll(&mut self, sequence: Sequence)
-        -> impl Future<Output = Vec<u8>> + Send;
+        -> impl Future<Output = Payload> + Send;
 }
 
 #[derive(Clone, Copy, Debug, Format, PartialEq)]
@@ -1,6 +1,5 @@
 use core::cell::RefCell;
 
-use alloc::{borrow::ToOwned, boxed::Box, vec::Vec};
 use cortex_m::interrupt::Mutex;
 use embedded_io::Write;
 use hal::{
@@ -9,7 +8,7 @@ use hal::{
 };
 use rtic_monotonics::{rp2040::prelude::*, Monotonic};
 use rtic_sync::channel::{Receiver, Sender};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
     debug,
@@ -17,12 +16,35 @@ use crate::{
     remote::{transport::Sequence, MethodId, RemoteInvoker, ServiceId},
 };
 
-use super::{Kind, Packet, TransportReceiver, TransportSender};
+use super::{Kind, Packet, Payload, TransportReceiver, TransportSender, PAYLOAD_CAPACITY};
 
 const UART_FRAME_BUFFER_SIZE_BYTES: usize = 256;
 
-static PACKET_BUFFER: Mutex<RefCell<[Option<Packet>; Sequence::MAX as usize + 1]>> =
-    Mutex::new(RefCell::new([None; Sequence::MAX as usize + 1]));
+// Received packets wait here until they are taken, in the slot of their sequence modulo the pool
+// size. Only a request or two are ever in flight, so a packet is only overwritten once its
+// sequence wrapped around the pool, the same as when every sequence had its own slot.
+const PACKET_POOL_SIZE: usize = 4;
+
+struct StoredPacket {
+    kind: Kind,
+    sequence: Sequence,
+    service_id: ServiceId,
+    method_id: MethodId,
+    payload: Payload,
+}
+
+static PACKET_POOL: Mutex<RefCell<[Option<StoredPacket>; PACKET_POOL_SIZE]>> =
+    Mutex::new(RefCell::new([const { None }; PACKET_POOL_SIZE]));
+
+fn take_packet(sequence: Sequence, kind: Kind) -> Option<StoredPacket> {
+    cortex_m::interrupt::free(|cs| {
+        let slot = &mut PACKET_POOL.borrow(cs).borrow_mut()[sequence as usize % PACKET_POOL_SIZE];
+        match slot {
+            Some(packet) if packet.sequence == sequence && packet.kind == kind => slot.take(),
+            _ => None,
+        }
+    })
+}
 
 pub struct UartReceiver {
     uart_reader: Reader<
@@ -66,12 +88,11 @@ impl TransportReceiver for UartReceiver {
     fn read_into_buffer(&mut self) {
         let start_time_read = Mono::now();
         let mut buffer = [0u8; UART_FRAME_BUFFER_SIZE_BYTES];
-        let mut offset = 0;
 
-        match self.uart_reader.read_raw(&mut buffer[offset..]) {
-            Ok(n) => {
-                offset += n;
-            }
+        // A frame is its length byte followed by that many bytes of COBS, terminator included.
+        // Only the length is read first, so that no byte of the next frame is taken along.
+        match self.uart_reader.read_raw(&mut buffer[..1]) {
+            Ok(_) => {}
             Err(_) => return, // fails on first read, drop
         }
 
@@ -79,15 +100,18 @@ impl TransportReceiver for UartReceiver {
         if expected_length == 0 {
             return;
         }
-        if expected_length > buffer.len() {
+        let frame_length = expected_length + 1;
+        if frame_length > buffer.len() {
             defmt::error!(
                 "expected packet overflows buffer: expected_len={}",
                 expected_length
-            )
+            );
+            return;
         }
 
-        while offset < expected_length {
-            match self.uart_reader.read_raw(&mut buffer[offset..]) {
+        let mut offset = 1;
+        while offset < frame_length {
+            match self.uart_reader.read_raw(&mut buffer[offset..frame_length]) {
                 Ok(n) => {
                     offset += n;
                 }
@@ -95,9 +119,6 @@ impl TransportReceiver for UartReceiver {
                 Err(_) => return, // unhandled
             }
         }
-        if offset == 0 {
-            return;
-        }
         let end_time_read = Mono::now();
         debug::log_duration(
             debug::LogDurationTag::UARTReceiverReadRead,
@@ -106,8 +127,13 @@ impl TransportReceiver for UartReceiver {
         );
 
         let start_time_deserialize = Mono::now();
-        let packet: Packet =
-            postcard::from_bytes_cobs(&mut buffer[1..expected_length + 1]).unwrap();
+        let packet: Packet = match postcard::from_bytes_cobs(&mut buffer[1..frame_length]) {
+            Ok(packet) => packet,
+            Err(_) => {
+                defmt::error!("dropping corrupted packet: len={}", expected_length);
+                return;
+            }
+        };
         let end_time_deserialize = Mono::now();
         debug::log_duration(
             debug::LogDurationTag::UARTReceiverReadDeserialize,
@@ -116,20 +142,23 @@ impl TransportReceiver for UartReceiver {
         );
 
         let start_time_buffer = Mono::now();
+        let Ok(payload) = Payload::from_slice(packet.payload) else {
+            defmt::error!(
+                "packet payload overflows pool slot: seq={} len={}",
+                packet.sequence,
+                packet.payload.len()
+            );
+            return;
+        };
         cortex_m::interrupt::free(|cs| {
-            if let Some(ref mut old_packet) =
-                PACKET_BUFFER.borrow(cs).borrow_mut()[packet.sequence as usize]
-            {
-                // defmt::warn!("dropping on wrap: size={}B", mem::size_of_val(old_packet));
-                drop(unsafe { Box::from_raw(old_packet.payload as *const [u8] as *mut [u8]) });
-            }
-            PACKET_BUFFER.borrow(cs).borrow_mut()[packet.sequence as usize] = Some(Packet {
-                kind: packet.kind,
-                sequence: packet.sequence,
-                service_id: packet.service_id,
-                method_id: packet.method_id,
-                payload: Box::leak(packet.payload.to_owned().into_boxed_slice()),
-            });
+            PACKET_POOL.borrow(cs).borrow_mut()[packet.sequence as usize % PACKET_POOL_SIZE] =
+                Some(StoredPacket {
+                    kind: packet.kind,
+                    sequence: packet.sequence,
+                    service_id: packet.service_id,
+                    method_id: packet.method_id,
+                    payload,
+                });
         });
         let end_time_buffer = Mono::now();
         debug::log_duration(
@@ -185,7 +214,10 @@ impl UartSender {
 
     fn send(&mut self, packet: &Packet) {
         let start_time_serialize = Mono::now();
-        let packet = postcard::to_allocvec_cobs(packet).unwrap();
+        let mut buffer = [0u8; UART_FRAME_BUFFER_SIZE_BYTES];
+        let length = postcard::to_slice_cobs(packet, &mut buffer[1..])
+            .unwrap()
+            .len();
         let end_time_serialize = Mono::now();
         debug::log_duration(
             debug::LogDurationTag::UARTSenderSendSerialize,
@@ -194,13 +226,11 @@ impl UartSender {
         );
 
         let start_time_transform = Mono::now();
-        let mut buffer = Vec::with_capacity(packet.len() + 1);
-        buffer.push(packet.len() as u8);
+        buffer[0] = length as u8;
         debug::log_string(
             debug::LogStringTag::PacketLength,
-            format!("{} bytes", packet.len()),
+            format_args!("{} bytes", length),
         );
-        buffer.extend_from_slice(&packet);
         let end_time_transform = Mono::now();
         debug::log_duration(
             debug::LogDurationTag::UARTSenderSendTransform,
@@ -209,7 +239,7 @@ impl UartSender {
         );
 
         let start_time_write = Mono::now();
-        self.uart_writer.write_all(&buffer).unwrap();
+        self.uart_writer.write_all(&buffer[..length + 1]).unwrap();
         let end_time_write = Mono::now();
         debug::log_duration(
             debug::LogDurationTag::UARTSenderSendWrite,
@@ -217,22 +247,6 @@ impl UartSender {
             end_time_write,
         );
     }
-
-    fn send_response(
-        &mut self,
-        sequence: Sequence,
-        service_id: ServiceId,
-        method_id: MethodId,
-        payload: &[u8],
-    ) {
-        self.send(&Packet {
-            kind: Kind::Response,
-            sequence,
-            service_id,
-            method_id,
-            payload,
-        })
-    }
 }
 
 impl TransportSender for UartSender {
@@ -253,74 +267,57 @@ impl TransportSender for UartSender {
         sequence
     }
 
-    fn get_payload(
+    fn take_request(
         &mut self,
         sequence: Sequence,
-    ) -> Result<(ServiceId, MethodId, &[u8], impl FnMut(&[u8])), super::Error> {
-        let packet = match cortex_m::interrupt::free(|cs| {
-            PACKET_BUFFER.borrow(cs).borrow()[sequence as usize]
-        }) {
-            Some(packet) => {
-                // TODO: dropping here allows the freeing of heap early, but this somehow breaks packet.payload
-                // defmt::warn!(
-                //     "dropping on listen: size={}B",
-                //     mem::size_of_val(packet.payload)
-                // );
-                // drop(unsafe { Box::from_raw(packet.payload as *const [u8] as *mut [u8]) });
-                packet
-            }
+    ) -> Result<(ServiceId, MethodId, Payload), super::Error> {
+        match take_packet(sequence, Kind::Request) {
+            Some(packet) => Ok((packet.service_id, packet.method_id, packet.payload)),
             None => {
                 defmt::error!("packet not found in buffer: seq={}", sequence);
-                return Result::Err(super::Error::MissingPacketBuffer);
+                Err(super::Error::MissingPacketBuffer)
             }
-        };
+        }
+    }
 
-        Result::Ok((
-            packet.service_id,
-            packet.method_id,
-            packet.payload,
-            move |res: &[u8]| {
-                self.send_response(sequence, packet.service_id, packet.method_id, res)
-            },
-        ))
+    fn send_response(
+        &mut self,
+        sequence: Sequence,
+        service_id: ServiceId,
+        method_id: MethodId,
+        payload: &[u8],
+    ) {
+        self.send(&Packet {
+            kind: Kind::Response,
+            sequence,
+            service_id,
+            method_id,
+            payload,
+        })
     }
 
-    async fn receive_response_poll(&mut self, seq: Sequence) -> Vec<u8> {
-        let stored_payload = loop {
-            if let Some(response) = cortex_m::interrupt::free(|cs| {
-                PACKET_BUFFER.borrow(cs).borrow_mut()[seq as usize].take()
-            }) {
-                break response.payload;
+    async fn receive_response_poll(&mut self, seq: Sequence) -> Payload {
+        loop {
+            if let Some(response) = take_packet(seq, Kind::Response) {
+                return response.payload;
             }
             Mono::delay(50.micros()).await;
-        };
-
-        let payload = stored_payload.to_owned();
-        drop(unsafe { Box::from_raw(stored_payload as *const [u8] as *mut [u8]) });
-        payload
+        }
     }
 }
 
 impl RemoteInvoker for UartSender {
-    async fn invoke<'b, Q, R>(
-        &mut self,
-        service_id: ServiceId,
-        method_id: MethodId,
-        request: Q,
-    ) -> R
+    async fn invoke<Q, R>(&mut self, service_id: ServiceId, method_id: MethodId, request: Q) -> R
     where
         Q: Serialize,
-        R: Deserialize<'b>,
+        R: DeserializeOwned,
     {
-        let mut request_buffer = [0u8; UART_FRAME_BUFFER_SIZE_BYTES];
+        let mut request_buffer = [0u8; PAYLOAD_CAPACITY];
         let request_payload = postcard::to_slice(&request, &mut request_buffer).unwrap();
 
         let seq = self.send_request(service_id, method_id, request_payload);
 
-        let response_buffer = self.receive_response_poll(seq).await.leak();
-        let response_payload = postcard::from_bytes(response_buffer).unwrap();
-        drop(unsafe { Box::from_raw(response_buffer as *const [u8] as *mut [u8]) });
-
-        response_payload
+        let response_payload = self.receive_response_poll(seq).await;
+        postcard::from_bytes(&response_payload).unwrap()
     }
 }
@@ -0,0 +1,408 @@
+use heapless::Vec;
+
+use crate::{
+    key::{Action, Edge, Key, LayerIndex, ModifiedKey},
+    processor::{Event, EVENTS_CAPACITY},
+};
+
+// Every key an event queue can hold, plus the modifiers of a `ModifiedKey`.
+pub const REPORT_CAPACITY: usize = EVENTS_CAPACITY + 8;
+// Most reports a single update returns. Events that would not fit are left for the next update.
+pub const REPORTS_CAPACITY: usize = 8;
+// Most reports one event adds: a flush on conflict, the held back modifier releases before and
+// after them, and the swap to the modifiers of a `ModifiedKey`.
+const EVENT_REPORTS_MAX: usize = 4;
+// Most reports added once events stop, for the remaining changes and modifier releases.
+const FLUSH_REPORTS_MAX: usize = 2;
+
+pub type Report = Vec<Key, REPORT_CAPACITY>;
+
+// Keys the host sees as pressed, kept from the Rising and Falling edges of key events. Each
+// update turns one pass of events into as many reports as it takes for the host to see them in
+// order:
+//  - a key that changes twice in a pass is reported in between, so taps are not lost,
+//  - newly pressed modifiers are reported before keys pressed along with them,
+//  - released modifiers are reported after keys released along with them.
+//
+// The modifiers of a `ModifiedKey` are weak: they replace the held modifiers only while it is the
+// last key pressed, and are reported on their own before the key goes down and after it goes up,
+// so they never leak into other keystrokes.
+pub struct ReportState {
+    // Pressed keys in press order, with the number of events holding each of them
+    pressed: Vec<(Key, u8), EVENTS_CAPACITY>,
+    weak: Option<ModifiedKey>,
+    needs_resync: bool,
+}
+
+impl ReportState {
+    pub fn new() -> Self {
+        ReportState {
+            pressed: Vec::new(),
+            weak: None,
+            needs_resync: false,
+        }
+    }
+
+    // Returns the reports to send, oldest first, or nothing if the pressed keys did not change,
+    // along with how many of the events they cover. The rest are to be passed again once the
+    // reports are sent, so that no change is lost.
+    pub fn update<L: LayerIndex>(
+        &mut self,
+        events: &[Event<L>],
+    ) -> (Vec<Report, REPORTS_CAPACITY>, usize) {
+        let mut reports = Vec::new();
+        let mut changes: Vec<(Key, Edge), EVENTS_CAPACITY> = Vec::new();
+        let mut modifier_releases: Vec<Key, EVENTS_CAPACITY> = Vec::new();
+        for (index, event) in events.iter().enumerate() {
+            if reports.len() + EVENT_REPORTS_MAX + FLUSH_REPORTS_MAX > REPORTS_CAPACITY {
+                if !modifier_releases.is_empty() {
+                    self.release_modifiers(&mut reports, &mut changes, &mut modifier_releases);
+                } else if !changes.is_empty() {
+                    push_report(&mut reports, self.keys());
+                }
+                return (reports, index);
+            }
+            let (key, weak) = match event.action {
+                Action::Key(key) => (key, None),
+                Action::ModifiedKey(mk) => (mk.get_key(), Some(mk)),
+                _ => continue,
+            };
+            match event.edge {
+                Edge::None => continue,
+                Edge::Falling if key.is_modifier() && weak.is_none() => {
+                    modifier_releases.push(key).ok();
+                    continue;
+                }
+                // A later press must not be typed with the modifiers released before it
+                Edge::Rising if !modifier_releases.is_empty() => {
+                    self.release_modifiers(&mut reports, &mut changes, &mut modifier_releases);
+                }
+                _ => {}
+            }
+            let is_conflicting = changes.iter().any(|&(k, edge)| {
+                k == key
+                    || (event.edge == Edge::Rising
+                        && edge == Edge::Rising
+                        && k.is_modifier()
+                        && !key.is_modifier())
+            });
+            if is_conflicting {
+                push_report(&mut reports, self.keys());
+                changes.clear();
+            }
+
+            match event.edge {
+                Edge::Rising if weak.is_some() => {
+                    // Swap the modifiers in before the key
+                    let previous = self.keys();
+                    self.weak = weak;
+                    let swapped = self.keys();
+                    if swapped != previous {
+                        push_report(&mut reports, swapped);
+                        changes.clear();
+                    }
+                    self.apply(key, Edge::Rising);
+                }
+                Edge::Rising => {
+                    if !key.is_modifier() {
+                        self.weak = None;
+                    }
+                    self.apply(key, Edge::Rising);
+                    // Keys pressed earlier in the pass wait for the modifier
+                    if key.is_modifier()
+                        && changes
+                            .iter()
+                            .any(|&(k, edge)| edge == Edge::Rising && !k.is_modifier())
+                    {
+                        let report = self
+                            .keys()
+                            .iter()
+                            .copied()
+                            .filter(|&k| k.is_modifier() || !changes.contains(&(k, Edge::Rising)))
+                            .collect();
+                        push_report(&mut reports, report);
+                    }
+                }
+                _ if weak.is_some() && self.weak == weak => {
+                    // Release the key before its modifiers are swapped out
+                    self.apply(key, Edge::Falling);
+                    let released = self.keys();
+                    self.weak = None;
+                    let is_swapped = self.keys() != released;
+                    push_report(&mut reports, released);
+                    changes.clear();
+                    if !is_swapped {
+                        continue;
+                    }
+                }
+                _ => self.apply(key, Edge::Falling),
+            }
+            changes.push((key, event.edge)).ok();
+        }
+
+        if !modifier_releases.is_empty() {
+            self.release_modifiers(&mut reports, &mut changes, &mut modifier_releases);
+        } else if !changes.is_empty() || self.needs_resync {
+            push_report(&mut reports, self.keys());
+        }
+        self.needs_resync = false;
+        (reports, events.len())
+    }
+
+    // Releases modifiers after the other changes so far, so that keys released with them are
+    // not typed unmodified.
+    fn release_modifiers(
+        &mut self,
+        reports: &mut Vec<Report, REPORTS_CAPACITY>,
+        changes: &mut Vec<(Key, Edge), EVENTS_CAPACITY>,
+        modifier_releases: &mut Vec<Key, EVENTS_CAPACITY>,
+    ) {
+        if !changes.is_empty() {
+            push_report(reports, self.keys());
+        }
+        modifier_releases
+            .iter()
+            .for_each(|&key| self.apply(key, Edge::Falling));
+        push_report(reports, self.keys());
+        changes.clear();
+        modifier_releases.clear();
+    }
+
+    // Forgets every pressed key, so that the next update reports all of them released. Keys that
+    // are still held are only reported again once pressed again.
+    pub fn release_all(&mut self) {
+        self.pressed.clear();
+        self.weak = None;
+        self.needs_resync = true;
+    }
+
+    // Some reports could not be sent, so the next update has to send the whole state again.
+    pub fn invalidate(&mut self) {
+        self.needs_resync = true;
+    }
+
+    fn keys(&self) -> Report {
+        let Some(weak) = self.weak else {
+            return self.pressed.iter().map(|&(key, _)| key).collect();
+        };
+        // Held modifiers are masked by the weak ones
+        self.pressed
+            .iter()
+            .map(|&(key, _)| key)
+            .filter(|&key| !key.is_modifier() || key == weak.get_key())
+            .chain(weak.get_modifiers().iter().filter_map(|m| m.to_key()))
+            .collect()
+    }
+
+    fn apply(&mut self, key: Key, edge: Edge) {
+        let index = self.pressed.iter().position(|&(k, _)| k == key);
+        match (edge, index) {
+            (Edge::Rising, Some(index)) => {
+                self.pressed[index].1 = self.pressed[index].1.saturating_add(1)
+            }
+            (Edge::Rising, None) => {
+                self.pressed.push((key, 1)).ok();
+            }
+            (Edge::Falling, Some(index)) => {
+                self.pressed[index].1 -= 1;
+                if self.pressed[index].1 == 0 {
+                    self.pressed.remove(index);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn push_report(reports: &mut Vec<Report, REPORTS_CAPACITY>, report: Report) {
+    // Updates stop early enough to leave room for every report
+    reports.push(report).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{key::Modifier, processor::tests::Layer};
+
+    fn event(j: usize, action: Action<Layer>, edge: Edge) -> Event<Layer> {
+        Event {
+            time_ticks: 0,
+            i: 0,
+            j,
+            edge,
+            action,
+        }
+    }
+
+    fn key(j: usize, key: Key, edge: Edge) -> Event<Layer> {
+        event(j, Action::Key(key), edge)
+    }
+
+    // Passes the events again from where the last update stopped, as the report loop does.
+    fn run(
+        state: &mut ReportState,
+        events: &[Event<Layer>],
+    ) -> alloc::vec::Vec<alloc::vec::Vec<Key>> {
+        let mut sent = alloc::vec::Vec::new();
+        let mut pending = events;
+        loop {
+            let (reports, count) = state.update(pending);
+            sent.extend(reports.iter().map(|report| report.to_vec()));
+            pending = &pending[count..];
+            if pending.is_empty() {
+                return sent;
+            }
+        }
+    }
+
+    #[test]
+    fn tap_within_one_pass_is_reported() {
+        let mut state = ReportState::new();
+        let sent = run(
+            &mut state,
+            &[key(0, Key::A, Edge::Rising), key(0, Key::A, Edge::Falling)],
+        );
+        assert_eq!(sent, vec![vec![Key::A], vec![]]);
+    }
+
+    #[test]
+    fn modifier_is_pressed_first_and_released_last() {
+        let mut state = ReportState::new();
+        let sent = run(
+            &mut state,
+            &[
+                key(0, Key::A, Edge::Rising),
+                key(1, Key::LeftShift, Edge::Rising),
+            ],
+        );
+        assert_eq!(
+            sent,
+            vec![vec![Key::LeftShift], vec![Key::A, Key::LeftShift]]
+        );
+
+        let sent = run(
+            &mut state,
+            &[
+                key(0, Key::A, Edge::None),
+                key(1, Key::LeftShift, Edge::None),
+            ],
+        );
+        assert!(sent.is_empty());
+
+        let sent = run(
+            &mut state,
+            &[
+                key(1, Key::LeftShift, Edge::Falling),
+                key(0, Key::A, Edge::Falling),
+            ],
+        );
+        assert_eq!(sent, vec![vec![Key::LeftShift], vec![]]);
+    }
+
+    #[test]
+    fn key_held_by_two_events_stays_pressed_until_both_release() {
+        let mut state = ReportState::new();
+        let sent = run(
+            &mut state,
+            &[key(0, Key::A, Edge::Rising), key(1, Key::A, Edge::Rising)],
+        );
+        assert_eq!(sent.last(), Some(&vec![Key::A]));
+
+        let sent = run(
+            &mut state,
+            &[key(0, Key::A, Edge::Falling), key(1, Key::A, Edge::None)],
+        );
+        assert_eq!(sent.last(), Some(&vec![Key::A]));
+
+        let sent = run(&mut state, &[key(1, Key::A, Edge::Falling)]);
+        assert_eq!(sent, vec![vec![]]);
+    }
+
+    #[test]
+    fn weak_modifiers_are_reported_alone_around_their_key() {
+        let mut state = ReportState::new();
+        let sent = run(
+            &mut state,
+            &[event(0, Action::ModifiedKey(LC!(Key::A)), Edge::Rising)],
+        );
+        assert_eq!(
+            sent,
+            vec![vec![Key::LeftControl], vec![Key::A, Key::LeftControl]]
+        );
+
+        let sent = run(
+            &mut state,
+            &[event(0, Action::ModifiedKey(LC!(Key::A)), Edge::Falling)],
+        );
+        assert_eq!(sent, vec![vec![Key::LeftControl], vec![]]);
+    }
+
+    #[test]
+    fn weak_modifiers_mask_held_ones_while_their_key_is_down() {
+        let mut state = ReportState::new();
+        run(&mut state, &[key(0, Key::LeftShift, Edge::Rising)]);
+
+        let sent = run(
+            &mut state,
+            &[
+                key(0, Key::LeftShift, Edge::None),
+                event(1, Action::ModifiedKey(LC!(Key::A)), Edge::Rising),
+            ],
+        );
+        assert_eq!(
+            sent,
+            vec![vec![Key::LeftControl], vec![Key::A, Key::LeftControl]]
+        );
+
+        let sent = run(
+            &mut state,
+            &[
+                key(0, Key::LeftShift, Edge::None),
+                event(1, Action::ModifiedKey(LC!(Key::A)), Edge::Falling),
+            ],
+        );
+        assert_eq!(sent, vec![vec![Key::LeftControl], vec![Key::LeftShift]]);
+    }
+
+    #[test]
+    fn plain_key_drops_weak_modifiers() {
+        let mut state = ReportState::new();
+        run(
+            &mut state,
+            &[
+                key(0, Key::LeftShift, Edge::Rising),
+                event(1, Action::ModifiedKey(LC!(Key::A)), Edge::Rising),
+            ],
+        );
+
+        let sent = run(
+            &mut state,
+            &[
+                key(0, Key::LeftShift, Edge::None),
+                event(1, Action::ModifiedKey(LC!(Key::A)), Edge::None),
+                key(2, Key::B, Edge::Rising),
+            ],
+        );
+        assert_eq!(sent, vec![vec![Key::LeftShift, Key::A, Key::B]]);
+    }
+
+    #[test]
+    fn reports_past_capacity_are_left_for_the_next_update() {
+        const TAP_COUNT: usize = REPORTS_CAPACITY;
+        let events: alloc::vec::Vec<_> = (0..TAP_COUNT)
+            .flat_map(|_| [key(0, Key::A, Edge::Rising), key(0, Key::A, Edge::Falling)])
+            .collect();
+
+        let mut state = ReportState::new();
+        let (reports, count) = state.update(&events);
+        assert!(count < events.len());
+        assert!(reports.len() <= REPORTS_CAPACITY);
+
+        let mut state = ReportState::new();
+        let sent = run(&mut state, &events);
+        let expected: alloc::vec::Vec<_> = (0..TAP_COUNT)
+            .flat_map(|_| [vec![Key::A], vec![]])
+            .collect();
+        assert_eq!(sent, expected);
+    }
+}
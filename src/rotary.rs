@@ -4,6 +4,7 @@ use embedded_hal::digital::{InputPin, OutputPin};
 use enum_map::Enum;
 use hal::gpio;
 use rtic_monotonics::Monotonic;
+use serde::{Deserialize, Serialize};
 
 use crate::{kb::Mono, key::Edge};
 
@@ -31,7 +32,7 @@ pub enum Mode {
     DentHighPrecision,
 }
 
-#[derive(Clone, Copy, Debug, Format, PartialEq, Enum)]
+#[derive(Clone, Copy, Debug, Deserialize, Format, PartialEq, Enum, Serialize)]
 pub enum Direction {
     None,
     Clockwise,
@@ -0,0 +1,203 @@
+#[cfg(test)]
+use alloc::{vec, vec::Vec};
+use hal::rom_data;
+
+use super::{Error, Result};
+
+pub const PAGE_SIZE_BYTES: usize = 256;
+pub const SECTOR_SIZE_BYTES: usize = 4096;
+
+pub trait Flash {
+    fn capacity(&self) -> usize;
+
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<()>;
+
+    // Offset and length must be sector aligned.
+    fn erase(&mut self, offset: usize, length: usize) -> Result<()>;
+
+    // Offset and length must be page aligned, programming can only clear bits.
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<()>;
+}
+
+fn check_range(capacity: usize, offset: usize, length: usize, alignment: usize) -> Result<()> {
+    if offset % alignment != 0 || length % alignment != 0 {
+        return Err(Error::Unaligned);
+    }
+    if offset + length > capacity {
+        return Err(Error::OutOfBounds);
+    }
+    Ok(())
+}
+
+// Must match the STORAGE region reserved in memory.x.
+const FLASH_SIZE_BYTES: usize = 2048 * 1024;
+const STORAGE_SIZE_BYTES: usize = 64 * 1024;
+const STORAGE_OFFSET_BYTES: usize = FLASH_SIZE_BYTES - STORAGE_SIZE_BYTES;
+const XIP_BASE: usize = 0x1000_0000;
+const BOOT2_SIZE_WORDS: usize = 64;
+
+const SECTOR_ERASE_COMMAND: u8 = 0x20;
+
+struct RomFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+    // boot2 copied into RAM, restores the fast XIP setup instead of flash_enter_cmd_xip
+    enter_xip: unsafe extern "C" fn(),
+}
+
+pub struct Rp2040Flash {
+    boot2: [u32; BOOT2_SIZE_WORDS],
+}
+
+impl Rp2040Flash {
+    pub fn new() -> Self {
+        let mut boot2 = [0u32; BOOT2_SIZE_WORDS];
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                XIP_BASE as *const u32,
+                boot2.as_mut_ptr(),
+                BOOT2_SIZE_WORDS,
+            )
+        };
+        Rp2040Flash { boot2 }
+    }
+
+    fn rom_functions(&self) -> RomFunctions {
+        RomFunctions {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+            // +1 to stay in thumb mode
+            enter_xip: unsafe {
+                core::mem::transmute::<*const u8, unsafe extern "C" fn()>(
+                    (self.boot2.as_ptr() as *const u8).add(1),
+                )
+            },
+        }
+    }
+
+    // Flash is inaccessible while erasing or programming, so this runs from RAM with interrupts
+    // disabled. Nothing in here may call into code that lives in flash.
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    unsafe fn write(
+        functions: &RomFunctions,
+        offset: u32,
+        data: *const u8,
+        length: usize,
+        erase: bool,
+    ) {
+        (functions.connect_internal_flash)();
+        (functions.flash_exit_xip)();
+        if erase {
+            (functions.flash_range_erase)(
+                offset,
+                length,
+                SECTOR_SIZE_BYTES as u32,
+                SECTOR_ERASE_COMMAND,
+            );
+        } else {
+            (functions.flash_range_program)(offset, data, length);
+        }
+        (functions.flash_flush_cache)();
+        (functions.enter_xip)();
+    }
+}
+
+impl Flash for Rp2040Flash {
+    fn capacity(&self) -> usize {
+        STORAGE_SIZE_BYTES
+    }
+
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<()> {
+        check_range(self.capacity(), offset, buffer.len(), 1)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (XIP_BASE + STORAGE_OFFSET_BYTES + offset) as *const u8,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+            )
+        };
+        Ok(())
+    }
+
+    fn erase(&mut self, offset: usize, length: usize) -> Result<()> {
+        check_range(self.capacity(), offset, length, SECTOR_SIZE_BYTES)?;
+        let functions = self.rom_functions();
+        cortex_m::interrupt::free(|_| unsafe {
+            Self::write(
+                &functions,
+                (STORAGE_OFFSET_BYTES + offset) as u32,
+                core::ptr::null(),
+                length,
+                true,
+            )
+        });
+        Ok(())
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        check_range(self.capacity(), offset, data.len(), PAGE_SIZE_BYTES)?;
+        // the source buffer must not live in flash either
+        let data = data.to_vec();
+        let functions = self.rom_functions();
+        cortex_m::interrupt::free(|_| unsafe {
+            Self::write(
+                &functions,
+                (STORAGE_OFFSET_BYTES + offset) as u32,
+                data.as_ptr(),
+                data.len(),
+                false,
+            )
+        });
+        Ok(())
+    }
+}
+
+// In-memory flash with NOR semantics, to run the store off-target.
+#[cfg(test)]
+pub struct MemoryFlash {
+    data: Vec<u8>,
+}
+
+#[cfg(test)]
+impl MemoryFlash {
+    pub fn new(capacity: usize) -> Self {
+        MemoryFlash {
+            data: vec![0xFF; capacity],
+        }
+    }
+}
+
+#[cfg(test)]
+impl Flash for MemoryFlash {
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<()> {
+        check_range(self.capacity(), offset, buffer.len(), 1)?;
+        buffer.copy_from_slice(&self.data[offset..offset + buffer.len()]);
+        Ok(())
+    }
+
+    fn erase(&mut self, offset: usize, length: usize) -> Result<()> {
+        check_range(self.capacity(), offset, length, SECTOR_SIZE_BYTES)?;
+        self.data[offset..offset + length].fill(0xFF);
+        Ok(())
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        check_range(self.capacity(), offset, data.len(), PAGE_SIZE_BYTES)?;
+        self.data[offset..offset + data.len()]
+            .iter_mut()
+            .zip(data)
+            .for_each(|(b, d)| *b &= d);
+        Ok(())
+    }
+}